@@ -0,0 +1,665 @@
+//! Assembles CHIP-8 source into ROM images.
+//!
+//! The syntax is the one the disassembler prints: lowercase mnemonics, `;`
+//! comments and `name:` labels, plus `define NAME value` constants, `db`/`dw`
+//! data and `include "file"`. Numbers are decimal, `0x` hex or `0b` binary,
+//! and may be added together with labels and constants. `db` and `dw` also
+//! take sprite rows written with `#` for set and `.` for clear pixels.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::PROGRAM_START;
+use crate::instruction::{AluOp, Instruction};
+
+const MAX_INCLUDE_DEPTH: usize = 16;
+// How deep constants may refer to other constants.
+const MAX_DEFINE_DEPTH: usize = 64;
+
+const MNEMONICS: [&str; 34] = [
+    "add", "and", "audio", "call", "cls", "drw", "exit", "high", "jp", "ld", "load", "low", "or",
+    "pitch", "plane", "ret", "rnd", "save", "scd", "scl", "scr", "scu", "se", "shl", "shr", "sknp",
+    "skp", "sne", "sub", "subn", "sys", "xor", "db", "dw",
+];
+const RESERVED: [&str; 9] = ["i", "dt", "st", "k", "f", "hf", "b", "r", "long"];
+
+/// An assembly error and where in the source it happened. `line` and
+/// `column` start at 1; both are 0 when a whole file could not be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.file, self.message)
+        } else {
+            write!(
+                f,
+                "{}:{}:{}: {}",
+                self.file, self.line, self.column, self.message
+            )
+        }
+    }
+}
+
+impl Error for AsmError {}
+
+/// Assembles `source`. Included files are looked up relative to the
+/// current directory.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut assembler = Assembler::default();
+    assembler.add_source("<source>", source, Path::new("."), 0)?;
+    assembler.finish()
+}
+
+/// Assembles the file at `path`. Included files are looked up relative to
+/// the file that includes them.
+pub fn assemble_file(path: impl AsRef<Path>) -> Result<Vec<u8>, AsmError> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|e| AsmError {
+        file: path.display().to_string(),
+        line: 0,
+        column: 0,
+        message: e.to_string(),
+    })?;
+    let mut assembler = Assembler::default();
+    assembler.add_source(&path.display().to_string(), &source, &parent(path), 0)?;
+    assembler.finish()
+}
+
+fn parent(path: &Path) -> PathBuf {
+    path.parent().unwrap_or(Path::new(".")).to_path_buf()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Location {
+    file: usize,
+    line: usize,
+    column: usize,
+}
+
+#[derive(Debug, Clone)]
+enum Term {
+    Number(i64),
+    Name(String),
+}
+
+/// Terms added together, each with its sign.
+#[derive(Debug, Clone)]
+struct Expr {
+    terms: Vec<(bool, Term)>,
+    location: Location,
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Register(u8),
+    I,
+    IndirectI,
+    Delay,
+    Sound,
+    Key,
+    Font,
+    BigFont,
+    Bcd,
+    Flags,
+    Long(Expr),
+    Value(Expr),
+    // A `#`/`.` sprite row for `db` or `dw`, padded on the right.
+    Bitmap(String, Location),
+}
+
+#[derive(Debug)]
+struct Statement {
+    mnemonic: String,
+    operands: Vec<Operand>,
+    location: Location,
+}
+
+impl Statement {
+    fn size(&self) -> usize {
+        match (self.mnemonic.as_str(), self.operands.as_slice()) {
+            ("db", operands) => operands.len(),
+            ("dw", operands) => 2 * operands.len(),
+            ("ld", [Operand::I, Operand::Long(_)]) => 4,
+            _ => 2,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Symbol {
+    Label(u16),
+    Constant(Expr),
+}
+
+#[derive(Debug, Default)]
+struct Assembler {
+    files: Vec<String>,
+    statements: Vec<Statement>,
+    symbols: HashMap<String, (Symbol, Location)>,
+    size: usize,
+}
+
+impl Assembler {
+    fn error(&self, location: Location, message: impl Into<String>) -> AsmError {
+        AsmError {
+            file: self.files[location.file].clone(),
+            line: location.line,
+            column: location.column,
+            message: message.into(),
+        }
+    }
+
+    fn add_source(
+        &mut self,
+        name: &str,
+        source: &str,
+        dir: &Path,
+        depth: usize,
+    ) -> Result<(), AsmError> {
+        self.files.push(name.to_string());
+        let file = self.files.len() - 1;
+        for (index, line) in source.lines().enumerate() {
+            self.add_line(line, file, index + 1, dir, depth)?;
+        }
+        Ok(())
+    }
+
+    fn add_line(
+        &mut self,
+        line: &str,
+        file: usize,
+        number: usize,
+        dir: &Path,
+        depth: usize,
+    ) -> Result<(), AsmError> {
+        let location = |offset: usize| Location {
+            file,
+            line: number,
+            column: line[..offset].chars().count() + 1,
+        };
+        let code = strip_comment(line);
+        let (mut word, mut offset) = next_word(code, 0);
+
+        if let Some(label) = word.strip_suffix(':') {
+            self.check_name(label, location(offset))?;
+            let address = self.address(location(offset))?;
+            self.define(label, Symbol::Label(address), location(offset))?;
+            (word, offset) = next_word(code, offset + word.len());
+        }
+        if word.is_empty() {
+            return Ok(());
+        }
+        let mnemonic = word.to_ascii_lowercase();
+        let rest = offset + word.len();
+
+        match mnemonic.as_str() {
+            "define" => {
+                let (name, name_offset) = next_word(code, rest);
+                self.check_name(name, location(name_offset))?;
+                let value_offset = name_offset + name.len();
+                let value = parse_expr(&code[value_offset..], location(value_offset))
+                    .map_err(|(location, message)| self.error(location, message))?;
+                self.define(name, Symbol::Constant(value), location(name_offset))
+            }
+            "include" => {
+                let argument = code[rest..].trim();
+                let path = argument
+                    .strip_prefix('"')
+                    .and_then(|path| path.strip_suffix('"'))
+                    .ok_or_else(|| self.error(location(offset), "expected a quoted file name"))?;
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(self.error(location(offset), "includes nested too deeply"));
+                }
+                let path = dir.join(path);
+                let source = fs::read_to_string(&path).map_err(|e| {
+                    self.error(location(offset), format!("{}: {}", path.display(), e))
+                })?;
+                self.add_source(
+                    &path.display().to_string(),
+                    &source,
+                    &parent(&path),
+                    depth + 1,
+                )
+            }
+            _ => {
+                if !MNEMONICS.contains(&mnemonic.as_str()) {
+                    return Err(
+                        self.error(location(offset), format!("unknown instruction `{}`", word))
+                    );
+                }
+                let mut operands = Vec::new();
+                if !code[rest..].trim().is_empty() {
+                    let mut start = rest;
+                    for piece in code[rest..].split(',') {
+                        let trimmed = piece.trim_start();
+                        let at = location(start + piece.len() - trimmed.len());
+                        let operand = parse_operand(trimmed.trim_end(), at)
+                            .map_err(|(location, message)| self.error(location, message))?;
+                        operands.push(operand);
+                        start += piece.len() + 1;
+                    }
+                }
+                let statement = Statement {
+                    mnemonic,
+                    operands,
+                    location: location(offset),
+                };
+                self.size += statement.size();
+                self.address(statement.location)?;
+                self.statements.push(statement);
+                Ok(())
+            }
+        }
+    }
+
+    fn address(&self, location: Location) -> Result<u16, AsmError> {
+        u16::try_from(PROGRAM_START as usize + self.size)
+            .map_err(|_| self.error(location, "program does not fit in memory"))
+    }
+
+    fn check_name(&self, name: &str, location: Location) -> Result<(), AsmError> {
+        let lower = name.to_ascii_lowercase();
+        if !is_identifier(name) || RESERVED.contains(&lower.as_str()) || register(&lower).is_some()
+        {
+            return Err(self.error(location, format!("invalid name `{}`", name)));
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, name: &str, symbol: Symbol, location: Location) -> Result<(), AsmError> {
+        if let Some((_, previous)) = self.symbols.get(name) {
+            return Err(self.error(
+                location,
+                format!(
+                    "`{}` is already defined at {}:{}",
+                    name, self.files[previous.file], previous.line
+                ),
+            ));
+        }
+        self.symbols.insert(name.to_string(), (symbol, location));
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Vec<u8>, AsmError> {
+        let mut rom = Vec::with_capacity(self.size);
+        for statement in &self.statements {
+            match statement.mnemonic.as_str() {
+                "db" => {
+                    for operand in &statement.operands {
+                        rom.push(self.data(operand, 8, statement.location)? as u8);
+                    }
+                }
+                "dw" => {
+                    for operand in &statement.operands {
+                        rom.extend(
+                            (self.data(operand, 16, statement.location)? as u16).to_be_bytes(),
+                        );
+                    }
+                }
+                _ => rom.extend(self.instruction(statement)?.encode()),
+            }
+        }
+        Ok(rom)
+    }
+
+    fn instruction(&self, statement: &Statement) -> Result<Instruction, AsmError> {
+        use Operand::*;
+        let at = statement.location;
+        let alu = |op| match statement.operands.as_slice() {
+            [Register(x), Register(y)] => Some(Instruction::Alu(op, *x, *y)),
+            // shr vx and shl vx shift in place.
+            [Register(x)] if matches!(op, AluOp::ShiftRight | AluOp::ShiftLeft) => {
+                Some(Instruction::Alu(op, *x, *x))
+            }
+            _ => None,
+        };
+        let instruction = match (statement.mnemonic.as_str(), statement.operands.as_slice()) {
+            ("sys", [nnn]) => Instruction::Sys(self.address_operand(nnn, at)?),
+            ("cls", []) => Instruction::ClearScreen,
+            ("ret", []) => Instruction::Return,
+            ("scd", [n]) => Instruction::ScrollDown(self.nibble(n, at)?),
+            ("scu", [n]) => Instruction::ScrollUp(self.nibble(n, at)?),
+            ("scr", []) => Instruction::ScrollRight,
+            ("scl", []) => Instruction::ScrollLeft,
+            ("exit", []) => Instruction::Exit,
+            ("low", []) => Instruction::LowRes,
+            ("high", []) => Instruction::HighRes,
+            ("jp", [Register(0), nnn]) => Instruction::JumpOffset(self.address_operand(nnn, at)?),
+            ("jp", [nnn]) => Instruction::Jump(self.address_operand(nnn, at)?),
+            ("call", [nnn]) => Instruction::Call(self.address_operand(nnn, at)?),
+            ("se", [Register(x), Register(y)]) => Instruction::SkipEqReg(*x, *y),
+            ("se", [Register(x), nn]) => Instruction::SkipEqImm(*x, self.byte(nn, at)?),
+            ("sne", [Register(x), Register(y)]) => Instruction::SkipNeReg(*x, *y),
+            ("sne", [Register(x), nn]) => Instruction::SkipNeImm(*x, self.byte(nn, at)?),
+            ("save", [Register(x), Register(y)]) => Instruction::SaveRange(*x, *y),
+            ("load", [Register(x), Register(y)]) => Instruction::LoadRange(*x, *y),
+            ("ld", [Register(x), Register(y)]) => Instruction::Alu(AluOp::Load, *x, *y),
+            ("ld", [Register(x), Delay]) => Instruction::GetDelay(*x),
+            ("ld", [Register(x), Key]) => Instruction::WaitKey(*x),
+            ("ld", [Register(x), IndirectI]) => Instruction::Load(*x),
+            ("ld", [Register(x), Flags]) => Instruction::LoadFlags(*x),
+            ("ld", [Register(x), nn]) => Instruction::LoadImm(*x, self.byte(nn, at)?),
+            ("ld", [Delay, Register(x)]) => Instruction::SetDelay(*x),
+            ("ld", [Sound, Register(x)]) => Instruction::SetSound(*x),
+            ("ld", [Font, Register(x)]) => Instruction::Font(*x),
+            ("ld", [BigFont, Register(x)]) => Instruction::BigFont(*x),
+            ("ld", [Bcd, Register(x)]) => Instruction::Bcd(*x),
+            ("ld", [IndirectI, Register(x)]) => Instruction::Store(*x),
+            ("ld", [Flags, Register(x)]) => Instruction::SaveFlags(*x),
+            ("ld", [I, Long(nnnn)]) => Instruction::LoadILong(self.value(nnnn, 0, 0xFFFF)? as u16),
+            ("ld", [I, nnn]) => Instruction::LoadI(self.address_operand(nnn, at)?),
+            ("add", [Register(x), Register(y)]) => Instruction::Alu(AluOp::Add, *x, *y),
+            ("add", [Register(x), nn]) => Instruction::AddImm(*x, self.byte(nn, at)?),
+            ("add", [I, Register(x)]) => Instruction::AddI(*x),
+            ("or", _) => alu(AluOp::Or).ok_or_else(|| self.bad_operands(statement))?,
+            ("and", _) => alu(AluOp::And).ok_or_else(|| self.bad_operands(statement))?,
+            ("xor", _) => alu(AluOp::Xor).ok_or_else(|| self.bad_operands(statement))?,
+            ("sub", _) => alu(AluOp::Sub).ok_or_else(|| self.bad_operands(statement))?,
+            ("shr", _) => alu(AluOp::ShiftRight).ok_or_else(|| self.bad_operands(statement))?,
+            ("subn", _) => alu(AluOp::SubN).ok_or_else(|| self.bad_operands(statement))?,
+            ("shl", _) => alu(AluOp::ShiftLeft).ok_or_else(|| self.bad_operands(statement))?,
+            ("rnd", [Register(x), nn]) => Instruction::Random(*x, self.byte(nn, at)?),
+            ("drw", [Register(x), Register(y), n]) => {
+                Instruction::Draw(*x, *y, self.nibble(n, at)?)
+            }
+            ("skp", [Register(x)]) => Instruction::SkipKeyPressed(*x),
+            ("sknp", [Register(x)]) => Instruction::SkipKeyNotPressed(*x),
+            ("plane", [n]) => Instruction::SelectPlanes(self.nibble(n, at)?),
+            ("audio", []) => Instruction::LoadAudio,
+            ("pitch", [Register(x)]) => Instruction::SetPitch(*x),
+            _ => return Err(self.bad_operands(statement)),
+        };
+        Ok(instruction)
+    }
+
+    fn bad_operands(&self, statement: &Statement) -> AsmError {
+        self.error(
+            statement.location,
+            format!("invalid operands for `{}`", statement.mnemonic),
+        )
+    }
+
+    fn address_operand(&self, operand: &Operand, at: Location) -> Result<u16, AsmError> {
+        Ok(self.value(self.expr(operand, at)?, 0, 0xFFF)? as u16)
+    }
+
+    // Bytes may also be written as negative numbers.
+    fn byte(&self, operand: &Operand, at: Location) -> Result<u8, AsmError> {
+        Ok(self.value(self.expr(operand, at)?, -0x80, 0xFF)? as u8)
+    }
+
+    fn nibble(&self, operand: &Operand, at: Location) -> Result<u8, AsmError> {
+        Ok(self.value(self.expr(operand, at)?, 0, 0xF)? as u8)
+    }
+
+    fn data(&self, operand: &Operand, bits: u32, at: Location) -> Result<i64, AsmError> {
+        match operand {
+            Operand::Bitmap(row, location) => {
+                if row.len() > bits as usize {
+                    return Err(self.error(
+                        *location,
+                        format!("sprite row is wider than {} pixels", bits),
+                    ));
+                }
+                let value = row
+                    .chars()
+                    .fold(0, |value, pixel| value << 1 | (pixel == '#') as i64);
+                Ok(value << (bits as usize - row.len()))
+            }
+            operand => {
+                let min = -(1 << (bits - 1));
+                self.value(self.expr(operand, at)?, min, (1 << bits) - 1)
+            }
+        }
+    }
+
+    fn expr<'a>(&self, operand: &'a Operand, at: Location) -> Result<&'a Expr, AsmError> {
+        match operand {
+            Operand::Value(expr) => Ok(expr),
+            Operand::Long(expr) => Err(self.error(expr.location, "`long` only works with `ld i`")),
+            Operand::Bitmap(_, location) => {
+                Err(self.error(*location, "sprite rows only work with `db` and `dw`"))
+            }
+            _ => Err(self.error(at, "expected a value")),
+        }
+    }
+
+    fn value(&self, expr: &Expr, min: i64, max: i64) -> Result<i64, AsmError> {
+        let value = self.evaluate(expr, 0)?;
+        if value < min || value > max {
+            let hex = |n: i64| {
+                if n < 0 {
+                    format!("-{:#X}", -n)
+                } else {
+                    format!("{:#X}", n)
+                }
+            };
+            return Err(self.error(
+                expr.location,
+                format!("{} is out of range ({}..={})", value, hex(min), hex(max)),
+            ));
+        }
+        Ok(value)
+    }
+
+    fn evaluate(&self, expr: &Expr, depth: usize) -> Result<i64, AsmError> {
+        if depth > MAX_DEFINE_DEPTH {
+            return Err(self.error(expr.location, "constant refers to itself"));
+        }
+        let mut total: i64 = 0;
+        for (negative, term) in &expr.terms {
+            let value = match term {
+                Term::Number(value) => *value,
+                Term::Name(name) => match self.symbols.get(name) {
+                    Some((Symbol::Label(address), _)) => *address as i64,
+                    Some((Symbol::Constant(value), _)) => self.evaluate(value, depth + 1)?,
+                    None => {
+                        return Err(self.error(expr.location, format!("undefined name `{}`", name)))
+                    }
+                },
+            };
+            total = if *negative {
+                total.saturating_sub(value)
+            } else {
+                total.saturating_add(value)
+            };
+        }
+        Ok(total)
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+// The next whitespace-separated word at or after `from`, and its offset.
+fn next_word(code: &str, from: usize) -> (&str, usize) {
+    let rest = &code[from..];
+    let start = from + rest.len() - rest.trim_start().len();
+    let end = code[start..]
+        .find(char::is_whitespace)
+        .map_or(code.len(), |end| start + end);
+    (&code[start..end], start)
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn register(name: &str) -> Option<u8> {
+    let digit = name.strip_prefix('v')?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+type ParseError = (Location, String);
+
+fn parse_operand(text: &str, location: Location) -> Result<Operand, ParseError> {
+    let lower = text.to_ascii_lowercase();
+    let operand = match lower.as_str() {
+        "" => return Err((location, "missing operand".to_string())),
+        "i" => Operand::I,
+        "[i]" => Operand::IndirectI,
+        "dt" => Operand::Delay,
+        "st" => Operand::Sound,
+        "k" => Operand::Key,
+        "f" => Operand::Font,
+        "hf" => Operand::BigFont,
+        "b" => Operand::Bcd,
+        "r" => Operand::Flags,
+        _ if text.chars().all(|c| c == '#' || c == '.') => {
+            Operand::Bitmap(text.to_string(), location)
+        }
+        _ => match register(&lower) {
+            Some(x) => Operand::Register(x),
+            None => match lower.strip_prefix("long") {
+                Some(rest) if rest.starts_with(char::is_whitespace) => {
+                    let offset = text.len() - rest.trim_start().len();
+                    let column = location.column + offset;
+                    Operand::Long(parse_expr(
+                        &text[offset..],
+                        Location { column, ..location },
+                    )?)
+                }
+                _ => Operand::Value(parse_expr(text, location)?),
+            },
+        },
+    };
+    Ok(operand)
+}
+
+fn parse_expr(text: &str, location: Location) -> Result<Expr, ParseError> {
+    let text = text.trim();
+    let error = |message: String| (location, message);
+    if text.is_empty() {
+        return Err(error("missing value".to_string()));
+    }
+    let mut terms = Vec::new();
+    let mut negative = false;
+    let mut rest = text;
+    if let Some(stripped) = rest.strip_prefix('-') {
+        negative = true;
+        rest = stripped;
+    }
+    loop {
+        let end = rest.find(['+', '-']).unwrap_or(rest.len());
+        let term = rest[..end].trim();
+        let value = if term.starts_with(|c: char| c.is_ascii_digit()) {
+            Term::Number(
+                parse_number(term).ok_or_else(|| error(format!("invalid number `{}`", term)))?,
+            )
+        } else if is_identifier(term) {
+            Term::Name(term.to_string())
+        } else {
+            return Err(error(format!("invalid value `{}`", text)));
+        };
+        terms.push((negative, value));
+        if end == rest.len() {
+            break;
+        }
+        negative = rest[end..].starts_with('-');
+        rest = &rest[end + 1..];
+    }
+    Ok(Expr { terms, location })
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let lower = text.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::disassemble;
+    use crate::instruction::decode_from;
+    use crate::platform::Platform;
+
+    #[test]
+    fn every_instruction_round_trips() {
+        for opcode in 0..=0xFFFFu16 {
+            let mut bytes = opcode.to_be_bytes().to_vec();
+            bytes.extend([0xAB, 0xCD]);
+            let instruction = decode_from(&bytes).unwrap();
+            let source = instruction.to_asm(|_| None);
+            assert_eq!(
+                assemble(&source).unwrap(),
+                instruction.encode(),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn disassembly_round_trips() {
+        let rom = [
+            0xA2, 0x0A, 0x22, 0x0C, 0x12, 0x04, 0x30, 0x01, 0x00, 0x00, 0xF0, 0x90, 0xD0, 0x12,
+            0x00, 0xEE, 0xFF,
+        ];
+        assert_eq!(assemble(&disassemble(&rom, Platform::Chip8)).unwrap(), rom);
+    }
+
+    #[test]
+    fn labels_constants_and_data() {
+        let source = "
+            define ROWS 2
+            start:  ld i, sprite
+                    drw v0, v1, ROWS
+                    jp start + 2 ; forward and backward references
+            sprite: db ##..##.., 0b0011
+                    dw 0x1234, -1
+        ";
+        assert_eq!(
+            assemble(source).unwrap(),
+            [0xA2, 0x06, 0xD0, 0x12, 0x12, 0x02, 0xCC, 0x03, 0x12, 0x34, 0xFF, 0xFF]
+        );
+    }
+
+    #[test]
+    fn errors_report_line_and_column() {
+        let error = assemble("cls\n  ld v0, missing\n").unwrap_err();
+        assert_eq!((error.line, error.column), (2, 10));
+        assert_eq!(error.message, "undefined name `missing`");
+
+        let error = assemble("  jp 0x1000").unwrap_err();
+        assert_eq!((error.line, error.column), (1, 6));
+        assert_eq!(error.message, "4096 is out of range (0x0..=0xFFF)");
+        let error = assemble("ld v0, -129").unwrap_err();
+        assert_eq!(error.message, "-129 is out of range (-0x80..=0xFF)");
+        assert_eq!(
+            assemble("\n\n mov v0, v1").unwrap_err().to_string(),
+            "<source>:3:2: unknown instruction `mov`"
+        );
+    }
+}
@@ -0,0 +1,265 @@
+//! Sound output. The machine only knows whether the buzzer is on; backends
+//! turn that into an actual tone.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[cfg(feature = "audio")]
+mod cpal_backend;
+
+#[cfg(feature = "audio")]
+pub use cpal_backend::CpalAudio;
+
+/// Frequency in Hz and volume in `0.0..=1.0` of the buzzer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tone {
+    pub frequency: f32,
+    pub volume: f32,
+}
+
+impl Default for Tone {
+    fn default() -> Self {
+        Tone {
+            frequency: 440.0,
+            volume: 0.25,
+        }
+    }
+}
+
+/// An XO-CHIP sample: 128 one-bit samples played in a loop at a rate set by
+/// `pitch`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioPattern {
+    pub buffer: [u8; 16],
+    pub pitch: u8,
+}
+
+impl AudioPattern {
+    pub const DEFAULT_PITCH: u8 = 64;
+
+    /// Bits played per second: 4000 Hz at the default pitch, doubling every
+    /// 48 steps.
+    pub fn playback_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - 64.0) / 48.0)
+    }
+
+    fn bit(&self, index: usize) -> bool {
+        self.buffer[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+}
+
+pub trait AudioBackend {
+    /// Called once per 60 Hz frame with whether the sound timer is running.
+    fn update(&mut self, beeping: bool);
+
+    /// Called before `update` with the XO-CHIP pattern to play instead of
+    /// the plain tone, if the program loaded one.
+    fn set_pattern(&mut self, _pattern: Option<AudioPattern>) {}
+}
+
+/// Square wave generator shared by the backends.
+pub struct SquareWave {
+    tone: Tone,
+    sample_rate: u32,
+    phase: f32,
+}
+
+impl SquareWave {
+    pub fn new(tone: Tone, sample_rate: u32) -> SquareWave {
+        SquareWave {
+            tone,
+            sample_rate,
+            phase: 0.0,
+        }
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        let sample = if self.phase < 0.5 {
+            self.tone.volume
+        } else {
+            -self.tone.volume
+        };
+        self.phase = (self.phase + self.tone.frequency / self.sample_rate as f32).fract();
+        sample
+    }
+}
+
+/// Plays an [`AudioPattern`] bit by bit.
+pub struct PatternWave {
+    pattern: AudioPattern,
+    volume: f32,
+    sample_rate: u32,
+    position: f32,
+}
+
+impl PatternWave {
+    pub fn new(pattern: AudioPattern, volume: f32, sample_rate: u32) -> PatternWave {
+        PatternWave {
+            pattern,
+            volume,
+            sample_rate,
+            position: 0.0,
+        }
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        let sample = if self.pattern.bit(self.position as usize) {
+            self.volume
+        } else {
+            -self.volume
+        };
+        self.position =
+            (self.position + self.pattern.playback_rate() / self.sample_rate as f32) % 128.0;
+        sample
+    }
+}
+
+/// The buzzer as a whole: the configured tone, or the XO-CHIP pattern once
+/// one is set.
+pub struct Voice {
+    tone: Tone,
+    sample_rate: u32,
+    square: SquareWave,
+    pattern: Option<PatternWave>,
+}
+
+impl Voice {
+    pub fn new(tone: Tone, sample_rate: u32) -> Voice {
+        Voice {
+            tone,
+            sample_rate,
+            square: SquareWave::new(tone, sample_rate),
+            pattern: None,
+        }
+    }
+
+    pub fn set_pattern(&mut self, pattern: Option<AudioPattern>) {
+        // Keep the playback position while the pattern is unchanged.
+        if self.pattern.as_ref().map(|wave| wave.pattern) == pattern {
+            return;
+        }
+        self.pattern =
+            pattern.map(|pattern| PatternWave::new(pattern, self.tone.volume, self.sample_rate));
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        match &mut self.pattern {
+            Some(wave) => wave.next_sample(),
+            None => self.square.next_sample(),
+        }
+    }
+}
+
+/// Discards all sound.
+pub struct NullAudio;
+
+impl AudioBackend for NullAudio {
+    fn update(&mut self, _beeping: bool) {}
+}
+
+/// Renders the tone into memory instead of a sound card. Clones share the
+/// same buffers, so keep one to inspect what a boxed copy recorded.
+#[derive(Clone)]
+pub struct CaptureAudio {
+    inner: Rc<RefCell<Capture>>,
+}
+
+struct Capture {
+    voice: Voice,
+    samples_per_frame: usize,
+    frames: Vec<bool>,
+    samples: Vec<f32>,
+}
+
+impl CaptureAudio {
+    pub fn new(tone: Tone, sample_rate: u32) -> CaptureAudio {
+        CaptureAudio {
+            inner: Rc::new(RefCell::new(Capture {
+                voice: Voice::new(tone, sample_rate),
+                samples_per_frame: sample_rate as usize / 60,
+                frames: Vec::new(),
+                samples: Vec::new(),
+            })),
+        }
+    }
+
+    /// Whether the buzzer was on, one entry per frame.
+    pub fn frames(&self) -> Vec<bool> {
+        self.inner.borrow().frames.clone()
+    }
+
+    pub fn samples(&self) -> Vec<f32> {
+        self.inner.borrow().samples.clone()
+    }
+}
+
+impl AudioBackend for CaptureAudio {
+    fn update(&mut self, beeping: bool) {
+        let mut capture = self.inner.borrow_mut();
+        capture.frames.push(beeping);
+        for _ in 0..capture.samples_per_frame {
+            let sample = if beeping {
+                capture.voice.next_sample()
+            } else {
+                0.0
+            };
+            capture.samples.push(sample);
+        }
+    }
+
+    fn set_pattern(&mut self, pattern: Option<AudioPattern>) {
+        self.inner.borrow_mut().voice.set_pattern(pattern);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Chip8;
+
+    // 200: ld v0, 5; 202: ld st, v0; 204: jp 204
+    const ROM: [u8; 6] = [0x60, 0x05, 0xF0, 0x18, 0x12, 0x04];
+
+    fn run(tone: Tone, frames: usize) -> (Chip8, CaptureAudio) {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(&ROM).unwrap();
+        let capture = CaptureAudio::new(tone, 48_000);
+        chip8.set_audio_backend(Box::new(capture.clone()));
+        for _ in 0..frames {
+            chip8.run_frame().unwrap();
+        }
+        (chip8, capture)
+    }
+
+    #[test]
+    fn sound_timer_beeps_for_its_frames() {
+        let (chip8, capture) = run(Tone::default(), 8);
+        assert_eq!(chip8.sound_timer(), 0);
+        assert_eq!(
+            capture.frames(),
+            [true, true, true, true, true, false, false, false]
+        );
+        // Silence is silent.
+        let samples = capture.samples();
+        assert_eq!(samples.len(), 8 * 800);
+        assert!(samples[5 * 800..].iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn captured_tone_matches_the_configuration() {
+        let tone = Tone {
+            frequency: 1200.0,
+            volume: 0.5,
+        };
+        let (_, capture) = run(tone, 5);
+        let samples = capture.samples();
+        assert!(samples.iter().all(|&sample| sample.abs() == 0.5));
+        // Five frames at 1200 Hz are 100 periods, each starting high.
+        let periods = samples
+            .windows(2)
+            .filter(|pair| pair[0] < 0.0 && pair[1] > 0.0)
+            .count()
+            + 1;
+        assert_eq!(periods, 100);
+    }
+}
@@ -0,0 +1,87 @@
+use super::{AudioBackend, AudioPattern, Tone, Voice};
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample, Stream, StreamConfig};
+use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Plays the tone on the default output device.
+pub struct CpalAudio {
+    beeping: Arc<AtomicBool>,
+    pattern: Arc<Mutex<Option<AudioPattern>>>,
+    // Dropping the stream stops playback.
+    _stream: Stream,
+}
+
+impl CpalAudio {
+    pub fn new(tone: Tone) -> Result<CpalAudio, Box<dyn Error>> {
+        let device = cpal::default_host()
+            .default_output_device()
+            .ok_or("no audio output device")?;
+        let supported = device.default_output_config()?;
+        let format = supported.sample_format();
+        let config: StreamConfig = supported.into();
+        let beeping = Arc::new(AtomicBool::new(false));
+        let pattern = Arc::new(Mutex::new(None));
+        let shared = (beeping.clone(), pattern.clone());
+
+        let stream = match format {
+            SampleFormat::F32 => build_stream::<f32>(&device, &config, tone, shared)?,
+            SampleFormat::I16 => build_stream::<i16>(&device, &config, tone, shared)?,
+            SampleFormat::U16 => build_stream::<u16>(&device, &config, tone, shared)?,
+            other => return Err(format!("unsupported sample format {:?}", other).into()),
+        };
+        stream.play()?;
+
+        Ok(CpalAudio {
+            beeping,
+            pattern,
+            _stream: stream,
+        })
+    }
+}
+
+impl AudioBackend for CpalAudio {
+    fn update(&mut self, beeping: bool) {
+        self.beeping.store(beeping, Ordering::Relaxed);
+    }
+
+    fn set_pattern(&mut self, pattern: Option<AudioPattern>) {
+        if let Ok(mut shared) = self.pattern.lock() {
+            *shared = pattern;
+        }
+    }
+}
+
+fn build_stream<T>(
+    device: &cpal::Device,
+    config: &StreamConfig,
+    tone: Tone,
+    (beeping, pattern): (Arc<AtomicBool>, Arc<Mutex<Option<AudioPattern>>>),
+) -> Result<Stream, Box<dyn Error>>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    let mut voice = Voice::new(tone, config.sample_rate.0);
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], _| {
+            let on = beeping.load(Ordering::Relaxed);
+            // Never block the audio thread; a missed update is picked up on
+            // the next callback.
+            if let Ok(pattern) = pattern.try_lock() {
+                voice.set_pattern(*pattern);
+            }
+            for frame in data.chunks_mut(channels) {
+                let sample = if on { voice.next_sample() } else { 0.0 };
+                for out in frame {
+                    *out = T::from_sample(sample);
+                }
+            }
+        },
+        |err| eprintln!("Audio stream error: {}", err),
+        None,
+    )?;
+    Ok(stream)
+}
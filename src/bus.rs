@@ -1,15 +1,20 @@
 use std::fmt;
 
+use crate::audio::AudioPattern;
 use crate::display::Display;
 use crate::keyboard::Keyboard;
 use crate::ram::Ram;
-use std::time;
+use crate::state::{ChunkWriter, StateError, StateReader, StateWriter};
+use log::trace;
 pub struct Bus {
     ram: Ram,
     keyboard: Keyboard,
     display: Display,
     delay_timer: u8,
-    delay_timer_set_time: time::Instant,
+    sound_timer: u8,
+    // XO-CHIP audio; the plain tone plays until a pattern is loaded.
+    audio_buffer: Option<[u8; 16]>,
+    audio_pitch: u8,
 }
 
 impl Bus {
@@ -19,73 +24,200 @@ impl Bus {
             keyboard: Keyboard::new(),
             display: Display::new(),
             delay_timer: 0,
-            delay_timer_set_time: time::Instant::now(),
+            sound_timer: 0,
+            audio_buffer: None,
+            audio_pitch: AudioPattern::DEFAULT_PITCH,
         }
     }
 
-    pub fn ram_read_byte(&self, address: u16) -> u8 {
+    pub fn ram_read_byte(&self, address: u16) -> Option<u8> {
         self.ram.read_byte(address)
     }
 
-    pub fn ram_write_byte(&mut self, address: u16, value: u8) {
+    pub fn ram_write_byte(&mut self, address: u16, value: u8) -> Option<()> {
         self.ram.write_byte(address, value)
     }
 
-    pub fn debug_draw_byte(&mut self, byte: u8, x: u8, y: u8) -> bool {
-        self.display.debug_draw_byte(byte, x, y)
+    pub fn ram_size(&self) -> usize {
+        self.ram.size()
+    }
+
+    pub fn set_ram_size(&mut self, size: usize) {
+        self.ram.resize(size);
+    }
+
+    pub fn draw_sprite(
+        &mut self,
+        x: u8,
+        y: u8,
+        data: &[u8],
+        bytes_per_row: usize,
+        plane: u8,
+        clip: bool,
+    ) -> bool {
+        self.display
+            .draw_sprite(x, y, data, bytes_per_row, plane, clip)
+    }
+
+    pub fn selected_planes(&self) -> u8 {
+        self.display.selected_planes()
+    }
+
+    pub fn select_planes(&mut self, planes: u8) {
+        self.display.select_planes(planes);
     }
 
     pub fn clear_screen(&mut self) {
         self.display.clear();
     }
 
-    pub fn set_key_pressed(&mut self, key: Option<u8>) {
-        self.keyboard.set_key_pressed(key);
+    pub fn set_hires(&mut self, hires: bool) {
+        self.display.set_hires(hires);
+    }
+
+    pub fn scroll_down(&mut self, rows: usize) {
+        self.display.scroll_down(rows);
+    }
+
+    pub fn scroll_up(&mut self, rows: usize) {
+        self.display.scroll_up(rows);
+    }
+
+    pub fn scroll_right(&mut self, columns: usize) {
+        self.display.scroll_right(columns);
+    }
+
+    pub fn scroll_left(&mut self, columns: usize) {
+        self.display.scroll_left(columns);
+    }
+
+    pub fn set_keys(&mut self, keys: u16) {
+        self.keyboard.set_keys(keys);
+    }
+
+    pub fn key_down(&mut self, key_code: u8) {
+        self.keyboard.key_down(key_code);
+    }
+
+    pub fn key_up(&mut self, key_code: u8) {
+        self.keyboard.key_up(key_code);
+    }
+
+    pub fn get_keys(&self) -> u16 {
+        self.keyboard.get_keys()
     }
 
     pub fn key_pressed(&self, key_code: u8) -> bool {
         self.keyboard.is_key_pressed(key_code)
     }
 
-    pub fn get_key_pressed(&self) -> Option<u8> {
-        self.keyboard.get_key_pressed()
+    pub fn clear_key_events(&mut self) {
+        self.keyboard.clear_events();
+    }
+
+    pub fn take_released_key(&mut self) -> Option<u8> {
+        self.keyboard.take_released_key()
     }
 
     pub fn set_delay_timer(&mut self, value: u8) {
-        self.delay_timer_set_time = time::Instant::now();
+        trace!(target: "bus", "delay timer = {}", value);
         self.delay_timer = value;
     }
 
     pub fn get_delay_timer(&self) -> u8 {
-        let diff = time::Instant::now() - self.delay_timer_set_time;
-        let ms = diff.get_millis();
-        let ticks = ms / 16;
-        if ticks >= self.delay_timer as u64 {
-            0
-        } else {
-            self.delay_timer - ticks as u8
-        }
+        self.delay_timer
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        trace!(target: "bus", "sound timer = {}", value);
+        self.sound_timer = value;
+    }
+
+    pub fn get_sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    pub fn audio_pattern(&self) -> Option<AudioPattern> {
+        self.audio_buffer.map(|buffer| AudioPattern {
+            buffer,
+            pitch: self.audio_pitch,
+        })
+    }
+
+    pub fn set_audio_buffer(&mut self, buffer: [u8; 16]) {
+        trace!(target: "bus", "audio pattern = {:02X?}", buffer);
+        self.audio_buffer = Some(buffer);
+    }
+
+    pub fn set_audio_pitch(&mut self, pitch: u8) {
+        trace!(target: "bus", "audio pitch = {}", pitch);
+        self.audio_pitch = pitch;
+    }
+
+    // Called once per 60 Hz frame.
+    pub fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+    }
+
+    /// Adds memory, the screen, the keypad, the timers and the audio
+    /// pattern to a save state.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.ram.save_state(state);
+        self.display.save_state(state);
+        self.keyboard.save_state(state);
+        let mut timers = ChunkWriter::new();
+        timers.u8(self.delay_timer).u8(self.sound_timer);
+        state.chunk("TIME", timers);
+        let mut audio = ChunkWriter::new();
+        audio
+            .bool(self.audio_buffer.is_some())
+            .bytes(&self.audio_buffer.unwrap_or_default())
+            .u8(self.audio_pitch);
+        state.chunk("SND ", audio);
+    }
+
+    pub fn load_state(state: &StateReader) -> Result<Bus, StateError> {
+        let mut timers = state.chunk("TIME")?;
+        let mut audio = state.chunk("SND ")?;
+        let has_pattern = audio.bool()?;
+        let buffer: [u8; 16] = audio.bytes()?.try_into().map_err(|_| audio.corrupt())?;
+        Ok(Bus {
+            ram: Ram::load_state(state)?,
+            keyboard: Keyboard::load_state(state)?,
+            display: Display::load_state(state)?,
+            delay_timer: timers.u8()?,
+            sound_timer: timers.u8()?,
+            audio_buffer: has_pattern.then_some(buffer),
+            audio_pitch: audio.u8()?,
+        })
     }
 
     pub fn get_display_buffer(&self) -> &[u8] {
         self.display.get_display_buffer()
     }
-}
 
-impl fmt::Debug for Bus {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, " Delay timer: {:?}", self.delay_timer)
+    pub fn display_width(&self) -> usize {
+        self.display.width()
+    }
+
+    pub fn display_height(&self) -> usize {
+        self.display.height()
     }
 }
 
-trait Milliseconds {
-    fn get_millis(&self) -> u64;
+impl Default for Bus {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl Milliseconds for time::Duration {
-    fn get_millis(&self) -> u64 {
-        let nanos = self.subsec_nanos() as u64;
-        let ms = (1000 * 1000 * 1000 * self.as_secs() + nanos) / (1000 * 1000);
-        ms
+impl fmt::Debug for Bus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            " Delay timer: {:?} Sound timer: {:?}",
+            self.delay_timer, self.sound_timer
+        )
     }
 }
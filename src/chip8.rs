@@ -1,10 +1,35 @@
+use crate::audio::{AudioBackend, NullAudio};
 use crate::bus::Bus;
 use crate::cpu::Cpu;
 use crate::cpu::PROGRAM_START;
+use crate::error::Chip8Error;
+use crate::image;
+use crate::palette::Palette;
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+use crate::random::RandomSource;
+use crate::state::{ChunkWriter, StateError, StateReader, StateWriter};
+use log::{trace, warn};
+use sha1::{Digest, Sha1};
+use std::io::Write;
+
+/// Default number of instructions executed by [`Chip8::run_frame`].
+pub const INSTRUCTIONS_PER_FRAME: usize = 8;
+
+/// The most instructions per frame the frontends accept, far beyond what
+/// any game needs but still a frame that ends.
+pub const MAX_INSTRUCTIONS_PER_FRAME: usize = 1_000_000;
 
 pub struct Chip8 {
     bus: Bus,
     cpu: Cpu,
+    rom: Vec<u8>,
+    audio: Box<dyn AudioBackend>,
+    instructions_per_frame: usize,
+    // Instructions run so far in the current frame.
+    frame_cycles: usize,
+    frames: u64,
+    trace: Option<Box<dyn Write>>,
 }
 
 impl Chip8 {
@@ -12,26 +37,344 @@ impl Chip8 {
         Chip8 {
             bus: Bus::new(),
             cpu: Cpu::new(),
+            rom: Vec::new(),
+            audio: Box::new(NullAudio),
+            instructions_per_frame: INSTRUCTIONS_PER_FRAME,
+            frame_cycles: 0,
+            frames: 0,
+            trace: None,
+        }
+    }
+
+    /// Writes one line per executed instruction to `trace`, or stops tracing
+    /// with `None`.
+    pub fn set_trace(&mut self, trace: Option<Box<dyn Write>>) {
+        self.trace = trace;
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.cpu.platform()
+    }
+
+    /// Selects the instruction set and switches to the quirks it expects.
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.cpu.set_platform(platform);
+        self.cpu.set_quirks(platform.quirks());
+        self.bus.set_ram_size(platform.memory_size());
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.cpu.quirks()
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.cpu.set_quirks(quirks);
+    }
+
+    pub fn instructions_per_frame(&self) -> usize {
+        self.instructions_per_frame
+    }
+
+    pub fn set_instructions_per_frame(&mut self, count: usize) {
+        self.instructions_per_frame = count;
+    }
+
+    /// The seed behind CXNN's random numbers.
+    pub fn seed(&self) -> u64 {
+        self.cpu.seed()
+    }
+
+    /// Makes CXNN's random numbers repeatable; resets restart the sequence.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.cpu.set_seed(seed);
+    }
+
+    /// Replaces where CXNN's random numbers come from, such as a
+    /// [`ScriptedSource`](crate::random::ScriptedSource) in tests.
+    pub fn set_random_source(&mut self, source: Box<dyn RandomSource>) {
+        self.cpu.set_random_source(source);
+    }
+
+    /// Replaces where the buzzer goes; sound is discarded by default.
+    pub fn set_audio_backend(&mut self, audio: Box<dyn AudioBackend>) {
+        self.audio = audio;
+    }
+
+    /// Copies `data` into memory at `PROGRAM_START`. The ROM is kept so that
+    /// [`Chip8::reset`] can reload it.
+    pub fn load_rom(&mut self, data: &[u8]) -> Result<(), Chip8Error> {
+        let max = self.bus.ram_size() - PROGRAM_START as usize;
+        if data.len() > max {
+            return Err(Chip8Error::RomTooLarge {
+                size: data.len(),
+                max,
+            });
+        }
+        self.rom = data.to_vec();
+        for (i, byte) in data.iter().enumerate() {
+            self.bus.ram_write_byte(PROGRAM_START + (i as u16), *byte);
+        }
+        Ok(())
+    }
+
+    /// Executes a single instruction. A frame ends, ticking the timers, after
+    /// the configured number of instructions or when the display-wait quirk
+    /// stalls for vblank, so stepping advances time just like `run_frame`.
+    /// On error the machine is left exactly as it was before the faulting
+    /// instruction.
+    pub fn step(&mut self) -> Result<(), Chip8Error> {
+        if self.trace.is_some() {
+            self.write_trace();
+        }
+        self.cpu.run_instruction(&mut self.bus)?;
+        trace!(target: "cpu", "{:?}", self.cpu);
+        trace!(target: "bus", "{:?}", self.bus);
+
+        self.frame_cycles += 1;
+        if self.cpu.take_vblank_wait() || self.frame_cycles >= self.instructions_per_frame {
+            self.frame_cycles = 0;
+            self.frames += 1;
+            self.tick_timers();
+        }
+        Ok(())
+    }
+
+    // The state before the instruction at PC runs.
+    fn write_trace(&mut self) {
+        let pc = self.cpu.pc();
+        let byte = |address: u16| self.bus.ram_read_byte(address).unwrap_or(0);
+        let opcode = (byte(pc) as u16) << 8 | byte(pc.wrapping_add(1)) as u16;
+        let mut line = format!("{:04X}: {:04X}  I={:04X} V=", pc, opcode, self.cpu.i());
+        for v in self.cpu.registers() {
+            line.push_str(&format!("{:02X}", v));
+        }
+        line.push_str(&format!(" SP={}", self.cpu.stack().len()));
+
+        if let Some(trace) = &mut self.trace {
+            if let Err(e) = writeln!(trace, "{}", line) {
+                warn!(target: "cpu", "instruction trace stopped: {}", e);
+                self.trace = None;
+            }
         }
     }
 
-    pub fn load_rom(&mut self, data: &Vec<u8>) {
-        for i in 0..data.len() {
-            self.bus.ram_write_byte(PROGRAM_START + (i as u16), data[i]);
+    /// Executes instructions until the current 60 Hz frame ends. Stops at the
+    /// first error, so the same inputs always produce the same machine state;
+    /// the next call finishes the interrupted frame.
+    pub fn run_frame(&mut self) -> Result<(), Chip8Error> {
+        let frame = self.frames;
+        while self.frames == frame {
+            self.step()?;
         }
+        Ok(())
+    }
+
+    /// Frames completed since the machine was created or reset.
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    /// Decrements the delay and sound timers; `step` does this at the end of
+    /// every frame. The buzzer sounded for the frame if the sound timer was
+    /// running, so FX18 with N beeps for N frames.
+    pub fn tick_timers(&mut self) {
+        let beeping = self.bus.get_sound_timer() > 0;
+        self.bus.tick_timers();
+        self.audio.set_pattern(self.bus.audio_pattern());
+        self.audio.update(beeping);
+    }
+
+    /// Puts the machine back in its power-on state with the last ROM loaded.
+    pub fn reset(&mut self) {
+        self.bus = Bus::new();
+        self.bus.set_ram_size(self.cpu.platform().memory_size());
+        self.cpu.reset();
+        self.frame_cycles = 0;
+        self.frames = 0;
+        let rom = std::mem::take(&mut self.rom);
+        // The ROM fitted when it was first loaded.
+        let _ = self.load_rom(&rom);
+    }
+
+    /// SHA-1 of the loaded ROM, which identifies it in save states.
+    pub fn rom_sha1(&self) -> [u8; 20] {
+        Sha1::digest(&self.rom).into()
+    }
+
+    /// Snapshots the whole machine: registers, stack, memory, screen,
+    /// keypad, timers, RNG position, quirks and the hash of the ROM.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new();
+        let mut rom = ChunkWriter::new();
+        rom.bytes(&self.rom_sha1()).u32(self.rom.len() as u32);
+        state.chunk("ROM ", rom);
+        let mut frames = ChunkWriter::new();
+        frames.u64(self.frames).u32(self.frame_cycles as u32);
+        state.chunk("FRAM", frames);
+        self.cpu.save_state(&mut state);
+        self.bus.save_state(&mut state);
+        state.finish()
     }
 
-    pub fn run_instruction(&mut self) {
-        self.cpu.run_instruction(&mut self.bus);
-        println!("Cpu state: {:#?}", self.cpu);
-        println!("Bus state: {:#?}", self.bus);
+    /// Restores a snapshot from [`Chip8::save_state`]. It must have been
+    /// taken with the same ROM loaded; nothing changes if it can't be
+    /// loaded.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let state = StateReader::parse(data)?;
+        let mut rom = state.chunk("ROM ")?;
+        if rom.bytes()? != self.rom_sha1() || rom.u32()? as usize != self.rom.len() {
+            return Err(StateError::RomMismatch);
+        }
+        let mut frames = state.chunk("FRAM")?;
+        let frame_count = frames.u64()?;
+        let frame_cycles = frames.u32()? as usize;
+        let bus = Bus::load_state(&state)?;
+        self.cpu.load_state(&state, bus.ram_size())?;
+        self.bus = bus;
+        self.frames = frame_count;
+        self.frame_cycles = frame_cycles;
+        self.audio.set_pattern(self.bus.audio_pattern());
+        self.audio.update(self.bus.get_sound_timer() > 0);
+        Ok(())
     }
 
-    pub fn get_display_buffer(&self) -> &[u8] {
+    /// One byte per pixel, row major, [`Chip8::width`] pixels per row.
+    pub fn framebuffer(&self) -> &[u8] {
         self.bus.get_display_buffer()
     }
 
-    pub fn set_key_pressed(&mut self, key: Option<u8>) {
-        self.bus.set_key_pressed(key);
+    /// The screen as a PNG in `palette`, each pixel `scale` pixels square:
+    /// 1 for the native resolution.
+    pub fn screenshot_png(&self, scale: usize, palette: &Palette) -> Vec<u8> {
+        image::to_png(self.framebuffer(), self.width(), scale, palette)
+    }
+
+    /// Current resolution; 128x64 while SUPER-CHIP hires mode is on.
+    pub fn width(&self) -> usize {
+        self.bus.display_width()
+    }
+
+    pub fn height(&self) -> usize {
+        self.bus.display_height()
+    }
+
+    /// Whether the program ran the SUPER-CHIP exit instruction.
+    pub fn has_exited(&self) -> bool {
+        self.cpu.has_exited()
+    }
+
+    /// Sets the whole keypad state; bit `n` set means key `n` is held.
+    pub fn set_keys(&mut self, keys: u16) {
+        self.bus.set_keys(keys);
+    }
+
+    pub fn key_down(&mut self, key: u8) {
+        self.bus.key_down(key);
+    }
+
+    pub fn key_up(&mut self, key: u8) {
+        self.bus.key_up(key);
+    }
+
+    pub fn keys(&self) -> u16 {
+        self.bus.get_keys()
+    }
+
+    /// Registers, I, PC and the stack, for debuggers.
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    /// Registers, I and PC can be changed from a debugger through this.
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    /// The byte at `address`, or `None` past the end of memory.
+    pub fn read_memory(&self, address: u16) -> Option<u8> {
+        self.bus.ram_read_byte(address)
+    }
+
+    /// Stores `value` at `address`, or returns `None` past the end of memory.
+    pub fn write_memory(&mut self, address: u16, value: u8) -> Option<()> {
+        self.bus.ram_write_byte(address, value)
+    }
+
+    pub fn memory_size(&self) -> usize {
+        self.bus.ram_size()
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.bus.get_delay_timer()
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.bus.get_sound_timer()
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.bus.set_delay_timer(value);
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.bus.set_sound_timer(value);
+    }
+}
+
+impl Default for Chip8 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chip8_with(rom: &[u8]) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(rom).unwrap();
+        chip8
+    }
+
+    #[test]
+    fn timers_tick_once_per_frame() {
+        // 200: ld v0, 0xFF; 202: ld dt, v0; 204: jp 204
+        let rom = [0x60, 0xFF, 0xF0, 0x15, 0x12, 0x04];
+        for instructions in [3, INSTRUCTIONS_PER_FRAME, 1000] {
+            let mut chip8 = chip8_with(&rom);
+            chip8.set_instructions_per_frame(instructions);
+            for frame in 1..=10 {
+                chip8.run_frame().unwrap();
+                assert_eq!(chip8.frame_count(), frame);
+                assert_eq!(chip8.delay_timer(), 0xFF - frame as u8);
+            }
+        }
+    }
+
+    #[test]
+    fn display_wait_ends_the_frame_early() {
+        // 200: ld v0, 0x10; 202: ld dt, v0; 204: drw v1, v1, 1; 206: jp 206
+        let rom = [0x60, 0x10, 0xF0, 0x15, 0xD1, 0x11, 0x12, 0x06];
+        let mut chip8 = chip8_with(&rom);
+        assert!(chip8.quirks().display_wait);
+        chip8.run_frame().unwrap();
+        assert_eq!(chip8.cpu().pc(), 0x206);
+        assert_eq!((chip8.frame_count(), chip8.delay_timer()), (1, 0x0F));
+        // The next frame starts counting instructions from scratch.
+        for _ in 0..INSTRUCTIONS_PER_FRAME - 1 {
+            chip8.step().unwrap();
+        }
+        assert_eq!((chip8.frame_count(), chip8.delay_timer()), (1, 0x0F));
+        chip8.step().unwrap();
+        assert_eq!((chip8.frame_count(), chip8.delay_timer()), (2, 0x0E));
+
+        // Without the quirk the sprite doesn't end the frame.
+        let mut chip8 = chip8_with(&rom);
+        chip8.set_quirks(Quirks::schip());
+        for _ in 0..3 {
+            chip8.step().unwrap();
+        }
+        assert_eq!(chip8.frame_count(), 0);
     }
 }
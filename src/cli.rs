@@ -0,0 +1,297 @@
+//! Subcommands and helpers shared by the command-line front end.
+
+pub mod asm;
+pub mod debug;
+pub mod disasm;
+pub mod gdb;
+pub mod headless;
+pub mod replay;
+
+#[cfg(feature = "logging")]
+use env_logger::Env;
+use rust_chip_8::audio::Tone;
+use rust_chip_8::chip8::{INSTRUCTIONS_PER_FRAME, MAX_INSTRUCTIONS_PER_FRAME};
+use rust_chip_8::movie::Movie;
+use rust_chip_8::octo;
+use rust_chip_8::palette::{parse_palettes, Palette, PRESETS as PALETTES};
+use rust_chip_8::platform::{Platform, PLATFORMS};
+use rust_chip_8::quirks::{Quirks, PRESETS};
+use rust_chip_8::rewind::DEFAULT_CAPACITY;
+use rust_chip_8::video::{self, VideoFormat, VideoRecorder};
+use rust_chip_8::Chip8;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// Why a subcommand stopped. Bad arguments print the usage and exit with 2,
+/// anything else exits with 1.
+pub enum Failure {
+    Usage(String),
+    Error(String),
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Failure {
+        Failure::Usage(message)
+    }
+}
+
+impl From<&str> for Failure {
+    fn from(message: &str) -> Failure {
+        Failure::Usage(message.to_string())
+    }
+}
+
+/// Machine and frontend settings shared by the window and the terminal
+/// subcommands.
+pub struct Options {
+    pub rom: String,
+    pub platform: Platform,
+    pub instructions_per_frame: usize,
+    pub quirks: Quirks,
+    // Fixes CXNN's random numbers; random otherwise.
+    pub seed: Option<u64>,
+    pub tone: Tone,
+    // env_logger filter such as "cpu=trace,input=debug"; RUST_LOG also works.
+    pub log: Option<String>,
+    pub trace: Option<String>,
+    // Run the window under the debugger, taking commands from stdin.
+    pub debug: bool,
+    // Serve the window to GDB on this port instead.
+    pub gdb: Option<u16>,
+    // Memory for the window's rewind history, in MiB; 0 turns it off.
+    pub rewind: usize,
+    // Write the window's input to this movie, or play one back.
+    pub record: Option<String>,
+    pub replay: Option<String>,
+    // Record every frame as a GIF, or raw frames for anything else.
+    pub video: Option<String>,
+    pub video_scale: usize,
+    // A palette name or hex colours, and a file of more named palettes.
+    pub palette: Option<String>,
+    pub palette_file: Option<String>,
+}
+
+pub fn parse_options(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        rom: "data/INVADERS".to_string(),
+        platform: Platform::default(),
+        instructions_per_frame: INSTRUCTIONS_PER_FRAME,
+        quirks: Quirks::default(),
+        seed: None,
+        tone: Tone::default(),
+        log: None,
+        trace: None,
+        debug: false,
+        gdb: None,
+        rewind: DEFAULT_CAPACITY >> 20,
+        record: None,
+        replay: None,
+        video: None,
+        video_scale: video::DEFAULT_SCALE,
+        palette: None,
+        palette_file: None,
+    };
+    // Quirks default to the platform's, so apply them once everything is read.
+    let mut preset = None;
+    let mut overrides = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--platform" => options.platform = parse_platform(arg, args.next())?,
+            "--ipf" => match parse_value(arg, args.next())? {
+                count @ 1..=MAX_INSTRUCTIONS_PER_FRAME => options.instructions_per_frame = count,
+                _ => {
+                    let message = format!(
+                        "--ipf needs a number from 1 to {}",
+                        MAX_INSTRUCTIONS_PER_FRAME
+                    );
+                    return Err(message);
+                }
+            },
+            "--quirks" => {
+                let name: String = parse_value(arg, args.next())?;
+                preset = Some(Quirks::preset(&name).ok_or_else(|| {
+                    format!(
+                        "unknown quirks preset {}, expected one of {:?}",
+                        name, PRESETS
+                    )
+                })?);
+            }
+            "--quirk" => {
+                let setting: String = parse_value(arg, args.next())?;
+                let (name, value) = match setting.split_once('=') {
+                    Some((name, "on")) => (name, true),
+                    Some((name, "off")) => (name, false),
+                    _ => return Err(format!("expected NAME=on|off, got {}", setting)),
+                };
+                overrides.push((name.to_string(), value));
+            }
+            "--seed" => options.seed = Some(parse_value(arg, args.next())?),
+            "--tone" => options.tone.frequency = parse_value(arg, args.next())?,
+            "--volume" => options.tone.volume = parse_value(arg, args.next())?,
+            "--log" => options.log = Some(parse_value(arg, args.next())?),
+            "--trace" => options.trace = Some(parse_value(arg, args.next())?),
+            "--debug" => options.debug = true,
+            "--gdb" => options.gdb = Some(parse_value(arg, args.next())?),
+            "--rewind" => options.rewind = parse_value(arg, args.next())?,
+            "--record" => options.record = Some(parse_value(arg, args.next())?),
+            "--replay" => options.replay = Some(parse_value(arg, args.next())?),
+            "--video" => options.video = Some(parse_value(arg, args.next())?),
+            "--video-scale" => match parse_value(arg, args.next())? {
+                scale @ 1..=video::MAX_SCALE => options.video_scale = scale,
+                _ => {
+                    let message = format!(
+                        "--video-scale needs a number from 1 to {}",
+                        video::MAX_SCALE
+                    );
+                    return Err(message);
+                }
+            },
+            "--palette" => options.palette = Some(parse_value(arg, args.next())?),
+            "--palette-file" => options.palette_file = Some(parse_value(arg, args.next())?),
+            flag if flag.starts_with("--") => return Err(format!("unknown option {}", flag)),
+            rom => options.rom = rom.to_string(),
+        }
+    }
+    let frontends = [
+        ("--debug", options.debug),
+        ("--gdb", options.gdb.is_some()),
+        ("--record", options.record.is_some()),
+        ("--replay", options.replay.is_some()),
+    ];
+    let chosen: Vec<&str> = frontends
+        .iter()
+        .filter(|(_, chosen)| *chosen)
+        .map(|(flag, _)| *flag)
+        .collect();
+    if chosen.len() > 1 {
+        return Err(format!("{} can't be used together", chosen.join(" and ")));
+    }
+    options.quirks = preset.unwrap_or_else(|| options.platform.quirks());
+    for (name, value) in overrides {
+        options.quirks.set(&name, value)?;
+    }
+    Ok(options)
+}
+
+impl Options {
+    /// Sets up logging; diagnostics are off unless asked for.
+    #[cfg(feature = "logging")]
+    pub fn init_logger(&self) {
+        let mut logger = env_logger::Builder::from_env(Env::default().default_filter_or("off"));
+        if let Some(filter) = &self.log {
+            logger.parse_filters(filter);
+        }
+        logger.init();
+    }
+
+    #[cfg(not(feature = "logging"))]
+    pub fn init_logger(&self) {
+        if self.log.is_some() {
+            eprintln!("built without the logging feature; --log is ignored");
+        }
+    }
+
+    /// A machine configured from these options with the ROM loaded.
+    pub fn create_chip8(&self) -> Result<Chip8, String> {
+        let data = load_program(&self.rom)?;
+        let mut chip8 = Chip8::new();
+        chip8.set_instructions_per_frame(self.instructions_per_frame);
+        chip8.set_platform(self.platform);
+        chip8.set_quirks(self.quirks);
+        if let Some(seed) = self.seed {
+            chip8.set_seed(seed);
+        }
+        if let Some(path) = &self.trace {
+            let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+            chip8.set_trace(Some(Box::new(BufWriter::new(file))));
+        }
+        chip8
+            .load_rom(&data)
+            .map_err(|e| format!("{}: {}", self.rom, e))?;
+        Ok(chip8)
+    }
+
+    /// The palettes to cycle through, presets first and then those from
+    /// `--palette-file`, and the index of the one `--palette` picks. A
+    /// palette given as colours joins the end as "custom".
+    pub fn palettes(&self) -> Result<(Vec<(String, Palette)>, usize), String> {
+        let mut palettes: Vec<(String, Palette)> = PALETTES
+            .iter()
+            .filter_map(|&name| Some((name.to_string(), Palette::preset(name)?)))
+            .collect();
+        if let Some(path) = &self.palette_file {
+            let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            palettes.extend(parse_palettes(&text).map_err(|e| format!("{}: {}", path, e))?);
+        }
+        let Some(chosen) = &self.palette else {
+            return Ok((palettes, 0));
+        };
+        // The file can redefine a preset, so the last of a name wins.
+        if let Some(index) = palettes.iter().rposition(|(name, _)| name == chosen) {
+            return Ok((palettes, index));
+        }
+        let index = palettes.len();
+        palettes.push(("custom".to_string(), chosen.parse()?));
+        Ok((palettes, index))
+    }
+}
+
+pub fn parse_value<T: FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("invalid value {} for {}", value, flag))
+}
+
+pub fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Reads a movie file from `--replay` or the `replay` subcommand.
+pub fn read_movie(path: &str) -> Result<Movie, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    Movie::parse(&text).map_err(|e| format!("{}: {}", path, e))
+}
+
+/// A recording being written to a file, or to standard output for `-`.
+pub type Video = VideoRecorder<Box<dyn Write>>;
+
+pub fn start_video(path: &str, scale: usize, palette: Palette) -> Result<Video, String> {
+    let writer: Box<dyn Write> = if path == "-" {
+        Box::new(BufWriter::new(io::stdout()))
+    } else {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        Box::new(BufWriter::new(file))
+    };
+    VideoRecorder::new(VideoFormat::from_path(path), writer, scale, palette)
+        .map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Ends a recording, saying how long it was.
+pub fn finish_video(video: Video, path: &str) -> Result<String, String> {
+    let frames = video.frame_count();
+    video.finish().map_err(|e| format!("{}: {}", path, e))?;
+    Ok(format!("recorded {} frames to {}", frames, path))
+}
+
+/// Reads a ROM, compiling it first when it is Octo source.
+pub fn load_program(path: &str) -> Result<Vec<u8>, String> {
+    if Path::new(path)
+        .extension()
+        .is_some_and(|extension| extension == "8o")
+    {
+        let source = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        return octo::compile(&source)
+            .map_err(|e| format!("{}:{}\n{}", path, e, e.snippet(&source)));
+    }
+    read_file(path)
+}
+
+pub fn parse_platform(flag: &str, value: Option<&String>) -> Result<Platform, String> {
+    let name: String = parse_value(flag, value)?;
+    Platform::from_name(&name)
+        .ok_or_else(|| format!("unknown platform {}, expected one of {:?}", name, PLATFORMS))
+}
@@ -0,0 +1,34 @@
+//! `asm`: assemble source into a ROM.
+
+use rust_chip_8::assembler::assemble_file;
+use std::fs;
+use std::path::Path;
+
+use super::{parse_value, Failure};
+
+pub const USAGE: &str = "usage: rust-chip-8 asm [-o OUT] SOURCE";
+
+pub fn run(args: &[String]) -> Result<(), Failure> {
+    let mut output: Option<String> = None;
+    let mut source = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => output = Some(parse_value(arg, args.next())?),
+            flag if flag.starts_with('-') => {
+                return Err(Failure::Usage(format!("unknown option {}", flag)))
+            }
+            path => source = Some(path.to_string()),
+        }
+    }
+    let source = source.ok_or("no source file given")?;
+    // game.asm assembles to game.ch8 unless told otherwise.
+    let output = output.unwrap_or_else(|| {
+        Path::new(&source)
+            .with_extension("ch8")
+            .display()
+            .to_string()
+    });
+    let rom = assemble_file(&source).map_err(|e| Failure::Error(e.to_string()))?;
+    fs::write(&output, rom).map_err(|e| Failure::Error(format!("{}: {}", output, e)))
+}
@@ -0,0 +1,74 @@
+//! `debug`: the debugger on a terminal, without a window.
+
+use rust_chip_8::debugger::Debugger;
+use std::io::{self, IsTerminal, Write};
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+
+use super::{parse_options, Failure};
+
+pub const USAGE: &str = "usage: rust-chip-8 debug [machine options] ROM|SOURCE.8o";
+
+pub const PROMPT: &str = "(chip8) ";
+
+pub fn run(args: &[String]) -> Result<(), Failure> {
+    let options = parse_options(args)?;
+    options.init_logger();
+    let mut chip8 = options.create_chip8().map_err(Failure::Error)?;
+    let mut debugger = Debugger::new();
+    // At a terminal, pressing Enter stops a run that would go on forever.
+    // Piped commands are all read at once, so there they wait their turn.
+    let interruptible = io::stdin().is_terminal();
+    let lines = stdin_lines();
+
+    println!("{}", debugger.execute(&mut chip8, "list"));
+    prompt();
+    while let Ok(line) = lines.recv() {
+        show(&debugger.execute(&mut chip8, &line));
+        if debugger.is_running() && interruptible {
+            println!("running, press Enter to stop");
+        }
+        // Nothing paces a headless run, so go flat out until it stops.
+        while debugger.is_running() {
+            if interruptible && lines.try_recv() != Err(TryRecvError::Empty) {
+                if let Some(message) = debugger.interrupt(&chip8) {
+                    show(&message);
+                }
+                break;
+            }
+            if let Some(message) = debugger.run_frame(&mut chip8) {
+                show(&message);
+            }
+        }
+        if debugger.has_quit() {
+            return Ok(());
+        }
+        prompt();
+    }
+    Ok(())
+}
+
+/// Lines typed on stdin, read on a separate thread so that whatever runs
+/// meanwhile stays live.
+pub fn stdin_lines() -> mpsc::Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+pub fn show(output: &str) {
+    if !output.is_empty() {
+        println!("{}", output);
+    }
+}
+
+pub fn prompt() {
+    print!("{}", PROMPT);
+    let _ = io::stdout().flush();
+}
@@ -0,0 +1,27 @@
+//! `disasm`: print a ROM as assembler source.
+
+use rust_chip_8::disassembler::disassemble;
+use rust_chip_8::platform::Platform;
+
+use super::{parse_platform, read_file, Failure};
+
+pub const USAGE: &str = "usage: rust-chip-8 disasm [--platform chip8|schip|xochip] ROM";
+
+pub fn run(args: &[String]) -> Result<(), Failure> {
+    let mut platform = Platform::default();
+    let mut rom = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--platform" => platform = parse_platform(arg, args.next())?,
+            flag if flag.starts_with("--") => {
+                return Err(Failure::Usage(format!("unknown option {}", flag)))
+            }
+            path => rom = Some(path.to_string()),
+        }
+    }
+    let rom = rom.ok_or("no ROM given")?;
+    let data = read_file(&rom).map_err(Failure::Error)?;
+    print!("; {}\n{}", rom, disassemble(&data, platform));
+    Ok(())
+}
@@ -0,0 +1,40 @@
+//! `gdb`: a GDB remote stub on a local port, without a window.
+
+use rust_chip_8::gdb::{GdbServer, DEFAULT_PORT};
+use std::thread;
+use std::time::Duration;
+
+use super::{parse_options, parse_value, Failure};
+
+pub const USAGE: &str = "usage: rust-chip-8 gdb [--port N] [machine options] ROM|SOURCE.8o";
+
+const FRAME: Duration = Duration::from_micros(16_667);
+
+pub fn run(args: &[String]) -> Result<(), Failure> {
+    let mut port = DEFAULT_PORT;
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => port = parse_value(arg, args.next())?,
+            _ => rest.push(arg.clone()),
+        }
+    }
+    let options = parse_options(&rest)?;
+    options.init_logger();
+    let mut chip8 = options.create_chip8().map_err(Failure::Error)?;
+
+    let mut server =
+        GdbServer::bind(port).map_err(|e| Failure::Error(format!("port {}: {}", port, e)))?;
+    if let Ok(address) = server.local_addr() {
+        eprintln!("waiting for gdb on {} (target remote {})", address, address);
+    }
+    // Keep to 60 frames a second so timers behave as they would in a window.
+    while !server.is_killed() {
+        server
+            .run_frame(&mut chip8)
+            .map_err(|e| Failure::Error(format!("gdb: {}", e)))?;
+        thread::sleep(FRAME);
+    }
+    Ok(())
+}
@@ -0,0 +1,224 @@
+//! `headless`: runs a ROM without a window and dumps the screen.
+
+use rust_chip_8::image::{ImageFormat, FORMATS};
+use rust_chip_8::palette::Palette;
+use rust_chip_8::Chip8;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+use super::{finish_video, parse_options, parse_value, start_video, Failure, Video};
+
+pub const USAGE: &str =
+    "usage: rust-chip-8 headless [--frames N | --cycles N] [--keys FRAME:KEYS,...]
+                            [--output FILE] [--format ascii|pbm|png] [--every N]
+                            [--video FILE.gif|FILE|-] [--video-scale N]
+                            [machine options] ROM|SOURCE.8o";
+
+const DEFAULT_FRAMES: u64 = 600;
+
+enum Length {
+    Frames(u64),
+    Cycles(u64),
+}
+
+struct Dump {
+    // Standard output when unset.
+    output: Option<String>,
+    format: ImageFormat,
+    // Also dump every Nth frame.
+    every: Option<u64>,
+    // Every frame also goes to this recording.
+    video: Option<(String, Video)>,
+    palette: Palette,
+}
+
+pub fn run(args: &[String]) -> Result<(), Failure> {
+    let mut length = Length::Frames(DEFAULT_FRAMES);
+    let mut keys = Vec::new();
+    let mut output: Option<String> = None;
+    let mut format = None;
+    let mut every = None;
+    let mut rest = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--frames" => length = Length::Frames(parse_value(arg, args.next())?),
+            "--cycles" => length = Length::Cycles(parse_value(arg, args.next())?),
+            "--keys" => keys = parse_keys(&parse_value::<String>(arg, args.next())?)?,
+            "--output" | "-o" => output = Some(parse_value(arg, args.next())?),
+            "--format" => {
+                let name: String = parse_value(arg, args.next())?;
+                format = Some(ImageFormat::from_name(&name).ok_or_else(|| {
+                    format!("unknown format {}, expected one of {:?}", name, FORMATS)
+                })?);
+            }
+            "--every" => match parse_value(arg, args.next())? {
+                0 => return Err("--every needs a positive number".into()),
+                n => every = Some(n),
+            },
+            _ => rest.push(arg.clone()),
+        }
+    }
+    // The output's extension picks the format unless one is given.
+    let format = format
+        .or_else(|| {
+            let path = output.as_ref()?;
+            let extension = Path::new(path).extension()?.to_str()?;
+            ImageFormat::from_name(&extension.to_lowercase())
+        })
+        .unwrap_or(ImageFormat::Ascii);
+    let options = parse_options(&rest)?;
+    if options.video.as_deref() == Some("-") && output.is_none() {
+        return Err("--video - needs --output for the screen".into());
+    }
+    options.init_logger();
+    let mut chip8 = options.create_chip8().map_err(Failure::Error)?;
+    let (palettes, chosen) = options.palettes().map_err(Failure::Error)?;
+    let palette = palettes[chosen].1;
+    let video = match &options.video {
+        Some(path) => Some((
+            path.clone(),
+            start_video(path, options.video_scale, palette).map_err(Failure::Error)?,
+        )),
+        None => None,
+    };
+    let mut dump = Dump {
+        output,
+        format,
+        every,
+        video,
+        palette,
+    };
+
+    let result = match length {
+        Length::Frames(frames) => run_frames(&mut chip8, frames, &keys, &mut dump),
+        Length::Cycles(cycles) => run_cycles(&mut chip8, cycles, &keys, &mut dump),
+    };
+    // The screen as the error left it is worth seeing too.
+    dump.write(&chip8, None)?;
+    if let Some((path, video)) = dump.video {
+        eprintln!("{}", finish_video(video, &path).map_err(Failure::Error)?);
+    }
+    result
+}
+
+// Keys change at the start of the frames listed.
+fn parse_keys(spec: &str) -> Result<Vec<(u64, u16)>, String> {
+    let mut script = Vec::new();
+    for entry in spec.split(',').filter(|entry| !entry.is_empty()) {
+        let (frame, keys) = entry
+            .split_once(':')
+            .ok_or_else(|| format!("expected FRAME:KEYS, got {}", entry))?;
+        let frame = frame
+            .parse()
+            .map_err(|_| format!("invalid frame {} in --keys", frame))?;
+        // Hex digits of the keys held, or - for none.
+        let mut mask = 0u16;
+        for key in keys.chars().filter(|&key| key != '-') {
+            let key = key
+                .to_digit(16)
+                .ok_or_else(|| format!("invalid key {} in --keys", key))?;
+            mask |= 1 << key;
+        }
+        script.push((frame, mask));
+    }
+    script.sort_by_key(|&(frame, _)| frame);
+    Ok(script)
+}
+
+fn apply_keys(chip8: &mut Chip8, keys: &[(u64, u16)]) {
+    let frame = chip8.frame_count();
+    if let Some(&(_, mask)) = keys.iter().rev().find(|&&(start, _)| start <= frame) {
+        chip8.set_keys(mask);
+    }
+}
+
+fn stopped(chip8: &Chip8, error: rust_chip_8::Chip8Error) -> Failure {
+    Failure::Error(format!("frame {}: {}", chip8.frame_count(), error))
+}
+
+fn run_frames(
+    chip8: &mut Chip8,
+    frames: u64,
+    keys: &[(u64, u16)],
+    dump: &mut Dump,
+) -> Result<(), Failure> {
+    while chip8.frame_count() < frames && !chip8.has_exited() {
+        apply_keys(chip8, keys);
+        chip8.run_frame().map_err(|e| stopped(chip8, e))?;
+        dump.frame_done(chip8)?;
+    }
+    Ok(())
+}
+
+fn run_cycles(
+    chip8: &mut Chip8,
+    cycles: u64,
+    keys: &[(u64, u16)],
+    dump: &mut Dump,
+) -> Result<(), Failure> {
+    for _ in 0..cycles {
+        if chip8.has_exited() {
+            break;
+        }
+        apply_keys(chip8, keys);
+        let frame = chip8.frame_count();
+        chip8.step().map_err(|e| stopped(chip8, e))?;
+        if chip8.frame_count() != frame {
+            dump.frame_done(chip8)?;
+        }
+    }
+    Ok(())
+}
+
+impl Dump {
+    fn frame_done(&mut self, chip8: &Chip8) -> Result<(), Failure> {
+        if let Some((path, video)) = &mut self.video {
+            video
+                .frame(chip8)
+                .map_err(|e| Failure::Error(format!("{}: {}", path, e)))?;
+        }
+        match self.every {
+            Some(every) if chip8.frame_count().is_multiple_of(every) => {
+                self.write(chip8, Some(chip8.frame_count()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Frame dumps go next to the final one, numbered.
+    fn write(&self, chip8: &Chip8, frame: Option<u64>) -> Result<(), Failure> {
+        let data = self
+            .format
+            .encode(chip8.framebuffer(), chip8.width(), &self.palette);
+        let Some(output) = &self.output else {
+            let mut stdout = io::stdout().lock();
+            if let Some(frame) = frame {
+                writeln!(stdout, "frame {}", frame).map_err(|e| Failure::Error(e.to_string()))?;
+            }
+            return stdout
+                .write_all(&data)
+                .map_err(|e| Failure::Error(e.to_string()));
+        };
+        let path = match frame {
+            Some(frame) => numbered(output, frame),
+            None => output.clone(),
+        };
+        fs::write(&path, data).map_err(|e| Failure::Error(format!("{}: {}", path, e)))
+    }
+}
+
+// out.png becomes out-000060.png.
+fn numbered(path: &str, frame: u64) -> String {
+    let path = Path::new(path);
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("");
+    let name = match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => format!("{}-{:06}.{}", stem, frame, extension),
+        None => format!("{}-{:06}", stem, frame),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
@@ -0,0 +1,47 @@
+//! `replay`: plays a movie back without a window and reports how it ended.
+
+use rust_chip_8::movie::{Input, Movie};
+use rust_chip_8::Chip8;
+use sha1::{Digest, Sha1};
+
+use super::{load_program, read_movie, Failure};
+
+pub const USAGE: &str = "usage: rust-chip-8 replay MOVIE ROM|SOURCE.8o";
+
+pub fn run(args: &[String]) -> Result<(), Failure> {
+    let [movie_path, rom] = args else {
+        return Err("expected a movie and a ROM".into());
+    };
+    let movie = read_movie(movie_path).map_err(Failure::Error)?;
+    let data = load_program(rom).map_err(Failure::Error)?;
+    let mut chip8 = Chip8::new();
+    chip8
+        .load_rom(&data)
+        .map_err(|e| format!("{}: {}", rom, e))
+        .map_err(Failure::Error)?;
+    movie
+        .start(&mut chip8)
+        .map_err(|e| Failure::Error(format!("{}: {}", movie_path, e)))?;
+
+    let mut frame = 0;
+    for &input in &movie.inputs {
+        Movie::play(&mut chip8, input)
+            .map_err(|e| Failure::Error(format!("frame {}: {}", frame, e)))?;
+        if let Input::Frame(_) = input {
+            frame += 1;
+        }
+    }
+    // The hash makes it easy to tell whether two runs ended the same way.
+    let screen: String = Sha1::digest(chip8.framebuffer())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    println!(
+        "{} frames, screen {}x{} sha1 {}",
+        frame,
+        chip8.width(),
+        chip8.height(),
+        screen
+    );
+    Ok(())
+}
@@ -1,333 +1,1053 @@
 use crate::bus::Bus;
-use rand::distributions::Distribution;
-use rand::rngs::ThreadRng;
+use crate::error::Chip8Error;
+use crate::instruction::{decode, AluOp, Instruction};
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+use crate::ram::{BIG_FONT_START, FONT_START};
+use crate::random::{ChaChaSource, RandomSource};
+use crate::state::{ChunkWriter, StateError, StateReader, StateWriter};
+use log::{debug, trace};
 use std::fmt;
 
 pub const PROGRAM_START: u16 = 0x200;
+pub const STACK_DEPTH: usize = 16;
 
 pub struct Cpu {
     vx: [u8; 16],
     pc: u16,
     i: u16,
-    prev_pc: u16,
+    opcode: u16,
     ret_stack: Vec<u16>,
-    rng: ThreadRng,
+    waiting_for_key: bool,
+    waiting_for_vblank: bool,
+    exited: bool,
+    // SUPER-CHIP "RPL user flags", kept across resets like on the HP 48.
+    rpl: [u8; 16],
+    quirks: Quirks,
+    platform: Platform,
+    // A random seed unless one is set; resets start the sequence over.
+    seed: u64,
+    rng: Box<dyn RandomSource>,
 }
 
 impl Cpu {
     pub fn new() -> Cpu {
+        let seed = rand::random();
         Cpu {
             vx: [0; 16],
             pc: PROGRAM_START,
             i: 0,
-            prev_pc: 0,
+            opcode: 0,
             ret_stack: Vec::<u16>::new(),
-            rng: rand::thread_rng(),
+            waiting_for_key: false,
+            waiting_for_vblank: false,
+            exited: false,
+            rpl: [0; 16],
+            quirks: Quirks::default(),
+            platform: Platform::default(),
+            seed,
+            rng: Box::new(ChaChaSource::new(seed)),
         }
     }
 
-    pub fn run_instruction(&mut self, bus: &mut Bus) {
-        let hi = bus.ram_read_byte(self.pc) as u16;
-        let lo = bus.ram_read_byte(self.pc + 1) as u16;
-        let instruction: u16 = (hi << 8) | lo;
-        println!(
-            "Instruction read {:#X}:{:#X}: hi{:#X} lo:{:#X} ",
-            self.pc, instruction, hi, lo
-        );
+    /// Back to the power-on state, keeping the configuration, RPL flags and
+    /// random source, which starts over from the seed.
+    pub fn reset(&mut self) {
+        let mut rng = std::mem::replace(&mut self.rng, Box::new(ChaChaSource::new(0)));
+        rng.reseed(self.seed);
+        *self = Cpu {
+            rpl: self.rpl,
+            quirks: self.quirks,
+            platform: self.platform,
+            seed: self.seed,
+            rng,
+            ..Cpu::new()
+        };
+    }
+
+    /// The seed CXNN's random numbers come from.
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Restarts the random number sequence from `seed`, here and after
+    /// every reset.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.rng.reseed(seed);
+    }
+
+    /// Replaces the random source, which starts from the current seed.
+    pub fn set_random_source(&mut self, mut rng: Box<dyn RandomSource>) {
+        rng.reseed(self.seed);
+        self.rng = rng;
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.vx
+    }
+
+    pub fn stack(&self) -> &[u16] {
+        &self.ret_stack
+    }
+
+    pub fn set_pc(&mut self, pc: u16) {
+        self.pc = pc;
+    }
+
+    pub fn set_i(&mut self, i: u16) {
+        self.i = i;
+    }
+
+    pub fn set_register(&mut self, x: u8, value: u8) {
+        self.vx[x as usize & 0xF] = value;
+    }
+
+    pub fn platform(&self) -> Platform {
+        self.platform
+    }
+
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.platform = platform;
+    }
+
+    /// Whether the program ran 00FD. Nothing executes after that.
+    pub fn has_exited(&self) -> bool {
+        self.exited
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
 
-        let nnn = instruction & 0x0FFF;
-        let nn = (instruction & 0x0FF) as u8;
-        let n = (instruction & 0x00F) as u8;
-        let x = ((instruction & 0x0F00) >> 8) as u8;
-        let y = ((instruction & 0x00F0) >> 4) as u8;
-        println!("nnn={:?}, nn={:?}, n={:?} x={}, y={}", nnn, nn, n, x, y);
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
 
-        if self.prev_pc == self.pc {
-            panic!("Please increment PC!");
+    /// Adds the registers, stack, configuration and RNG position to a save
+    /// state.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        let mut cpu = ChunkWriter::new();
+        cpu.bytes(&self.vx)
+            .u16(self.i)
+            .u16(self.pc)
+            .u16(self.opcode);
+        cpu.u8(self.ret_stack.len() as u8);
+        for &address in &self.ret_stack {
+            cpu.u16(address);
         }
-        self.prev_pc = self.pc;
-
-        match (instruction & 0xF000) >> 12 {
-            0x0 => {
-                match nn {
-                    0xE0 => {
-                        bus.clear_screen();
-                        self.pc += 2;
-                    }
-                    0xEE => {
-                        //return from subroutine
-                        let addr = self.ret_stack.pop().unwrap();
-                        self.pc = addr;
-                    }
-                    _ => panic!(
-                        "Unrecognized 0x00** instruction {:#X}:{:#X}",
-                        self.pc, instruction
-                    ),
-                }
+        cpu.bool(self.waiting_for_key)
+            .bool(self.waiting_for_vblank)
+            .bool(self.exited)
+            .bytes(&self.rpl);
+        state.chunk("CPU ", cpu);
+
+        let mut config = ChunkWriter::new();
+        config
+            .str(self.platform.name())
+            .str(&self.quirks.to_string());
+        state.chunk("CONF", config);
+
+        let mut rng = ChunkWriter::new();
+        rng.u64(self.seed).u128(self.rng.position());
+        state.chunk("RNG ", rng);
+    }
+
+    /// Restores the CPU a save state describes, keeping the random source
+    /// but moving it to the saved position. Nothing changes on error,
+    /// including when the saved platform doesn't have `memory_size` bytes of
+    /// memory.
+    pub fn load_state(
+        &mut self,
+        state: &StateReader,
+        memory_size: usize,
+    ) -> Result<(), StateError> {
+        let mut cpu = state.chunk("CPU ")?;
+        let vx = cpu.bytes()?.try_into().map_err(|_| cpu.corrupt())?;
+        let i = cpu.u16()?;
+        let pc = cpu.u16()?;
+        let opcode = cpu.u16()?;
+        let depth = cpu.u8()? as usize;
+        if depth > STACK_DEPTH {
+            return Err(cpu.corrupt());
+        }
+        let ret_stack = (0..depth).map(|_| cpu.u16()).collect::<Result<_, _>>()?;
+        let waiting_for_key = cpu.bool()?;
+        let waiting_for_vblank = cpu.bool()?;
+        let exited = cpu.bool()?;
+        let rpl = cpu.bytes()?.try_into().map_err(|_| cpu.corrupt())?;
+
+        let mut config = state.chunk("CONF")?;
+        let platform = Platform::from_name(config.str()?).ok_or(config.corrupt())?;
+        let quirks = config.str()?.parse().map_err(|_| config.corrupt())?;
+        if platform.memory_size() != memory_size {
+            return Err(StateError::Corrupt("RAM "));
+        }
+
+        let mut rng = state.chunk("RNG ")?;
+        let seed = rng.u64()?;
+        let position = rng.u128()?;
+
+        self.vx = vx;
+        self.pc = pc;
+        self.i = i;
+        self.opcode = opcode;
+        self.ret_stack = ret_stack;
+        self.waiting_for_key = waiting_for_key;
+        self.waiting_for_vblank = waiting_for_vblank;
+        self.exited = exited;
+        self.rpl = rpl;
+        self.quirks = quirks;
+        self.platform = platform;
+        self.seed = seed;
+        self.rng.seek(seed, position);
+        Ok(())
+    }
+
+    /// Whether the last instruction asked to end the frame early, as DXYN does
+    /// with the display wait quirk. Clears the request.
+    pub fn take_vblank_wait(&mut self) -> bool {
+        std::mem::take(&mut self.waiting_for_vblank)
+    }
+
+    /// Fetches, decodes and executes the instruction at PC.
+    pub fn run_instruction(&mut self, bus: &mut Bus) -> Result<(), Chip8Error> {
+        if self.exited {
+            return Ok(());
+        }
+        self.opcode = 0;
+        let hi = self.read_byte(bus, self.pc)? as u16;
+        let lo = self.read_byte(bus, self.pc.wrapping_add(1))? as u16;
+        let opcode: u16 = (hi << 8) | lo;
+        self.opcode = opcode;
+        trace!(target: "cpu", "{:#05X}: {:#06X}", self.pc, opcode);
+
+        let instruction = match decode(opcode) {
+            Instruction::LoadILong(_) => {
+                let hi = self.read_byte(bus, self.pc.wrapping_add(2))? as u16;
+                let lo = self.read_byte(bus, self.pc.wrapping_add(3))? as u16;
+                Instruction::LoadILong((hi << 8) | lo)
+            }
+            instruction => instruction,
+        };
+        self.execute(bus, instruction)
+    }
+
+    /// Executes `instruction` as if it were at PC. Nothing changes if it
+    /// fails.
+    pub fn execute(&mut self, bus: &mut Bus, instruction: Instruction) -> Result<(), Chip8Error> {
+        if !instruction.is_supported_by(self.platform) {
+            return Err(self.unknown_opcode());
+        }
+        let mut next_pc = self.pc.wrapping_add(instruction.size());
+
+        match instruction {
+            Instruction::ClearScreen => bus.clear_screen(),
+            Instruction::Return => {
+                //return from subroutine
+                next_pc = self.ret_stack.pop().ok_or(Chip8Error::StackUnderflow {
+                    pc: self.pc,
+                    opcode: self.opcode,
+                })?;
             }
-            0x1 => {
-                //goto nnn;
-                self.pc = nnn;
+            Instruction::ScrollDown(n) => bus.scroll_down(n as usize),
+            Instruction::ScrollUp(n) => bus.scroll_up(n as usize),
+            Instruction::ScrollRight => bus.scroll_right(4),
+            Instruction::ScrollLeft => bus.scroll_left(4),
+            Instruction::Exit => {
+                // Exit the interpreter
+                debug!(target: "cpu", "{:#05X}: program exited", self.pc);
+                self.exited = true;
+                next_pc = self.pc;
             }
-            0x2 => {
+            Instruction::LowRes => bus.set_hires(false),
+            Instruction::HighRes => bus.set_hires(true),
+            Instruction::Jump(nnn) => next_pc = nnn,
+            Instruction::Call(nnn) => {
                 //Call subroutine at address NNN
-                self.ret_stack.push(self.pc + 2);
-                self.pc = nnn;
+                if self.ret_stack.len() == STACK_DEPTH {
+                    return Err(Chip8Error::StackOverflow {
+                        pc: self.pc,
+                        opcode: self.opcode,
+                    });
+                }
+                self.ret_stack.push(next_pc);
+                next_pc = nnn;
             }
-            0x3 => {
-                //if(Vx==NN)
-                let vx = self.read_vx(x);
-                if vx == nn {
-                    self.pc += 4;
-                } else {
-                    self.pc += 2;
+            Instruction::SkipEqImm(x, nn) => {
+                if self.read_vx(x) == nn {
+                    next_pc = self.pc.wrapping_add(self.skip_length(bus));
                 }
             }
-            0x4 => {
-                //if(Vx!=NN)
-                let vx = self.read_vx(x);
-                if vx != nn {
-                    self.pc += 4;
-                } else {
-                    self.pc += 2;
+            Instruction::SkipNeImm(x, nn) => {
+                if self.read_vx(x) != nn {
+                    next_pc = self.pc.wrapping_add(self.skip_length(bus));
                 }
             }
-            0x5 => {
-                //Skip next instruction if(Vx==Vy)
-                let vx = self.read_vx(x);
-                let vy = self.read_vx(y);
-                if vx == vy {
-                    self.pc += 4;
-                } else {
-                    self.pc += 2;
+            Instruction::SkipEqReg(x, y) => {
+                if self.read_vx(x) == self.read_vx(y) {
+                    next_pc = self.pc.wrapping_add(self.skip_length(bus));
                 }
             }
-            0x6 => {
-                //vx = nn
-                self.write_vx(x, nn);
-                self.pc += 2;
+            Instruction::SaveRange(x, y) => {
+                // Save Vx..=Vy to memory at i, i unchanged
+                self.read_byte(bus, self.i.wrapping_add(x.abs_diff(y) as u16))?;
+                for (offset, index) in Cpu::register_range(x, y).enumerate() {
+                    let value = self.read_vx(index);
+                    self.write_byte(bus, self.i.wrapping_add(offset as u16), value)?;
+                }
+            }
+            Instruction::LoadRange(x, y) => {
+                // Load Vx..=Vy from memory at i, i unchanged
+                let mut values = Vec::new();
+                for offset in 0..=x.abs_diff(y) {
+                    values.push(self.read_byte(bus, self.i.wrapping_add(offset as u16))?);
+                }
+                for (index, value) in Cpu::register_range(x, y).zip(values) {
+                    self.write_vx(index, value);
+                }
             }
-            0x7 => {
+            Instruction::LoadImm(x, nn) => self.write_vx(x, nn),
+            Instruction::AddImm(x, nn) => {
                 let vx = self.read_vx(x);
                 self.write_vx(x, vx.wrapping_add(nn));
-                self.pc += 2;
             }
-            0x8 => {
-                let vy = self.read_vx(y);
-                let vx = self.read_vx(x);
-
-                match n {
-                    0 => {
-                        // Vx=Vy
-                        self.write_vx(x, vy);
-                    }
-                    2 => {
-                        // Vx=Vx&Vy
-                        self.write_vx(x, vx & vy);
-                    }
-                    3 => {
-                        // Vx=Vx^Vy
-                        self.write_vx(x, vx ^ vy);
-                    }
-                    4 => {
-                        //	Vx += Vy
-                        let sum: u16 = vx as u16 + vy as u16;
-                        self.write_vx(x, sum as u8);
-                        if sum > 0xFF {
-                            self.write_vx(0xF, 1);
-                        }
-                    }
-                    5 => {
-                        let diff: i8 = vx as i8 - vy as i8;
-                        self.write_vx(x, diff as u8);
-                        if diff < 0 {
-                            self.write_vx(0xF, 1);
-                        }
-                    }
-                    6 => {
-                        // Vx=Vy=Vy>>1
-                        self.write_vx(0xF, vy & 0x1);
-                        self.write_vx(y, vy >> 1);
-                        self.write_vx(x, vy >> 1);
-                    }
-                    0x7 => {
-                        let diff: i8 = vy as i8 - vx as i8;
-                        self.write_vx(x, diff as u8);
-                        if diff < 0 {
-                            self.write_vx(0xF, 1);
-                        } else {
-                            self.write_vx(0xF, 0);
-                        }
-                    }
-                    0xE => {
-                        // VF is the most significant bit value.
-                        // SHR Vx
-                        self.write_vx(0xF, (vx & 0x80) >> 7);
-                        self.write_vx(x, vx << 1);
-                    }
-                    _ => panic!(
-                        "Unrecognized 0x8XY* instruction {:#X}:{:#X}",
-                        self.pc, instruction
-                    ),
+            Instruction::Alu(op, x, y) => {
+                let (result, flag) = self.alu(op, self.read_vx(x), self.read_vx(y));
+                // The flag goes in last, so it wins when x is F.
+                self.write_vx(x, result);
+                if let Some(flag) = flag {
+                    self.write_vx(0xF, flag);
+                }
+            }
+            Instruction::SkipNeReg(x, y) => {
+                if self.read_vx(x) != self.read_vx(y) {
+                    next_pc = self.pc.wrapping_add(self.skip_length(bus));
+                }
+            }
+            Instruction::LoadI(nnn) => self.i = nnn,
+            Instruction::JumpOffset(nnn) => {
+                let register = if self.quirks.jump_uses_vx {
+                    (nnn >> 8) as u8
+                } else {
+                    0
                 };
-
-                self.pc += 2;
+                next_pc = self.read_vx(register) as u16 + nnn;
             }
-            0x9 => {
-                //skips the next instruction if(Vx!=Vy)
+            Instruction::Random(x, nn) => {
+                // Vx=rand() & NN
+                let number = self.rng.next_byte();
+                self.write_vx(x, number & nn);
+            }
+            Instruction::Draw(x, y, n) => {
                 let vx = self.read_vx(x);
                 let vy = self.read_vx(y);
-                if vx != vy {
-                    self.pc += 4;
+                if n == 0 && self.platform.has_schip_instructions() {
+                    // 16x16 sprite, two bytes per row
+                    self.draw_sprite(bus, vx, vy, 16, 2)?;
                 } else {
-                    self.pc += 2;
+                    self.draw_sprite(bus, vx, vy, n, 1)?;
+                }
+                self.waiting_for_vblank = self.quirks.display_wait;
+            }
+            Instruction::SkipKeyPressed(x) => {
+                if bus.key_pressed(self.read_vx(x)) {
+                    next_pc = self.pc.wrapping_add(self.skip_length(bus));
                 }
             }
-            0xA => {
-                self.i = nnn;
-                self.pc += 2;
+            Instruction::SkipKeyNotPressed(x) => {
+                if !bus.key_pressed(self.read_vx(x)) {
+                    next_pc = self.pc.wrapping_add(self.skip_length(bus));
+                }
             }
-            0xB => {
-                self.pc = self.read_vx(0) as u16 + nnn;
+            Instruction::LoadILong(nnnn) => self.i = nnnn,
+            Instruction::SelectPlanes(n) => bus.select_planes(n),
+            Instruction::LoadAudio => {
+                // Load the 16-byte audio pattern at i
+                let mut buffer = [0; 16];
+                for (offset, byte) in buffer.iter_mut().enumerate() {
+                    *byte = self.read_byte(bus, self.i.wrapping_add(offset as u16))?;
+                }
+                bus.set_audio_buffer(buffer);
             }
-            0xC => {
-                // Vx=rand() & NN
-                let interval = rand::distributions::Uniform::new(0, 255);
-                let number = interval.sample(&mut self.rng);
-                self.write_vx(x, number & nn);
-                self.pc += 2;
+            Instruction::GetDelay(x) => self.write_vx(x, bus.get_delay_timer()),
+            Instruction::WaitKey(x) => {
+                // Wait for a key to be pressed and released. The PC stays put
+                // so this re-executes until it happens.
+                if !self.waiting_for_key {
+                    bus.clear_key_events();
+                    self.waiting_for_key = true;
+                }
+                match bus.take_released_key() {
+                    Some(key) => {
+                        self.waiting_for_key = false;
+                        self.write_vx(x, key);
+                    }
+                    None => next_pc = self.pc,
+                }
             }
-            0xD => {
-                // Draw sprite
+            Instruction::SetDelay(x) => bus.set_delay_timer(self.read_vx(x)),
+            Instruction::SetSound(x) => bus.set_sound_timer(self.read_vx(x)),
+            Instruction::AddI(x) => {
+                //I +=Vx
+                self.i = self.i.wrapping_add(self.read_vx(x) as u16);
+            }
+            Instruction::Font(x) => {
+                //i == sprite address for character in Vx
+                //Multiply by 5 because each sprite has 5 lines, each line
+                //is 1 byte.
+                self.i = FONT_START + self.read_vx(x) as u16 * 5;
+            }
+            Instruction::BigFont(x) => {
+                // i == big (8x10) sprite address for the digit in Vx
+                self.i = BIG_FONT_START + (self.read_vx(x) & 0xF) as u16 * 10;
+            }
+            Instruction::Bcd(x) => {
                 let vx = self.read_vx(x);
-                let vy = self.read_vx(y);
-                self.debug_draw_sprite(bus, vx, vy, n);
-                self.pc += 2;
-            }
-            0xE => {
-                match nn {
-                    0xA1 => {
-                        // Skip next instruction if key in VX is not pressed
-                        let key = self.read_vx(x);
-                        if !bus.key_pressed(key) {
-                            self.pc += 4;
-                        } else {
-                            self.pc += 2;
-                        }
-                    }
-                    0x9E => {
-                        // Skip next instruction if key in VX is pressed
-                        let key = self.read_vx(x);
-                        if bus.key_pressed(key) {
-                            self.pc += 4;
-                        } else {
-                            self.pc += 2;
-                        }
-                    }
-                    _ => panic!(
-                        "Unrecognized 0xEX** instruction {:#X}:{:#X}",
-                        self.pc, instruction
-                    ),
-                };
+                // Check the whole range first so a fault writes nothing.
+                self.read_byte(bus, self.i.wrapping_add(2))?;
+                self.write_byte(bus, self.i, vx / 100)?;
+                self.write_byte(bus, self.i.wrapping_add(1), (vx % 100) / 10)?;
+                self.write_byte(bus, self.i.wrapping_add(2), vx % 10)?;
             }
-            0xF => {
-                match nn {
-                    0x07 => {
-                        self.write_vx(x, bus.get_delay_timer());
-                        self.pc += 2;
-                    }
-                    0x0A => {
-                        if let Some(val) = bus.get_key_pressed() {
-                            self.write_vx(x, val);
-                            self.pc += 2;
-                        }
-                    }
-                    0x15 => {
-                        bus.set_delay_timer(self.read_vx(x));
-                        self.pc += 2;
-                    }
-                    0x18 => {
-                        // TODO Sound timer
-                        self.pc += 2;
-                    }
-                    0x1E => {
-                        //I +=Vx
-                        let vx = self.read_vx(x);
-                        self.i += vx as u16;
-                        self.pc += 2;
-                    }
-                    0x29 => {
-                        //i == sprite address for character in Vx
-                        //Multiply by 5 because each sprite has 5 lines, each line
-                        //is 1 byte.
-                        self.i = self.read_vx(x) as u16 * 5;
-                        self.pc += 2;
-                    }
-                    0x33 => {
-                        let vx = self.read_vx(x);
-                        bus.ram_write_byte(self.i, vx / 100);
-                        bus.ram_write_byte(self.i + 1, (vx % 100) / 10);
-                        bus.ram_write_byte(self.i + 2, vx % 10);
-                        self.pc += 2;
-                    }
-                    0x55 => {
-                        for index in 0..x + 1 {
-                            let value = self.read_vx(index);
-                            bus.ram_write_byte(self.i + index as u16, value);
-                        }
-                        self.i += x as u16 + 1;
-                        self.pc += 2;
-                    }
-                    0x65 => {
-                        for index in 0..x + 1 {
-                            let value = bus.ram_read_byte(self.i + index as u16);
-                            self.write_vx(index, value);
-                        }
-                        self.i += x as u16 + 1;
-                        self.pc += 2;
-                    }
-                    _ => panic!(
-                        "Unrecognized 0xF instruction {:#X}:{:#X}",
-                        self.pc, instruction
-                    ),
+            Instruction::SetPitch(x) => bus.set_audio_pitch(self.read_vx(x)),
+            Instruction::Store(x) => {
+                self.read_byte(bus, self.i.wrapping_add(x as u16))?;
+                for index in 0..=x {
+                    let value = self.read_vx(index);
+                    self.write_byte(bus, self.i.wrapping_add(index as u16), value)?;
+                }
+                if self.quirks.load_store_increments_i {
+                    self.i = self.i.wrapping_add(x as u16 + 1);
+                }
+            }
+            Instruction::Load(x) => {
+                let mut values = Vec::new();
+                for index in 0..=x {
+                    values.push(self.read_byte(bus, self.i.wrapping_add(index as u16))?);
+                }
+                for (index, value) in values.into_iter().enumerate() {
+                    self.write_vx(index as u8, value);
+                }
+                if self.quirks.load_store_increments_i {
+                    self.i = self.i.wrapping_add(x as u16 + 1);
                 }
             }
+            Instruction::SaveFlags(x) => {
+                // Save V0..=Vx to the RPL flags
+                for index in 0..=x {
+                    self.rpl[index as usize] = self.read_vx(index);
+                }
+            }
+            Instruction::LoadFlags(x) => {
+                // Restore V0..=Vx from the RPL flags
+                for index in 0..=x {
+                    self.write_vx(index, self.rpl[index as usize]);
+                }
+            }
+            Instruction::Sys(_) | Instruction::Invalid(_) => return Err(self.unknown_opcode()),
+        }
+
+        self.pc = next_pc;
+        Ok(())
+    }
 
-            _ => panic!("Unrecognized instruction {:#X}:{:#X}", self.pc, instruction),
+    fn draw_sprite(
+        &mut self,
+        bus: &mut Bus,
+        x: u8,
+        y: u8,
+        height: u8,
+        bytes_per_row: u8,
+    ) -> Result<(), Chip8Error> {
+        let plane_size = height as u16 * bytes_per_row as u16;
+        // With both XO-CHIP planes selected the data for the second plane
+        // follows the first. Everything is read before drawing so a bad
+        // address leaves the screen untouched.
+        let mut sprites = Vec::new();
+        let mut address = self.i;
+        for plane in [1, 2] {
+            if bus.selected_planes() & plane == 0 {
+                continue;
+            }
+            let mut data = Vec::with_capacity(plane_size as usize);
+            for _ in 0..plane_size {
+                data.push(self.read_byte(bus, address)?);
+                address = address.wrapping_add(1);
+            }
+            sprites.push((plane, data));
+        }
+
+        let mut collision = false;
+        for (plane, data) in sprites {
+            let clip = self.quirks.clip_sprites;
+            collision |= bus.draw_sprite(x, y, &data, bytes_per_row as usize, plane, clip);
         }
+        self.write_vx(0xF, collision as u8);
+        Ok(())
     }
 
-    fn debug_draw_sprite(&mut self, bus: &mut Bus, x: u8, y: u8, height: u8) {
-        let mut should_set_vf = false;
-        for sprite_y in 0..height {
-            let b = bus.ram_read_byte(self.i + sprite_y as u16);
-            if bus.debug_draw_byte(b, x, y + sprite_y) {
-                should_set_vf = true;
+    // The 8XYN group. Returns the new Vx and, if the operation sets it, VF.
+    fn alu(&self, op: AluOp, vx: u8, vy: u8) -> (u8, Option<u8>) {
+        let logic_flag = if self.quirks.logic_resets_vf {
+            Some(0)
+        } else {
+            None
+        };
+        let shift_source = if self.quirks.shift_uses_vy { vy } else { vx };
+        match op {
+            // Vx=Vy
+            AluOp::Load => (vy, None),
+            // Vx=Vx|Vy
+            AluOp::Or => (vx | vy, logic_flag),
+            // Vx=Vx&Vy
+            AluOp::And => (vx & vy, logic_flag),
+            // Vx=Vx^Vy
+            AluOp::Xor => (vx ^ vy, logic_flag),
+            // Vx+=Vy, VF is the carry
+            AluOp::Add => {
+                let (sum, carry) = vx.overflowing_add(vy);
+                (sum, Some(carry as u8))
             }
+            // Vx-=Vy, VF is 1 when there is no borrow
+            AluOp::Sub => {
+                let (diff, borrow) = vx.overflowing_sub(vy);
+                (diff, Some(!borrow as u8))
+            }
+            // Vx=Vy>>1, or Vx>>=1; VF is the bit shifted out
+            AluOp::ShiftRight => (shift_source >> 1, Some(shift_source & 0x1)),
+            // Vx=Vy-Vx, VF is 1 when there is no borrow
+            AluOp::SubN => {
+                let (diff, borrow) = vy.overflowing_sub(vx);
+                (diff, Some(!borrow as u8))
+            }
+            // Vx=Vy<<1, or Vx<<=1; VF is the bit shifted out
+            AluOp::ShiftLeft => (shift_source << 1, Some(shift_source >> 7)),
         }
-        if should_set_vf {
-            self.write_vx(0xF, 1);
+    }
+
+    // Skipping over an XO-CHIP F000 NNNN has to step over all four bytes.
+    fn skip_length(&self, bus: &Bus) -> u16 {
+        let next = (
+            bus.ram_read_byte(self.pc.wrapping_add(2)),
+            bus.ram_read_byte(self.pc.wrapping_add(3)),
+        );
+        if self.platform.has_xo_instructions() && next == (Some(0xF0), Some(0x00)) {
+            6
         } else {
-            self.write_vx(0xF, 0);
+            4
+        }
+    }
+
+    // Registers from x to y inclusive, counting down if y < x.
+    fn register_range(x: u8, y: u8) -> impl Iterator<Item = u8> {
+        let count = x.abs_diff(y) + 1;
+        (0..count).map(move |step| if x <= y { x + step } else { x - step })
+    }
+
+    fn read_byte(&self, bus: &Bus, address: u16) -> Result<u8, Chip8Error> {
+        bus.ram_read_byte(address)
+            .ok_or_else(|| self.out_of_bounds(address))
+    }
+
+    fn write_byte(&self, bus: &mut Bus, address: u16, value: u8) -> Result<(), Chip8Error> {
+        bus.ram_write_byte(address, value)
+            .ok_or_else(|| self.out_of_bounds(address))
+    }
+
+    fn out_of_bounds(&self, address: u16) -> Chip8Error {
+        Chip8Error::MemoryOutOfBounds {
+            pc: self.pc,
+            opcode: self.opcode,
+            address,
+        }
+    }
+
+    fn unknown_opcode(&self) -> Chip8Error {
+        Chip8Error::UnknownOpcode {
+            pc: self.pc,
+            opcode: self.opcode,
         }
     }
 
     fn write_vx(&mut self, x: u8, value: u8) {
         self.vx[x as usize] = value;
     }
-    fn read_vx(&mut self, x: u8) -> u8 {
+    fn read_vx(&self, x: u8) -> u8 {
         self.vx[x as usize]
     }
 }
 
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl fmt::Debug for Cpu {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "PC: {:#X}\n", self.pc)?;
+        writeln!(f, "PC: {:#X}", self.pc)?;
         write!(f, "VX: ")?;
         for item in self.vx.iter() {
             write!(f, "{:#X} ", *item)?;
         }
-        write!(f, "\n");
-        write!(f, "i: {:#X}\n", self.i)
+        writeln!(f)?;
+        writeln!(f, "i: {:#X}", self.i)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ram::RAM_SIZE;
+    use crate::random::ScriptedSource;
+
+    fn cpu_with(quirks: Quirks) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.set_quirks(quirks);
+        cpu
+    }
+
+    // A CPU and bus for `platform`, with `program` at PROGRAM_START.
+    fn machine(platform: Platform, program: &[u16]) -> (Cpu, Bus) {
+        let mut cpu = cpu_with(platform.quirks());
+        cpu.set_platform(platform);
+        let mut bus = Bus::new();
+        bus.set_ram_size(platform.memory_size());
+        for (index, opcode) in program.iter().enumerate() {
+            let address = PROGRAM_START + index as u16 * 2;
+            bus.ram_write_byte(address, (opcode >> 8) as u8);
+            bus.ram_write_byte(address + 1, *opcode as u8);
+        }
+        (cpu, bus)
+    }
+
+    fn run(cpu: &mut Cpu, bus: &mut Bus, instructions: usize) {
+        for _ in 0..instructions {
+            cpu.run_instruction(bus).unwrap();
+        }
+    }
+
+    // Runs `check` for every operand pair through the 8XYN ALU.
+    fn for_all_pairs(cpu: &Cpu, op: AluOp, check: impl Fn(u8, u8, u8, Option<u8>)) {
+        for vx in 0..=255u8 {
+            for vy in 0..=255u8 {
+                let (result, flag) = cpu.alu(op, vx, vy);
+                check(vx, vy, result, flag);
+            }
+        }
+    }
+
+    #[test]
+    fn alu_load() {
+        for_all_pairs(
+            &cpu_with(Quirks::vip()),
+            AluOp::Load,
+            |_, vy, result, flag| {
+                assert_eq!((result, flag), (vy, None));
+            },
+        );
+    }
+
+    #[test]
+    fn alu_logic() {
+        for quirks in [Quirks::vip(), Quirks::schip()] {
+            let cpu = cpu_with(quirks);
+            let flag = if quirks.logic_resets_vf {
+                Some(0)
+            } else {
+                None
+            };
+            for_all_pairs(&cpu, AluOp::Or, |vx, vy, result, f| {
+                assert_eq!((result, f), (vx | vy, flag));
+            });
+            for_all_pairs(&cpu, AluOp::And, |vx, vy, result, f| {
+                assert_eq!((result, f), (vx & vy, flag));
+            });
+            for_all_pairs(&cpu, AluOp::Xor, |vx, vy, result, f| {
+                assert_eq!((result, f), (vx ^ vy, flag));
+            });
+        }
+    }
+
+    #[test]
+    fn alu_add() {
+        for_all_pairs(
+            &cpu_with(Quirks::vip()),
+            AluOp::Add,
+            |vx, vy, result, flag| {
+                let sum = vx as u16 + vy as u16;
+                assert_eq!(result, (sum & 0xFF) as u8);
+                assert_eq!(flag, Some((sum > 0xFF) as u8), "{} + {}", vx, vy);
+            },
+        );
+    }
+
+    #[test]
+    fn alu_sub() {
+        for_all_pairs(
+            &cpu_with(Quirks::vip()),
+            AluOp::Sub,
+            |vx, vy, result, flag| {
+                assert_eq!(result, (vx as i16 - vy as i16).rem_euclid(256) as u8);
+                assert_eq!(flag, Some((vx >= vy) as u8), "{} - {}", vx, vy);
+            },
+        );
+    }
+
+    #[test]
+    fn alu_subn() {
+        for_all_pairs(
+            &cpu_with(Quirks::vip()),
+            AluOp::SubN,
+            |vx, vy, result, flag| {
+                assert_eq!(result, (vy as i16 - vx as i16).rem_euclid(256) as u8);
+                assert_eq!(flag, Some((vy >= vx) as u8), "{} - {}", vy, vx);
+            },
+        );
+    }
+
+    #[test]
+    fn alu_shifts() {
+        for quirks in [Quirks::vip(), Quirks::schip()] {
+            let cpu = cpu_with(quirks);
+            let source = |vx, vy| if quirks.shift_uses_vy { vy } else { vx };
+            for_all_pairs(&cpu, AluOp::ShiftRight, |vx, vy, result, flag| {
+                let s: u8 = source(vx, vy);
+                assert_eq!((result, flag), (s >> 1, Some(s & 1)));
+            });
+            for_all_pairs(&cpu, AluOp::ShiftLeft, |vx, vy, result, flag| {
+                let s: u8 = source(vx, vy);
+                assert_eq!((result, flag), (s << 1, Some(s >> 7)));
+            });
+        }
+    }
+
+    #[test]
+    fn unused_alu_opcodes_are_rejected() {
+        let mut cpu = cpu_with(Quirks::vip());
+        let mut bus = Bus::new();
+        for n in [0x8, 0x9, 0xA, 0xB, 0xC, 0xD, 0xF] {
+            let opcode = 0x8120 | n;
+            assert_eq!(decode(opcode), Instruction::Invalid(opcode));
+            bus.ram_write_byte(PROGRAM_START, 0x81);
+            bus.ram_write_byte(PROGRAM_START + 1, 0x20 | n as u8);
+            assert_eq!(
+                cpu.run_instruction(&mut bus),
+                Err(Chip8Error::UnknownOpcode {
+                    pc: PROGRAM_START,
+                    opcode
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn faults_return_the_error_and_change_nothing() {
+        // SYS calls aren't emulated.
+        let (mut cpu, mut bus) = machine(Platform::Chip8, &[0x0123]);
+        assert_eq!(
+            cpu.run_instruction(&mut bus),
+            Err(Chip8Error::UnknownOpcode {
+                pc: PROGRAM_START,
+                opcode: 0x0123
+            })
+        );
+
+        let (mut cpu, mut bus) = machine(Platform::Chip8, &[0x00EE]);
+        assert_eq!(
+            cpu.run_instruction(&mut bus),
+            Err(Chip8Error::StackUnderflow {
+                pc: PROGRAM_START,
+                opcode: 0x00EE
+            })
+        );
+
+        // 200: call 200, forever
+        let (mut cpu, mut bus) = machine(Platform::Chip8, &[0x2200]);
+        run(&mut cpu, &mut bus, STACK_DEPTH);
+        assert_eq!(
+            cpu.run_instruction(&mut bus),
+            Err(Chip8Error::StackOverflow {
+                pc: PROGRAM_START,
+                opcode: 0x2200
+            })
+        );
+        assert_eq!(cpu.stack().len(), STACK_DEPTH);
+    }
+
+    #[test]
+    fn memory_faults_return_the_address() {
+        // ld [i], v1; ld v1, [i]; drw v0, v0, 2, all with I at the last byte
+        // so the second byte falls off the end.
+        let (mut cpu, mut bus) = machine(Platform::Chip8, &[0xF155, 0xF165, 0xD002]);
+        let last = RAM_SIZE as u16 - 1;
+        cpu.set_i(last);
+        cpu.set_register(0, 0xAA);
+        for _ in 0..3 {
+            let pc = cpu.pc();
+            let opcode = (bus.ram_read_byte(pc).unwrap() as u16) << 8
+                | bus.ram_read_byte(pc + 1).unwrap() as u16;
+            let error = cpu.run_instruction(&mut bus).unwrap_err();
+            assert_eq!(
+                error,
+                Chip8Error::MemoryOutOfBounds {
+                    pc,
+                    opcode,
+                    address: RAM_SIZE as u16
+                }
+            );
+            assert_eq!(cpu.pc(), pc);
+            cpu.set_pc(pc + 2);
+        }
+        // Nothing was half written, read or drawn.
+        assert_eq!(bus.ram_read_byte(last), Some(0));
+        assert_eq!(cpu.registers()[0], 0xAA);
+        assert!(bus.get_display_buffer().iter().all(|&pixel| pixel == 0));
+
+        // Fetching past the end of memory faults too.
+        cpu.set_pc(last);
+        assert_eq!(
+            cpu.run_instruction(&mut bus),
+            Err(Chip8Error::MemoryOutOfBounds {
+                pc: last,
+                opcode: 0,
+                address: RAM_SIZE as u16
+            })
+        );
+    }
+
+    #[test]
+    fn wait_key_needs_a_press_and_release() {
+        // 200: ld v3, k
+        let (mut cpu, mut bus) = machine(Platform::Chip8, &[0xF30A]);
+        // Held from before the wait, so letting go doesn't count.
+        bus.key_down(0x2);
+        run(&mut cpu, &mut bus, 1);
+        bus.key_up(0x2);
+        run(&mut cpu, &mut bus, 1);
+        assert_eq!(cpu.pc(), PROGRAM_START);
+
+        bus.key_down(0x7);
+        run(&mut cpu, &mut bus, 2);
+        assert_eq!(cpu.pc(), PROGRAM_START);
+        bus.key_up(0x7);
+        run(&mut cpu, &mut bus, 1);
+        assert_eq!(cpu.registers()[3], 0x7);
+        assert_eq!(cpu.pc(), PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn quirks_change_what_instructions_do() {
+        // 200: ld v1, 0x81; 202: shr v0, v1; 204: or v2, v2; 206: ld [i], v1;
+        // 208: jp v0, 0x234
+        let program = [0x6181, 0x8016, 0x8221, 0xF155, 0xB234];
+        for quirks in [Quirks::vip(), Quirks::schip()] {
+            let (mut cpu, mut bus) = machine(Platform::SuperChip, &program);
+            cpu.set_quirks(quirks);
+            cpu.set_i(0x300);
+            cpu.set_register(0, 0x03);
+            run(&mut cpu, &mut bus, 3);
+            let v = *cpu.registers();
+            // V1 or V0 shifted.
+            assert_eq!(v[0], if quirks.shift_uses_vy { 0x40 } else { 0x01 });
+            // The shift's carry, cleared again by OR when VF resets.
+            assert_eq!(v[0xF], if quirks.logic_resets_vf { 0 } else { 1 });
+
+            run(&mut cpu, &mut bus, 1);
+            let i = if quirks.load_store_increments_i {
+                0x302
+            } else {
+                0x300
+            };
+            assert_eq!(cpu.i(), i);
+
+            cpu.set_register(2, 0x20);
+            run(&mut cpu, &mut bus, 1);
+            // BNNN adds V0, BXNN adds VX, here V2.
+            let target = if quirks.jump_uses_vx {
+                0x254
+            } else {
+                0x234 + v[0] as u16
+            };
+            assert_eq!(cpu.pc(), target);
+        }
+    }
+
+    #[test]
+    fn big_sprites_collide() {
+        // 200: high; 202: drw v0, v1, 0; 204: drw v0, v1, 0
+        let (mut cpu, mut bus) = machine(Platform::SuperChip, &[0x00FF, 0xD010, 0xD010]);
+        cpu.set_i(0x300);
+        for offset in 0..32 {
+            bus.ram_write_byte(0x300 + offset, 0xFF);
+        }
+        let lit = |bus: &Bus| bus.get_display_buffer().iter().filter(|&&p| p != 0).count();
+        run(&mut cpu, &mut bus, 2);
+        assert_eq!(lit(&bus), 16 * 16);
+        assert_eq!(cpu.registers()[0xF], 0);
+        run(&mut cpu, &mut bus, 1);
+        assert_eq!(lit(&bus), 0);
+        assert_eq!(cpu.registers()[0xF], 1);
+    }
+
+    #[test]
+    fn font_addresses() {
+        // 200: ld hf, v0; 202: ld f, v0
+        let (mut cpu, mut bus) = machine(Platform::SuperChip, &[0xF030, 0xF029]);
+        for digit in 0..16 {
+            cpu.set_pc(PROGRAM_START);
+            cpu.set_register(0, digit);
+            run(&mut cpu, &mut bus, 1);
+            assert_eq!(cpu.i(), BIG_FONT_START + digit as u16 * 10);
+            run(&mut cpu, &mut bus, 1);
+            assert_eq!(cpu.i(), FONT_START + digit as u16 * 5);
+        }
+        // The 8x10 zero.
+        assert_eq!(bus.ram_read_byte(BIG_FONT_START), Some(0x3C));
+    }
+
+    #[test]
+    fn rpl_flags_survive_resets() {
+        // 200: ld r, v2; 202: ld v3, r
+        let (mut cpu, mut bus) = machine(Platform::SuperChip, &[0xF275, 0xF385]);
+        for x in 0..4 {
+            cpu.set_register(x, x + 1);
+        }
+        run(&mut cpu, &mut bus, 1);
+        cpu.reset();
+        cpu.set_pc(PROGRAM_START + 2);
+        cpu.set_register(3, 0xAA);
+        run(&mut cpu, &mut bus, 1);
+        // V3 wasn't saved, so it loads the flag's initial 0.
+        assert_eq!(&cpu.registers()[..4], [1, 2, 3, 0]);
+    }
+
+    #[test]
+    fn skips_step_over_long_loads() {
+        // 200: se v0, 0; 202: ld i, long 0x1234; 206: ld v0, 1
+        let program = [0x3000, 0xF000, 0x1234, 0x6001];
+        let (mut cpu, mut bus) = machine(Platform::XoChip, &program);
+        run(&mut cpu, &mut bus, 1);
+        assert_eq!(cpu.pc(), 0x206);
+        // Elsewhere F000 is just another two bytes.
+        let (mut cpu, mut bus) = machine(Platform::SuperChip, &program);
+        run(&mut cpu, &mut bus, 1);
+        assert_eq!(cpu.pc(), 0x204);
+    }
+
+    #[test]
+    fn long_loads_reach_all_memory() {
+        // 200: ld i, long 0xFFF0; 204: ld [i], v1
+        let (mut cpu, mut bus) = machine(Platform::XoChip, &[0xF000, 0xFFF0, 0xF155]);
+        cpu.set_register(0, 0x12);
+        cpu.set_register(1, 0x34);
+        run(&mut cpu, &mut bus, 2);
+        assert_eq!(cpu.i(), 0xFFF2);
+        assert_eq!(bus.ram_read_byte(0xFFF0), Some(0x12));
+        assert_eq!(bus.ram_read_byte(0xFFF1), Some(0x34));
+
+        let (mut cpu, mut bus) = machine(Platform::SuperChip, &[0xF000, 0xFFF0]);
+        assert!(cpu.run_instruction(&mut bus).is_err());
+    }
+
+    #[test]
+    fn register_ranges_go_both_ways() {
+        // 200: save v1 - v3; 202: save v3 - v1; 204: load v3 - v1
+        let (mut cpu, mut bus) = machine(Platform::XoChip, &[0x5132, 0x5312, 0x5313]);
+        for x in 1..=3 {
+            cpu.set_register(x, x);
+        }
+        cpu.set_i(0x300);
+        run(&mut cpu, &mut bus, 1);
+        cpu.set_i(0x310);
+        run(&mut cpu, &mut bus, 1);
+        let memory = |address: u16| {
+            (address..address + 4)
+                .map(|address| bus.ram_read_byte(address).unwrap())
+                .collect::<Vec<u8>>()
+        };
+        assert_eq!(memory(0x300), [1, 2, 3, 0]);
+        assert_eq!(memory(0x310), [3, 2, 1, 0]);
+        assert_eq!(cpu.i(), 0x310);
+
+        cpu.set_i(0x300);
+        run(&mut cpu, &mut bus, 1);
+        assert_eq!(&cpu.registers()[..5], [0, 3, 2, 1, 0]);
+        assert_eq!(cpu.i(), 0x300);
+    }
+
+    #[test]
+    fn sprites_go_to_the_selected_planes() {
+        // 200: plane 2; 202: drw v0, v0, 1; 204: plane 3; 206: drw v0, v1, 1
+        let program = [0xF201, 0xD001, 0xF301, 0xD011];
+        let (mut cpu, mut bus) = machine(Platform::XoChip, &program);
+        cpu.set_register(1, 1);
+        // Both planes take a row each, the first plane's first.
+        cpu.set_i(0x300);
+        bus.ram_write_byte(0x300, 0x80);
+        bus.ram_write_byte(0x301, 0xC0);
+        run(&mut cpu, &mut bus, 4);
+        let screen = bus.get_display_buffer();
+        assert_eq!(&screen[..2], [2, 0]);
+        assert_eq!(&screen[64..66], [3, 2]);
+        assert_eq!(cpu.registers()[0xF], 0);
+    }
+
+    #[test]
+    fn flag_is_written_after_result() {
+        // 8FY4 with VF=0xFF, VY=0x01: the carry overwrites the sum.
+        let mut cpu = cpu_with(Quirks::vip());
+        let mut bus = Bus::new();
+        bus.ram_write_byte(PROGRAM_START, 0x8F);
+        bus.ram_write_byte(PROGRAM_START + 1, 0x14);
+        cpu.write_vx(0xF, 0xFF);
+        cpu.write_vx(0x1, 0x01);
+        cpu.run_instruction(&mut bus).unwrap();
+        assert_eq!(cpu.read_vx(0xF), 1);
+        assert_eq!(cpu.pc, PROGRAM_START + 2);
+    }
+
+    #[test]
+    fn random_masks_the_source() {
+        let mut cpu = cpu_with(Quirks::vip());
+        cpu.set_random_source(Box::new(ScriptedSource::new(&[0xFF, 0x5A])));
+        let mut bus = Bus::new();
+        for _ in 0..3 {
+            cpu.execute(&mut bus, Instruction::Random(0, 0xFF)).unwrap();
+            cpu.execute(&mut bus, Instruction::Random(1, 0x0F)).unwrap();
+        }
+        // The full 0-255 range, and the script wraps around.
+        assert_eq!(cpu.read_vx(0), 0xFF);
+        assert_eq!(cpu.read_vx(1), 0x0A);
+    }
+
+    #[test]
+    fn seeds_repeat_across_resets() {
+        let mut bus = Bus::new();
+        let mut cpu = Cpu::new();
+        cpu.set_seed(7);
+        let mut draw = |cpu: &mut Cpu| {
+            (0..256)
+                .map(|_| {
+                    cpu.execute(&mut bus, Instruction::Random(0, 0xFF)).unwrap();
+                    cpu.read_vx(0)
+                })
+                .collect::<Vec<u8>>()
+        };
+        let first = draw(&mut cpu);
+        cpu.reset();
+        assert_eq!(draw(&mut cpu), first);
+        let mut other = Cpu::new();
+        other.set_seed(7);
+        assert_eq!(draw(&mut other), first);
     }
 }
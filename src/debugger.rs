@@ -0,0 +1,387 @@
+//! An interactive debugger that drives a [`Chip8`] through its stepping API.
+//!
+//! The frontend passes it each line the user types and, while it is running,
+//! calls [`Debugger::run_frame`] once per frame instead of
+//! [`Chip8::run_frame`]. It never touches the screen or the terminal itself,
+//! so the same debugger works behind a window and in a headless shell.
+
+use std::collections::BTreeSet;
+
+use crate::chip8::Chip8;
+use crate::instruction::{decode_from, Instruction};
+
+pub const HELP: &str = "\
+s, step [N]          execute N instructions (default 1)
+n, next              step, running called subroutines to their return
+c, continue          run until a breakpoint, an error or exit
+p, pause             stop running
+b, break ADDR        set a breakpoint
+d, delete [ADDR]     remove one breakpoint, or all of them
+bl, breakpoints      list breakpoints
+r, regs              show V0-VF, I, PC, the stack and the timers
+x, mem ADDR [LEN]    dump LEN bytes of memory (default 64)
+l, list [ADDR] [N]   disassemble N instructions around ADDR (default PC)
+keys MASK            hold the keys in the 16-bit MASK
+reset                reset the machine
+q, quit              leave the debugger
+An empty line repeats the last command. Addresses are hex.";
+
+const DEFAULT_DUMP_LENGTH: usize = 64;
+const DEFAULT_LIST_LENGTH: usize = 10;
+
+/// Breakpoints and run state for one debugging session.
+#[derive(Debug, Default)]
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    running: bool,
+    // Set when execution resumes so the breakpoint under PC doesn't stop it
+    // straight away.
+    resuming: bool,
+    // `next` over a call: the return address and the stack depth to stop at.
+    step_over: Option<(u16, usize)>,
+    last_command: String,
+    quit: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger::default()
+    }
+
+    /// Whether the frontend should call [`Debugger::run_frame`].
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn has_quit(&self) -> bool {
+        self.quit
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    /// Runs one command line and returns what to show the user.
+    pub fn execute(&mut self, chip8: &mut Chip8, line: &str) -> String {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        self.last_command = line.clone();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((command, args)) = words.split_first() else {
+            return String::new();
+        };
+        self.command(chip8, command, args)
+            .unwrap_or_else(|message| message)
+    }
+
+    /// Runs the rest of the current frame, stopping early at a breakpoint,
+    /// the end of a `next`, an error or exit. Returns a message when it
+    /// stops.
+    pub fn run_frame(&mut self, chip8: &mut Chip8) -> Option<String> {
+        if !self.running {
+            return None;
+        }
+        let frame = chip8.frame_count();
+        while chip8.frame_count() == frame {
+            let pc = chip8.cpu().pc();
+            if !self.resuming {
+                if self.breakpoints.contains(&pc) {
+                    return Some(self.stop(chip8, "breakpoint"));
+                }
+                if self.step_over == Some((pc, chip8.cpu().stack().len())) {
+                    return Some(self.stop(chip8, "returned"));
+                }
+            }
+            self.resuming = false;
+            if chip8.has_exited() {
+                return Some(self.stop(chip8, "program exited"));
+            }
+            if let Err(e) = chip8.step() {
+                return Some(self.stop(chip8, &e.to_string()));
+            }
+        }
+        None
+    }
+
+    /// Stops a `continue` or `next` from outside, the way Ctrl-C would.
+    /// Returns a message, or `None` when nothing was running.
+    pub fn interrupt(&mut self, chip8: &Chip8) -> Option<String> {
+        self.running.then(|| self.stop(chip8, "interrupted"))
+    }
+
+    fn stop(&mut self, chip8: &Chip8, reason: &str) -> String {
+        self.running = false;
+        self.step_over = None;
+        format!("{}\n{}", reason, location(chip8))
+    }
+
+    fn resume(&mut self) {
+        self.running = true;
+        self.resuming = true;
+    }
+
+    fn command(
+        &mut self,
+        chip8: &mut Chip8,
+        command: &str,
+        args: &[&str],
+    ) -> Result<String, String> {
+        match command {
+            "s" | "step" => {
+                let count = match args.first() {
+                    Some(count) => count
+                        .parse()
+                        .map_err(|_| format!("invalid count {}", count))?,
+                    None => 1,
+                };
+                for _ in 0..count {
+                    chip8.step().map_err(|e| e.to_string())?;
+                }
+                Ok(location(chip8))
+            }
+            "n" | "next" => {
+                let pc = chip8.cpu().pc();
+                match instruction_at(chip8, pc) {
+                    Some(Instruction::Call(_)) => {
+                        self.step_over = Some((pc.wrapping_add(2), chip8.cpu().stack().len()));
+                        self.resume();
+                        Ok(String::new())
+                    }
+                    _ => {
+                        chip8.step().map_err(|e| e.to_string())?;
+                        Ok(location(chip8))
+                    }
+                }
+            }
+            "c" | "continue" => {
+                self.resume();
+                Ok(String::new())
+            }
+            "p" | "pause" => {
+                self.running = false;
+                self.step_over = None;
+                Ok(location(chip8))
+            }
+            "b" | "break" => {
+                let address = parse_address(args.first())?;
+                self.breakpoints.insert(address);
+                Ok(format!("breakpoint at {:04X}", address))
+            }
+            "d" | "delete" => match args.first() {
+                Some(_) => {
+                    let address = parse_address(args.first())?;
+                    if !self.breakpoints.remove(&address) {
+                        return Err(format!("no breakpoint at {:04X}", address));
+                    }
+                    Ok(format!("deleted breakpoint at {:04X}", address))
+                }
+                None => {
+                    self.breakpoints.clear();
+                    Ok("deleted all breakpoints".to_string())
+                }
+            },
+            "bl" | "breakpoints" => Ok(self
+                .breakpoints
+                .iter()
+                .map(|address| format!("{:04X}", address))
+                .collect::<Vec<_>>()
+                .join("\n")),
+            "r" | "regs" => Ok(registers(chip8)),
+            "x" | "mem" => {
+                let address = parse_address(args.first())?;
+                let length = match args.get(1) {
+                    Some(length) => length
+                        .parse()
+                        .map_err(|_| format!("invalid length {}", length))?,
+                    None => DEFAULT_DUMP_LENGTH,
+                };
+                Ok(dump(chip8, address, length))
+            }
+            "l" | "list" => {
+                let address = match args.first() {
+                    Some(_) => parse_address(args.first())?,
+                    // Show a few instructions before PC as well.
+                    None => chip8.cpu().pc().saturating_sub(6),
+                };
+                let count = match args.get(1) {
+                    Some(count) => count
+                        .parse()
+                        .map_err(|_| format!("invalid count {}", count))?,
+                    None => DEFAULT_LIST_LENGTH,
+                };
+                Ok(self.list(chip8, address, count))
+            }
+            "keys" => {
+                let mask = parse_address(args.first())?;
+                chip8.set_keys(mask);
+                Ok(format!("keys {:04X}", mask))
+            }
+            "reset" => {
+                chip8.reset();
+                self.running = false;
+                self.step_over = None;
+                Ok(location(chip8))
+            }
+            "q" | "quit" => {
+                self.quit = true;
+                self.running = false;
+                Ok(String::new())
+            }
+            "h" | "help" => Ok(HELP.to_string()),
+            _ => Err(format!("unknown command {}, try help", command)),
+        }
+    }
+
+    fn list(&self, chip8: &Chip8, mut address: u16, count: usize) -> String {
+        let pc = chip8.cpu().pc();
+        let mut lines = Vec::new();
+        for _ in 0..count {
+            let Some(instruction) = instruction_at(chip8, address) else {
+                break;
+            };
+            let marker = if address == pc { '>' } else { ' ' };
+            let breakpoint = if self.breakpoints.contains(&address) {
+                '*'
+            } else {
+                ' '
+            };
+            let raw: String = instruction
+                .encode()
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+            lines.push(format!(
+                "{}{}{:04X}: {:<8} {}",
+                marker, breakpoint, address, raw, instruction
+            ));
+            address = address.wrapping_add(instruction.size());
+        }
+        lines.join("\n")
+    }
+}
+
+fn instruction_at(chip8: &Chip8, address: u16) -> Option<Instruction> {
+    let bytes: Vec<u8> = (0..4)
+        .map_while(|offset| chip8.read_memory(address.wrapping_add(offset)))
+        .collect();
+    decode_from(&bytes)
+}
+
+// The instruction about to run.
+fn location(chip8: &Chip8) -> String {
+    let pc = chip8.cpu().pc();
+    match instruction_at(chip8, pc) {
+        Some(instruction) => format!("{:04X}: {}", pc, instruction),
+        None => format!("{:04X}: outside memory", pc),
+    }
+}
+
+fn registers(chip8: &Chip8) -> String {
+    let cpu = chip8.cpu();
+    let mut out = String::new();
+    for (index, value) in cpu.registers().iter().enumerate() {
+        out.push_str(&format!("V{:X}={:02X}", index, value));
+        out.push(if index % 8 == 7 { '\n' } else { ' ' });
+    }
+    out.push_str(&format!(
+        "I={:04X} PC={:04X} SP={} DT={:02X} ST={:02X}",
+        cpu.i(),
+        cpu.pc(),
+        cpu.stack().len(),
+        chip8.delay_timer(),
+        chip8.sound_timer()
+    ));
+    if !cpu.stack().is_empty() {
+        let stack: Vec<String> = cpu.stack().iter().map(|a| format!("{:04X}", a)).collect();
+        out.push_str(&format!("\nstack: {}", stack.join(" ")));
+    }
+    out
+}
+
+fn dump(chip8: &Chip8, address: u16, length: usize) -> String {
+    let mut lines = Vec::new();
+    for row in (0..length).step_by(16) {
+        let start = address as usize + row;
+        let bytes: Vec<String> = (start..(start + 16).min(address as usize + length))
+            .map_while(|a| chip8.read_memory(u16::try_from(a).ok()?))
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        if bytes.is_empty() {
+            break;
+        }
+        lines.push(format!("{:04X}: {}", start, bytes.join(" ")));
+    }
+    lines.join("\n")
+}
+
+fn parse_address(text: Option<&&str>) -> Result<u16, String> {
+    let text = text.ok_or("missing address")?;
+    let digits = text.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid address {}", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chip8_with(rom: &[u8]) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(rom).unwrap();
+        chip8
+    }
+
+    fn run(debugger: &mut Debugger, chip8: &mut Chip8) -> String {
+        loop {
+            if let Some(message) = debugger.run_frame(chip8) {
+                return message;
+            }
+        }
+    }
+
+    #[test]
+    fn continue_stops_at_breakpoints() {
+        // 200: ld v0, 1; 202: add v0, 1; 204: jp 202
+        let mut chip8 = chip8_with(&[0x60, 0x01, 0x70, 0x01, 0x12, 0x02]);
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut chip8, "break 204");
+        debugger.execute(&mut chip8, "continue");
+        assert_eq!(run(&mut debugger, &mut chip8), "breakpoint\n0204: jp 0x202");
+        debugger.execute(&mut chip8, "c");
+        run(&mut debugger, &mut chip8);
+        assert_eq!(chip8.cpu().registers()[0], 3);
+    }
+
+    #[test]
+    fn interrupts_stop_a_run() {
+        // 200: jp 200
+        let mut chip8 = chip8_with(&[0x12, 0x00]);
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.interrupt(&chip8), None);
+        debugger.execute(&mut chip8, "continue");
+        for _ in 0..10 {
+            assert_eq!(debugger.run_frame(&mut chip8), None);
+        }
+        assert_eq!(
+            debugger.interrupt(&chip8).unwrap(),
+            "interrupted\n0200: jp 0x200"
+        );
+        assert!(!debugger.is_running());
+    }
+
+    #[test]
+    fn next_steps_over_calls() {
+        // 200: call 206; 202: ld v1, 1; 204: jp 204; 206: ld v0, 1; 208: ret
+        let mut chip8 = chip8_with(&[0x22, 0x06, 0x61, 0x01, 0x12, 0x04, 0x60, 0x01, 0x00, 0xEE]);
+        let mut debugger = Debugger::new();
+        debugger.execute(&mut chip8, "next");
+        assert!(debugger.is_running());
+        assert_eq!(
+            run(&mut debugger, &mut chip8),
+            "returned\n0202: ld v1, 0x01"
+        );
+        assert_eq!(chip8.cpu().registers()[0], 1);
+        assert_eq!(debugger.execute(&mut chip8, ""), "0204: jp 0x204");
+    }
+}
@@ -0,0 +1,226 @@
+//! Disassembles ROM images into assembler source.
+//!
+//! Code is found by recursive traversal from `PROGRAM_START`: jumps, calls
+//! and both sides of every skip are followed, and whatever is never reached
+//! is printed as `db` data. Branch targets get generated labels, so the
+//! output assembles back into the same bytes.
+
+use std::collections::BTreeMap;
+
+use crate::cpu::PROGRAM_START;
+use crate::instruction::{decode_from, Instruction};
+use crate::platform::Platform;
+
+const BYTES_PER_DATA_LINE: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LabelKind {
+    Data,
+    Label,
+    Sub,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Byte {
+    Data,
+    Code,
+    // Part of the instruction starting at an earlier address.
+    Operand,
+}
+
+/// The result of tracing a ROM: which bytes are code and which addresses
+/// are referenced.
+#[derive(Debug)]
+pub struct Disassembly<'a> {
+    rom: &'a [u8],
+    bytes: Vec<Byte>,
+    labels: BTreeMap<u16, LabelKind>,
+}
+
+impl<'a> Disassembly<'a> {
+    /// Traces `rom`, loaded at `PROGRAM_START`, as `platform` would run it.
+    pub fn new(rom: &'a [u8], platform: Platform) -> Disassembly<'a> {
+        let mut disassembly = Disassembly {
+            rom,
+            bytes: vec![Byte::Data; rom.len()],
+            labels: BTreeMap::new(),
+        };
+        disassembly.trace(platform);
+        disassembly
+    }
+
+    /// Whether `address` starts an instruction.
+    pub fn is_code(&self, address: u16) -> bool {
+        self.offset(address)
+            .is_some_and(|offset| self.bytes[offset] == Byte::Code)
+    }
+
+    /// The generated label for `address`, if anything refers to it.
+    pub fn label(&self, address: u16) -> Option<String> {
+        let prefix = match self.labels.get(&address)? {
+            LabelKind::Data => "data",
+            LabelKind::Label => "label",
+            LabelKind::Sub => "sub",
+        };
+        Some(format!("{}_{:03X}", prefix, address))
+    }
+
+    fn offset(&self, address: u16) -> Option<usize> {
+        let offset = address.checked_sub(PROGRAM_START)? as usize;
+        (offset < self.rom.len()).then_some(offset)
+    }
+
+    fn instruction_at(&self, address: u16) -> Option<Instruction> {
+        decode_from(&self.rom[self.offset(address)?..])
+    }
+
+    fn reference(&mut self, address: u16, kind: LabelKind) {
+        if self.offset(address).is_some() {
+            let label = self.labels.entry(address).or_insert(kind);
+            *label = (*label).max(kind);
+        }
+    }
+
+    fn trace(&mut self, platform: Platform) {
+        let mut pending = vec![PROGRAM_START];
+        while let Some(address) = pending.pop() {
+            let Some(instruction) = self.instruction_at(address) else {
+                continue;
+            };
+            let offset = address as usize - PROGRAM_START as usize;
+            let size = instruction.size() as usize;
+            // Stop at anything already traced and at jumps into the middle
+            // of another instruction; those stay as hex addresses.
+            if self.bytes[offset..offset + size]
+                .iter()
+                .any(|&byte| byte != Byte::Data)
+            {
+                continue;
+            }
+            if !instruction.is_supported_by(platform) {
+                continue;
+            }
+            self.bytes[offset] = Byte::Code;
+            self.bytes[offset + 1..offset + size].fill(Byte::Operand);
+
+            let next = address.wrapping_add(size as u16);
+            match instruction {
+                Instruction::Jump(target) => {
+                    self.reference(target, LabelKind::Label);
+                    pending.push(target);
+                }
+                Instruction::Call(target) => {
+                    self.reference(target, LabelKind::Sub);
+                    pending.push(target);
+                    pending.push(next);
+                }
+                Instruction::SkipEqImm(..)
+                | Instruction::SkipNeImm(..)
+                | Instruction::SkipEqReg(..)
+                | Instruction::SkipNeReg(..)
+                | Instruction::SkipKeyPressed(_)
+                | Instruction::SkipKeyNotPressed(_) => {
+                    // XO-CHIP skips over both words of F000 NNNN.
+                    let skipped = match self.instruction_at(next) {
+                        Some(Instruction::LoadILong(_)) if platform.has_xo_instructions() => 4,
+                        _ => 2,
+                    };
+                    pending.push(next);
+                    pending.push(next.wrapping_add(skipped));
+                }
+                Instruction::LoadI(target) | Instruction::LoadILong(target) => {
+                    self.reference(target, LabelKind::Data);
+                    pending.push(next);
+                }
+                Instruction::JumpOffset(target) => self.reference(target, LabelKind::Label),
+                Instruction::Return | Instruction::Exit => {}
+                _ => pending.push(next),
+            }
+        }
+        // A label in the middle of an instruction could not be placed.
+        let bytes = &self.bytes;
+        self.labels
+            .retain(|&address, _| bytes[(address - PROGRAM_START) as usize] != Byte::Operand);
+    }
+
+    /// The whole ROM as assembler source, one instruction or up to eight
+    /// data bytes per line, each followed by its address and raw bytes.
+    pub fn to_asm(&self) -> String {
+        let mut out = String::new();
+        let mut offset = 0;
+        while offset < self.rom.len() {
+            let address = PROGRAM_START + offset as u16;
+            if let Some(label) = self.label(address) {
+                out.push_str(&format!("{}:\n", label));
+            }
+            let (text, size) = match self.bytes[offset] {
+                Byte::Code => {
+                    let instruction = self.instruction_at(address).unwrap();
+                    let text = instruction.to_asm(|target| self.label(target));
+                    (text, instruction.size() as usize)
+                }
+                _ => {
+                    let size = (1..BYTES_PER_DATA_LINE)
+                        .take_while(|&n| {
+                            offset + n < self.rom.len()
+                                && self.bytes[offset + n] == Byte::Data
+                                && !self.labels.contains_key(&(address + n as u16))
+                        })
+                        .count()
+                        + 1;
+                    let bytes: Vec<String> = self.rom[offset..offset + size]
+                        .iter()
+                        .map(|byte| format!("{:#04X}", byte))
+                        .collect();
+                    (format!("db {}", bytes.join(", ")), size)
+                }
+            };
+            let raw: String = self.rom[offset..offset + size]
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+            out.push_str(&format!("    {:<27} ; {:03X}: {}\n", text, address, raw));
+            offset += size;
+        }
+        out
+    }
+}
+
+/// Disassembles `rom` as `platform` would run it.
+pub fn disassemble(rom: &[u8], platform: Platform) -> String {
+    Disassembly::new(rom, platform).to_asm()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn separates_code_from_sprite_data() {
+        let rom = [
+            0xA2, 0x08, // ld i, data_208
+            0x22, 0x0A, // call sub_20A
+            0x12, 0x04, // jp label_204
+            0x00, 0x00, // unreachable
+            0xF0, 0x90, // sprite
+            0xD0, 0x12, // drw v0, v1, 2
+            0x00, 0xEE, // ret
+        ];
+        let disassembly = Disassembly::new(&rom, Platform::Chip8);
+        let code: Vec<u16> = (0x200..0x20E).filter(|&a| disassembly.is_code(a)).collect();
+        assert_eq!(code, [0x200, 0x202, 0x204, 0x20A, 0x20C]);
+        assert_eq!(
+            disassemble(&rom, Platform::Chip8),
+            "    ld i, data_208              ; 200: A208\n\
+             \x20   call sub_20A                ; 202: 220A\n\
+             label_204:\n\
+             \x20   jp label_204                ; 204: 1204\n\
+             \x20   db 0x00, 0x00               ; 206: 0000\n\
+             data_208:\n\
+             \x20   db 0xF0, 0x90               ; 208: F090\n\
+             sub_20A:\n\
+             \x20   drw v0, v1, 2               ; 20A: D012\n\
+             \x20   ret                         ; 20C: 00EE\n"
+        );
+    }
+}
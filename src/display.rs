@@ -1,69 +1,315 @@
-const WIDTH: usize = 64;
-const HEIGHT: usize = 32;
+use crate::image;
+use crate::palette::Palette;
+use crate::state::{ChunkWriter, StateError, StateReader, StateWriter};
+use log::debug;
 
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+pub const HIRES_WIDTH: usize = 128;
+pub const HIRES_HEIGHT: usize = 64;
+
+// Each pixel holds one bit per bitplane, so values range from 0 to 3 once
+// XO-CHIP programs enable the second plane.
 pub struct Display {
-    screen: [u8; WIDTH * HEIGHT],
+    screen: Vec<u8>,
+    hires: bool,
+    planes: u8,
 }
 
 impl Display {
     pub fn new() -> Display {
         Display {
-            screen: [0; WIDTH * HEIGHT],
+            screen: vec![0; WIDTH * HEIGHT],
+            hires: false,
+            planes: 1,
         }
     }
 
-    pub fn get_index_from_coords(x: usize, y: usize) -> usize {
-        y * WIDTH + x
+    /// Bitmask of the planes drawing, clearing and scrolling act on.
+    pub fn selected_planes(&self) -> u8 {
+        self.planes
+    }
+
+    pub fn select_planes(&mut self, planes: u8) {
+        debug!(target: "display", "planes = {:#b}", planes & 0b11);
+        self.planes = planes & 0b11;
     }
 
-    pub fn debug_draw_byte(&mut self, byte: u8, x: u8, y: u8) -> bool {
-        let mut flipped = false;
-        let mut coord_x = x as usize;
-        let coord_y = y as usize;
-        let mut b = byte;
+    pub fn width(&self) -> usize {
+        if self.hires {
+            HIRES_WIDTH
+        } else {
+            WIDTH
+        }
+    }
 
-        for _ in 0..8 {
-            let index = Display::get_index_from_coords(coord_x, coord_y);
-            match (b & 0b1000_0000) >> 7 {
-                0 => {
-                    if self.screen[index] == 1 {
-                        flipped = true;
+    pub fn height(&self) -> usize {
+        if self.hires {
+            HIRES_HEIGHT
+        } else {
+            HEIGHT
+        }
+    }
+
+    pub fn is_hires(&self) -> bool {
+        self.hires
+    }
+
+    // Switching resolution clears the screen.
+    pub fn set_hires(&mut self, hires: bool) {
+        debug!(target: "display", "hires = {}", hires);
+        self.hires = hires;
+        self.screen = vec![0; self.width() * self.height()];
+    }
+
+    pub fn get_index_from_coords(&self, x: usize, y: usize) -> usize {
+        y * self.width() + x
+    }
+
+    /// XORs a sprite onto `plane` with its top-left corner at (x, y), taken
+    /// modulo the screen size. `data` holds the rows, `bytes_per_row` bytes
+    /// each, most significant bit leftmost. Pixels past the right or bottom
+    /// edge are dropped when `clip` is set and wrap around otherwise.
+    /// Returns whether any lit pixel was turned off.
+    pub fn draw_sprite(
+        &mut self,
+        x: u8,
+        y: u8,
+        data: &[u8],
+        bytes_per_row: usize,
+        plane: u8,
+        clip: bool,
+    ) -> bool {
+        let (width, height) = (self.width(), self.height());
+        let start_x = x as usize % width;
+        let start_y = y as usize % height;
+        let mut collision = false;
+
+        for (row, bytes) in data.chunks(bytes_per_row).enumerate() {
+            let coord_y = start_y + row;
+            if clip && coord_y >= height {
+                break;
+            }
+            for (column, byte) in bytes.iter().enumerate() {
+                for bit in 0..8 {
+                    if byte & (0x80 >> bit) == 0 {
+                        continue;
+                    }
+                    let coord_x = start_x + column * 8 + bit;
+                    if clip && coord_x >= width {
+                        break;
                     }
-                    self.screen[index] = 0;
+                    let index = self.get_index_from_coords(coord_x % width, coord_y % height);
+                    if self.screen[index] & plane != 0 {
+                        collision = true;
+                    }
+                    self.screen[index] ^= plane;
                 }
-                1 => self.screen[index] = 1,
-                _ => unreachable!(),
-            };
-            coord_x += 1;
-            b = b << 1;
+            }
+        }
+        collision
+    }
+
+    pub fn scroll_down(&mut self, rows: usize) {
+        self.scroll(0, rows as isize);
+    }
+
+    pub fn scroll_up(&mut self, rows: usize) {
+        self.scroll(0, -(rows as isize));
+    }
+
+    pub fn scroll_right(&mut self, columns: usize) {
+        self.scroll(columns as isize, 0);
+    }
+
+    pub fn scroll_left(&mut self, columns: usize) {
+        self.scroll(-(columns as isize), 0);
+    }
+
+    // Moves the selected planes, filling the uncovered area with 0.
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.width() as isize, self.height() as isize);
+        let old = self.screen.clone();
+        for y in 0..height {
+            for x in 0..width {
+                let (src_x, src_y) = (x - dx, y - dy);
+                let moved = if (0..width).contains(&src_x) && (0..height).contains(&src_y) {
+                    old[(src_y * width + src_x) as usize] & self.planes
+                } else {
+                    0
+                };
+                let index = (y * width + x) as usize;
+                self.screen[index] = (old[index] & !self.planes) | moved;
+            }
         }
-        flipped
     }
 
     pub fn clear(&mut self) {
         for pixel in self.screen.iter_mut() {
-            *pixel = 0;
+            *pixel &= !self.planes;
         }
     }
 
     pub fn present(&self) {
-        for index in 0..self.screen.len() {
-            let pixel = self.screen[index];
+        println!();
+        print!("{}", image::to_ascii(&self.screen, self.width()));
+    }
 
-            if index % WIDTH == 0 {
-                print!("\n");
-            }
+    /// The screen as a PNG, each pixel `scale` pixels square.
+    pub fn to_png(&self, scale: usize, palette: &Palette) -> Vec<u8> {
+        image::to_png(&self.screen, self.width(), scale, palette)
+    }
+
+    pub fn get_display_buffer(&self) -> &[u8] {
+        &self.screen
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        let mut chunk = ChunkWriter::new();
+        chunk.bool(self.hires).u8(self.planes).bytes(&self.screen);
+        state.chunk("DISP", chunk);
+    }
+
+    pub fn load_state(state: &StateReader) -> Result<Display, StateError> {
+        let mut chunk = state.chunk("DISP")?;
+        let mut display = Display::new();
+        display.hires = chunk.bool()?;
+        display.planes = chunk.u8()?;
+        display.screen = chunk.bytes()?.to_vec();
+        if display.planes > 0b11
+            || display.screen.len() != display.width() * display.height()
+            || display.screen.iter().any(|&pixel| pixel > 0b11)
+        {
+            return Err(chunk.corrupt());
+        }
+        Ok(display)
+    }
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lit(display: &Display, x: usize, y: usize) -> bool {
+        display.get_display_buffer()[display.get_index_from_coords(x, y)] != 0
+    }
 
-            match pixel {
-                0 => print!("_"),
-                1 => print!("*"),
-                _ => unreachable!(),
+    fn lit_count(display: &Display) -> usize {
+        display
+            .get_display_buffer()
+            .iter()
+            .filter(|&&p| p != 0)
+            .count()
+    }
+
+    #[test]
+    fn drawing_twice_erases_and_collides() {
+        let mut display = Display::new();
+        assert!(!display.draw_sprite(0, 0, &[0b1010_0000], 1, 1, true));
+        assert!(lit(&display, 0, 0) && !lit(&display, 1, 0) && lit(&display, 2, 0));
+        assert!(display.draw_sprite(0, 0, &[0b1010_0000], 1, 1, true));
+        assert!(display.get_display_buffer().iter().all(|&p| p == 0));
+    }
+
+    #[test]
+    fn zero_bits_leave_pixels_alone() {
+        let mut display = Display::new();
+        display.draw_sprite(0, 0, &[0xFF], 1, 1, true);
+        assert!(!display.draw_sprite(0, 0, &[0x00], 1, 1, true));
+        assert!((0..8).all(|x| lit(&display, x, 0)));
+    }
+
+    #[test]
+    fn start_position_wraps() {
+        let mut display = Display::new();
+        display.draw_sprite(WIDTH as u8 + 1, HEIGHT as u8 + 2, &[0x80], 1, 1, true);
+        assert!(lit(&display, 1, 2));
+    }
+
+    #[test]
+    fn edges_clip_or_wrap() {
+        let mut clipped = Display::new();
+        clipped.draw_sprite(60, 31, &[0xFF, 0xFF], 1, 1, true);
+        assert_eq!(
+            clipped
+                .get_display_buffer()
+                .iter()
+                .filter(|&&p| p != 0)
+                .count(),
+            4
+        );
+
+        let mut wrapped = Display::new();
+        wrapped.draw_sprite(60, 31, &[0xFF, 0xFF], 1, 1, false);
+        assert!(lit(&wrapped, 0, 31) && lit(&wrapped, 3, 0) && lit(&wrapped, 63, 0));
+    }
+
+    #[test]
+    fn hires_doubles_the_screen_and_clears_it() {
+        let mut display = Display::new();
+        display.draw_sprite(0, 0, &[0x80], 1, 1, true);
+        display.set_hires(true);
+        assert_eq!(
+            (display.width(), display.height()),
+            (HIRES_WIDTH, HIRES_HEIGHT)
+        );
+        assert_eq!(
+            display.get_display_buffer().len(),
+            HIRES_WIDTH * HIRES_HEIGHT
+        );
+        assert_eq!(lit_count(&display), 0);
+        // Positions wrap at the hires size now.
+        display.draw_sprite(WIDTH as u8 + 1, HEIGHT as u8 + 2, &[0x80], 1, 1, true);
+        assert!(lit(&display, WIDTH + 1, HEIGHT + 2));
+    }
+
+    #[test]
+    fn scrolling_moves_every_direction() {
+        for hires in [false, true] {
+            let mut display = Display::new();
+            display.set_hires(hires);
+            let mut scrolled = |scroll: fn(&mut Display, usize), by, x, y| {
+                display.clear();
+                display.draw_sprite(8, 8, &[0x80], 1, 1, true);
+                scroll(&mut display, by);
+                assert!(lit(&display, x, y), "hires {} to {},{}", hires, x, y);
+                assert_eq!(lit_count(&display), 1);
             };
+            scrolled(Display::scroll_down, 3, 8, 11);
+            scrolled(Display::scroll_up, 3, 8, 5);
+            scrolled(Display::scroll_right, 4, 12, 8);
+            scrolled(Display::scroll_left, 4, 4, 8);
+
+            // What moves off the edge is gone rather than wrapping.
+            let (width, height) = (display.width(), display.height());
+            display.clear();
+            display.draw_sprite(width as u8 - 1, height as u8 - 1, &[0x80], 1, 1, true);
+            display.scroll_right(4);
+            display.scroll_down(4);
+            assert_eq!(lit_count(&display), 0);
         }
-        print!("\n");
     }
 
-    pub fn get_display_buffer(&self) -> &[u8] {
-        &self.screen
+    #[test]
+    fn planes_are_drawn_scrolled_and_cleared_separately() {
+        let mut display = Display::new();
+        display.draw_sprite(0, 0, &[0xC0], 1, 1, true);
+        display.draw_sprite(1, 0, &[0xC0], 1, 2, true);
+        assert_eq!(&display.get_display_buffer()[..3], [1, 3, 2]);
+        // Only the second plane collides with itself.
+        assert!(!display.draw_sprite(0, 1, &[0x80], 1, 2, true));
+
+        display.select_planes(2);
+        display.scroll_right(4);
+        assert_eq!(&display.get_display_buffer()[..7], [1, 1, 0, 0, 0, 2, 2]);
+        display.clear();
+        assert_eq!(&display.get_display_buffer()[..7], [1, 1, 0, 0, 0, 0, 0]);
+        assert_eq!(lit_count(&display), 2);
     }
 }
@@ -0,0 +1,48 @@
+use std::error::Error;
+use std::fmt;
+
+/// Everything that can stop the emulator. Instruction errors carry the
+/// address and opcode of the faulting instruction; the machine state is left
+/// untouched so it can be inspected before a reset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chip8Error {
+    UnknownOpcode { pc: u16, opcode: u16 },
+    StackUnderflow { pc: u16, opcode: u16 },
+    StackOverflow { pc: u16, opcode: u16 },
+    MemoryOutOfBounds { pc: u16, opcode: u16, address: u16 },
+    RomTooLarge { size: usize, max: usize },
+}
+
+impl fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Chip8Error::UnknownOpcode { pc, opcode } => {
+                write!(f, "{:#05X}: unknown opcode {:#06X}", pc, opcode)
+            }
+            Chip8Error::StackUnderflow { pc, opcode } => {
+                write!(
+                    f,
+                    "{:#05X}: {:#06X} returned with an empty stack",
+                    pc, opcode
+                )
+            }
+            Chip8Error::StackOverflow { pc, opcode } => {
+                write!(f, "{:#05X}: {:#06X} overflowed the call stack", pc, opcode)
+            }
+            Chip8Error::MemoryOutOfBounds {
+                pc,
+                opcode,
+                address,
+            } => write!(
+                f,
+                "{:#05X}: {:#06X} accessed memory out of bounds at {:#06X}",
+                pc, opcode, address
+            ),
+            Chip8Error::RomTooLarge { size, max } => {
+                write!(f, "ROM is {} bytes, at most {} fit in memory", size, max)
+            }
+        }
+    }
+}
+
+impl Error for Chip8Error {}
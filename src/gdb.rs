@@ -0,0 +1,607 @@
+//! A GDB remote serial protocol stub.
+//!
+//! [`GdbStub`] answers protocol packets against a [`Chip8`]; [`GdbServer`]
+//! carries them over a local TCP connection. The registers are V0-VF, I,
+//! PC, SP (the stack depth), DT and ST, described to the client through
+//! `target.xml`, and memory is the machine's RAM. Multi-byte registers are
+//! little endian.
+
+use std::collections::BTreeSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+use log::{debug, info};
+
+use crate::chip8::Chip8;
+use crate::error::Chip8Error;
+
+pub const DEFAULT_PORT: u16 = 1234;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.cpu">
+    <reg name="v0" bitsize="8" type="uint8"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="dt" bitsize="8" type="uint8"/>
+    <reg name="st" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+const REGISTER_COUNT: usize = 21;
+const I: usize = 16;
+const PC: usize = 17;
+const SP: usize = 18;
+const DT: usize = 19;
+const ST: usize = 20;
+
+/// Protocol state for one machine: breakpoints and whether it is running.
+#[derive(Debug, Default)]
+pub struct GdbStub {
+    breakpoints: BTreeSet<u16>,
+    running: bool,
+    // Set when execution resumes so the breakpoint under PC doesn't stop it
+    // straight away.
+    resuming: bool,
+}
+
+impl GdbStub {
+    pub fn new() -> GdbStub {
+        GdbStub::default()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Answers one packet payload. Returns `None` when the packet resumed
+    /// the machine; the stop reply then comes from [`GdbStub::run_frame`].
+    pub fn handle(&mut self, chip8: &mut Chip8, packet: &str) -> Option<String> {
+        let command_len = packet.chars().next().map_or(0, char::len_utf8);
+        let (command, args) = packet.split_at(command_len);
+        let action = args.strip_prefix("Cont;").filter(|_| command == "v");
+        match (command, action) {
+            ("c", _) => {
+                self.jump_to(chip8, args);
+                self.resume();
+                None
+            }
+            ("s", _) => {
+                self.jump_to(chip8, args);
+                Some(self.step(chip8))
+            }
+            // Only one thread, so the first action is the one that applies.
+            (_, Some(action)) if action.starts_with(['s', 'S']) => Some(self.step(chip8)),
+            (_, Some(_)) => {
+                self.resume();
+                None
+            }
+            _ => Some(
+                self.reply(chip8, command, args)
+                    .unwrap_or_else(|| "E01".to_string()),
+            ),
+        }
+    }
+
+    // Packets that answer straight away; `None` when they are malformed.
+    fn reply(&mut self, chip8: &mut Chip8, command: &str, args: &str) -> Option<String> {
+        let reply = match command {
+            "?" => stop_reply(chip8, None),
+            "g" => (0..REGISTER_COUNT)
+                .map(|n| hex(&register(chip8, n)))
+                .collect(),
+            "G" => {
+                let bytes = unhex(args)?;
+                let mut rest = bytes.as_slice();
+                for n in 0..REGISTER_COUNT {
+                    let size = register(chip8, n).len();
+                    if rest.len() < size || !set_register(chip8, n, &rest[..size]) {
+                        return None;
+                    }
+                    rest = &rest[size..];
+                }
+                "OK".to_string()
+            }
+            "p" => {
+                let n = usize::from_str_radix(args, 16).ok()?;
+                if n >= REGISTER_COUNT {
+                    return None;
+                }
+                hex(&register(chip8, n))
+            }
+            "P" => {
+                let (n, value) = args.split_once('=')?;
+                let n = usize::from_str_radix(n, 16).ok()?;
+                if n >= REGISTER_COUNT || !set_register(chip8, n, &unhex(value)?) {
+                    return None;
+                }
+                "OK".to_string()
+            }
+            "m" => {
+                let (address, length) = parse_range(args)?;
+                let bytes: Vec<u8> = (0..length)
+                    .map_while(|offset| chip8.read_memory(address.checked_add(offset)?))
+                    .collect();
+                // A read running off the end returns what there is.
+                if bytes.is_empty() && length > 0 {
+                    return None;
+                }
+                hex(&bytes)
+            }
+            "M" => {
+                let (range, data) = args.split_once(':')?;
+                let (address, length) = parse_range(range)?;
+                for (offset, &byte) in unhex(data)?.iter().take(length as usize).enumerate() {
+                    chip8.write_memory(address.checked_add(offset as u16)?, byte)?;
+                }
+                "OK".to_string()
+            }
+            "Z" | "z" => {
+                let mut fields = args.split(',');
+                let kind = fields.next()?;
+                let address = u16::from_str_radix(fields.next()?, 16).ok()?;
+                // Hardware breakpoints behave just like software ones here.
+                if kind != "0" && kind != "1" {
+                    return Some(String::new());
+                }
+                if command == "Z" {
+                    self.breakpoints.insert(address);
+                } else {
+                    self.breakpoints.remove(&address);
+                }
+                "OK".to_string()
+            }
+            // Leave the machine running on its own.
+            "D" => {
+                self.breakpoints.clear();
+                self.resume();
+                "OK".to_string()
+            }
+            "H" | "T" => "OK".to_string(),
+            "q" => query(args)?,
+            "v" if args == "Cont?" => "vCont;c;C;s;S".to_string(),
+            _ => String::new(),
+        };
+        Some(reply)
+    }
+
+    /// Stops a running machine, as the client's Ctrl-C does.
+    pub fn interrupt(&mut self) -> Option<String> {
+        if !self.running {
+            return None;
+        }
+        self.running = false;
+        // SIGINT
+        Some("S02".to_string())
+    }
+
+    /// Runs the rest of the current frame while the machine is running,
+    /// stopping early at a breakpoint, an error or exit. Returns the stop
+    /// reply when it stops.
+    pub fn run_frame(&mut self, chip8: &mut Chip8) -> Option<String> {
+        if !self.running {
+            return None;
+        }
+        let frame = chip8.frame_count();
+        while chip8.frame_count() == frame {
+            if !self.resuming && self.breakpoints.contains(&chip8.cpu().pc()) {
+                self.running = false;
+                return Some(stop_reply(chip8, None));
+            }
+            self.resuming = false;
+            if chip8.has_exited() {
+                self.running = false;
+                return Some(stop_reply(chip8, None));
+            }
+            if let Err(e) = chip8.step() {
+                self.running = false;
+                return Some(stop_reply(chip8, Some(e)));
+            }
+        }
+        None
+    }
+
+    // Stops whatever is running, which is what attaching a client does.
+    fn halt(&mut self) {
+        self.running = false;
+    }
+
+    fn resume(&mut self) {
+        self.running = true;
+        self.resuming = true;
+    }
+
+    fn step(&mut self, chip8: &mut Chip8) -> String {
+        self.running = false;
+        let error = chip8.step().err();
+        stop_reply(chip8, error)
+    }
+
+    // `c ADDR` and `s ADDR` resume somewhere else.
+    fn jump_to(&mut self, chip8: &mut Chip8, address: &str) {
+        if let Ok(address) = u16::from_str_radix(address, 16) {
+            chip8.cpu_mut().set_pc(address);
+        }
+    }
+}
+
+fn query(args: &str) -> Option<String> {
+    if args.starts_with("Supported") {
+        return Some("PacketSize=4000;qXfer:features:read+".to_string());
+    }
+    if let Some(range) = args.strip_prefix("Xfer:features:read:target.xml:") {
+        let (offset, length) = range.split_once(',')?;
+        let offset = usize::from_str_radix(offset, 16).ok()?;
+        let length = usize::from_str_radix(length, 16).ok()?;
+        let xml = TARGET_XML.as_bytes();
+        let chunk = &xml[offset.min(xml.len())..offset.saturating_add(length).min(xml.len())];
+        let more = offset.saturating_add(length) < xml.len();
+        return Some(format!(
+            "{}{}",
+            if more { 'm' } else { 'l' },
+            String::from_utf8_lossy(chunk)
+        ));
+    }
+    let reply = match args {
+        "Attached" => "1",
+        "C" => "QC1",
+        "fThreadInfo" => "m1",
+        "sThreadInfo" => "l",
+        "Symbol::" => "OK",
+        _ => "",
+    };
+    Some(reply.to_string())
+}
+
+fn stop_reply(chip8: &Chip8, error: Option<Chip8Error>) -> String {
+    if chip8.has_exited() {
+        return "W00".to_string();
+    }
+    let signal = match error {
+        None => 5,                                   // SIGTRAP
+        Some(Chip8Error::UnknownOpcode { .. }) => 4, // SIGILL
+        Some(_) => 11,                               // SIGSEGV
+    };
+    format!("S{:02x}", signal)
+}
+
+fn register(chip8: &Chip8, n: usize) -> Vec<u8> {
+    let cpu = chip8.cpu();
+    match n {
+        0..=15 => vec![cpu.registers()[n]],
+        I => cpu.i().to_le_bytes().to_vec(),
+        PC => cpu.pc().to_le_bytes().to_vec(),
+        SP => vec![cpu.stack().len() as u8],
+        DT => vec![chip8.delay_timer()],
+        _ => vec![chip8.sound_timer()],
+    }
+}
+
+// The stack pointer follows calls and returns, so writes may only keep it.
+fn set_register(chip8: &mut Chip8, n: usize, value: &[u8]) -> bool {
+    let word = || Some(u16::from_le_bytes(value.try_into().ok()?));
+    match (n, value) {
+        (0..=15, &[value]) => chip8.cpu_mut().set_register(n as u8, value),
+        (I, _) => match word() {
+            Some(i) => chip8.cpu_mut().set_i(i),
+            None => return false,
+        },
+        (PC, _) => match word() {
+            Some(pc) => chip8.cpu_mut().set_pc(pc),
+            None => return false,
+        },
+        (SP, &[sp]) => return sp as usize == chip8.cpu().stack().len(),
+        (DT, &[value]) => chip8.set_delay_timer(value),
+        (ST, &[value]) => chip8.set_sound_timer(value),
+        _ => return false,
+    }
+    true
+}
+
+fn parse_range(args: &str) -> Option<(u16, u16)> {
+    let (address, length) = args.split_once(',')?;
+    Some((
+        u16::from_str_radix(address, 16).ok()?,
+        u16::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// `$payload#checksum`, with `#`, `$`, `}` and `*` escaped.
+fn frame(payload: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    for &byte in payload.as_bytes() {
+        if matches!(byte, b'#' | b'$' | b'}' | b'*') {
+            body.extend([b'}', byte ^ 0x20]);
+        } else {
+            body.push(byte);
+        }
+    }
+    let checksum = checksum(&body);
+    let mut packet = vec![b'$'];
+    packet.extend(body);
+    packet.extend(format!("#{:02x}", checksum).into_bytes());
+    packet
+}
+
+// The payload of a whole `$payload#checksum` packet, if the checksum is
+// right.
+fn unframe(packet: &[u8]) -> Option<&[u8]> {
+    let (body, digits) = packet
+        .strip_prefix(b"$")?
+        .split_at(packet.len().checked_sub(4)?);
+    let [b'#', high, low] = *digits else {
+        return None;
+    };
+    let digits = [high, low];
+    let sent = u8::from_str_radix(std::str::from_utf8(&digits).ok()?, 16).ok()?;
+    (sent == checksum(body)).then_some(body)
+}
+
+fn checksum(body: &[u8]) -> u8 {
+    body.iter().fold(0, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Serves one GDB client at a time on a local TCP port, without blocking
+/// the caller.
+pub struct GdbServer {
+    listener: TcpListener,
+    client: Option<TcpStream>,
+    input: Vec<u8>,
+    stub: GdbStub,
+    killed: bool,
+}
+
+impl GdbServer {
+    /// Listens on `port` on the loopback interface. The machine stays
+    /// halted until a client attaches and continues it.
+    pub fn bind(port: u16) -> io::Result<GdbServer> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        listener.set_nonblocking(true)?;
+        Ok(GdbServer {
+            listener,
+            client: None,
+            input: Vec::new(),
+            stub: GdbStub::new(),
+            killed: false,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Whether the client sent `k`; the frontend should quit.
+    pub fn is_killed(&self) -> bool {
+        self.killed
+    }
+
+    /// Accepts a client, answers whatever it sent and runs one frame if the
+    /// machine is running. Call once per frame in place of
+    /// [`Chip8::run_frame`].
+    pub fn run_frame(&mut self, chip8: &mut Chip8) -> io::Result<()> {
+        if self.client.is_none() {
+            match self.listener.accept() {
+                Ok((stream, address)) => {
+                    info!(target: "gdb", "client connected from {}", address);
+                    stream.set_nonblocking(true)?;
+                    stream.set_nodelay(true)?;
+                    self.client = Some(stream);
+                    self.input.clear();
+                    self.stub.halt();
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.receive()?;
+        self.process(chip8)?;
+        if let Some(reply) = self.stub.run_frame(chip8) {
+            self.send(&reply)?;
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> io::Result<()> {
+        let Some(client) = &mut self.client else {
+            return Ok(());
+        };
+        let mut buffer = [0; 4096];
+        loop {
+            match client.read(&mut buffer) {
+                Ok(0) => {
+                    info!(target: "gdb", "client disconnected");
+                    self.client = None;
+                    // Without a client the game carries on by itself.
+                    self.stub.resume();
+                    return Ok(());
+                }
+                Ok(count) => self.input.extend_from_slice(&buffer[..count]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn process(&mut self, chip8: &mut Chip8) -> io::Result<()> {
+        loop {
+            let Some(start) = self.input.iter().position(|&b| b == b'$' || b == 0x03) else {
+                self.input.clear();
+                return Ok(());
+            };
+            if self.input[start] == 0x03 {
+                self.input.drain(..=start);
+                if let Some(reply) = self.stub.interrupt() {
+                    self.send(&reply)?;
+                }
+                continue;
+            }
+            // Wait for the rest of the packet and its two checksum digits.
+            let Some(end) = self.input[start..].iter().position(|&b| b == b'#') else {
+                return Ok(());
+            };
+            let end = start + end;
+            if self.input.len() < end + 3 {
+                return Ok(());
+            }
+            let packet: Vec<u8> = self.input.drain(..end + 3).skip(start).collect();
+            // A garbled packet is dropped and the client asked to send it again.
+            let Some(packet) = unframe(&packet) else {
+                debug!(target: "gdb", "<- bad checksum");
+                self.write(b"-")?;
+                continue;
+            };
+            self.write(b"+")?;
+            // Every packet GDB sends is ASCII; anything else is unsupported.
+            let Some(packet) = packet.is_ascii().then(|| String::from_utf8_lossy(packet)) else {
+                debug!(target: "gdb", "<- {:02x?}", packet);
+                self.send("")?;
+                continue;
+            };
+            debug!(target: "gdb", "<- {}", packet);
+            if packet == "k" {
+                self.killed = true;
+                self.client = None;
+                return Ok(());
+            }
+            if let Some(reply) = self.stub.handle(chip8, &packet) {
+                self.send(&reply)?;
+            }
+        }
+    }
+
+    fn send(&mut self, reply: &str) -> io::Result<()> {
+        debug!(target: "gdb", "-> {}", reply);
+        self.write(&frame(reply))
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        let Some(client) = &mut self.client else {
+            return Ok(());
+        };
+        // Replies are small; block for them rather than queue them.
+        client.set_nonblocking(false)?;
+        let result = client.write_all(bytes);
+        client.set_nonblocking(true)?;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chip8_with(rom: &[u8]) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(rom).unwrap();
+        chip8
+    }
+
+    fn handle(stub: &mut GdbStub, chip8: &mut Chip8, packet: &str) -> String {
+        stub.handle(chip8, packet).unwrap()
+    }
+
+    #[test]
+    fn registers_and_memory() {
+        // ld v3, 0xAB; ld i, 0x345
+        let mut chip8 = chip8_with(&[0x63, 0xAB, 0xA3, 0x45]);
+        let mut stub = GdbStub::new();
+        assert_eq!(handle(&mut stub, &mut chip8, "s"), "S05");
+        assert_eq!(handle(&mut stub, &mut chip8, "s"), "S05");
+        // V0-VF, then I, PC, SP, DT and ST.
+        assert_eq!(
+            handle(&mut stub, &mut chip8, "g"),
+            format!("000000ab{}4503040200{}", "00".repeat(12), "0000")
+        );
+        assert_eq!(handle(&mut stub, &mut chip8, "p11"), "0402");
+        assert_eq!(handle(&mut stub, &mut chip8, "P0=7f"), "OK");
+        assert_eq!(chip8.cpu().registers()[0], 0x7F);
+        assert_eq!(handle(&mut stub, &mut chip8, "m200,4"), "63aba345");
+        assert_eq!(handle(&mut stub, &mut chip8, "M300,2:beef"), "OK");
+        assert_eq!(chip8.read_memory(0x301), Some(0xEF));
+        assert_eq!(handle(&mut stub, &mut chip8, "m2000,1"), "E01");
+    }
+
+    #[test]
+    fn continue_stops_at_breakpoints() {
+        // 200: add v0, 1; 202: jp 200
+        let mut chip8 = chip8_with(&[0x70, 0x01, 0x12, 0x00]);
+        let mut stub = GdbStub::new();
+        assert_eq!(handle(&mut stub, &mut chip8, "Z0,202,2"), "OK");
+        assert_eq!(stub.handle(&mut chip8, "c"), None);
+        assert_eq!(stub.run_frame(&mut chip8), Some("S05".to_string()));
+        assert_eq!(chip8.cpu().pc(), 0x202);
+        assert_eq!(stub.handle(&mut chip8, "c"), None);
+        assert_eq!(stub.run_frame(&mut chip8), Some("S05".to_string()));
+        assert_eq!(chip8.cpu().registers()[0], 2);
+    }
+
+    #[test]
+    fn packets_are_framed_with_checksums() {
+        assert_eq!(frame("OK"), b"$OK#9a");
+        assert_eq!(frame("a#b"), b"$a}\x03b#43");
+        assert_eq!(unframe(&frame("a#b")), Some(&b"a}\x03b"[..]));
+        assert_eq!(unframe(b"$OK#9a"), Some(&b"OK"[..]));
+        assert_eq!(unframe(b"$OK#9b"), None);
+        assert_eq!(unframe(b"$OK#zz"), None);
+        assert_eq!(unframe(b"$#00"), Some(&b""[..]));
+    }
+
+    #[test]
+    fn binary_packets_get_an_empty_reply() {
+        let mut chip8 = chip8_with(&[0x12, 0x00]);
+        let mut server = GdbServer::bind(0).unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.write_all(b"$\xff#ff$\xffa#60$?#3f").unwrap();
+        let expected = b"+$#00+$#00+$S05#b8";
+        let mut received = Vec::new();
+        client.set_nonblocking(true).unwrap();
+        for _ in 0..1000 {
+            server.run_frame(&mut chip8).unwrap();
+            let mut buffer = [0; 64];
+            if let Ok(count) = client.read(&mut buffer) {
+                received.extend_from_slice(&buffer[..count]);
+            }
+            if received.len() >= expected.len() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert_eq!(
+            String::from_utf8_lossy(&received),
+            String::from_utf8_lossy(expected)
+        );
+        assert_eq!(handle(&mut GdbStub::new(), &mut chip8, "\u{e9}"), "");
+    }
+}
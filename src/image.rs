@@ -0,0 +1,140 @@
+//! Encoding the framebuffer as text and image files.
+//!
+//! All encoders take the framebuffer as [`Chip8::framebuffer`] returns it:
+//! one byte per pixel holding a value from 0 to 3.
+//!
+//! [`Chip8::framebuffer`]: crate::Chip8::framebuffer
+
+use std::fmt;
+
+use crate::palette::Palette;
+
+/// File formats the framebuffer can be written as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// One character per pixel: `_`, `*`, `+` and `#` for values 0 to 3.
+    Ascii,
+    /// Binary portable bitmap; any lit pixel is black.
+    Pbm,
+    /// PNG in the palette given.
+    Png,
+}
+
+pub const FORMATS: [&str; 3] = ["ascii", "pbm", "png"];
+
+impl ImageFormat {
+    pub fn from_name(name: &str) -> Option<ImageFormat> {
+        match name {
+            "ascii" | "txt" => Some(ImageFormat::Ascii),
+            "pbm" => Some(ImageFormat::Pbm),
+            "png" => Some(ImageFormat::Png),
+            _ => None,
+        }
+    }
+
+    /// Encodes a `width` pixels wide framebuffer; only PNGs have colour.
+    pub fn encode(self, pixels: &[u8], width: usize, palette: &Palette) -> Vec<u8> {
+        match self {
+            ImageFormat::Ascii => to_ascii(pixels, width).into_bytes(),
+            ImageFormat::Pbm => to_pbm(pixels, width),
+            ImageFormat::Png => to_png(pixels, width, 1, palette),
+        }
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ImageFormat::Ascii => "ascii",
+            ImageFormat::Pbm => "pbm",
+            ImageFormat::Png => "png",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// One line of text per row.
+pub fn to_ascii(pixels: &[u8], width: usize) -> String {
+    let mut text = String::with_capacity(pixels.len() + pixels.len() / width);
+    for row in pixels.chunks(width) {
+        for &pixel in row {
+            text.push(match pixel {
+                0 => '_',
+                1 => '*',
+                2 => '+',
+                _ => '#',
+            });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+pub fn to_pbm(pixels: &[u8], width: usize) -> Vec<u8> {
+    let height = pixels.len() / width;
+    let mut data = format!("P4\n{} {}\n", width, height).into_bytes();
+    for row in pixels.chunks(width) {
+        // Rows are padded to whole bytes, most significant bit leftmost.
+        for byte in row.chunks(8) {
+            let bits = byte.iter().enumerate().fold(0u8, |bits, (bit, &pixel)| {
+                bits | ((pixel != 0) as u8) << (7 - bit)
+            });
+            data.push(bits);
+        }
+    }
+    data
+}
+
+/// An RGB PNG with every pixel drawn as a `scale` by `scale` square.
+pub fn to_png(pixels: &[u8], width: usize, scale: usize, palette: &Palette) -> Vec<u8> {
+    let height = pixels.len() / width;
+    let mut data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut data, (width * scale) as u32, (height * scale) as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut rgb = Vec::with_capacity(pixels.len() * scale * scale * 3);
+    for row in pixels.chunks(width) {
+        let line: Vec<u8> = row
+            .iter()
+            .flat_map(|&pixel| palette.rgb(pixel).repeat(scale))
+            .collect();
+        for _ in 0..scale {
+            rgb.extend(&line);
+        }
+    }
+    // Writing to a Vec only fails on a size mismatch, which can't happen.
+    let mut writer = encoder.write_header().expect("PNG header");
+    writer.write_image_data(&rgb).expect("PNG data");
+    writer.finish().expect("PNG end");
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_encode_every_pixel() {
+        let pixels = [0, 1, 2, 3, 0, 0, 0, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+        assert_eq!(to_ascii(&pixels, 10), "_*+#_____*\n*________*\n");
+        // Rows are padded to 16 bits.
+        assert_eq!(to_pbm(&pixels, 10), b"P4\n10 2\n\x70\x40\x80\x40");
+
+        let png = to_png(&pixels, 10, 2, &Palette::CLASSIC);
+        let decoder = png::Decoder::new(png.as_slice());
+        let mut reader = decoder.read_info().unwrap();
+        let mut rgb = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut rgb).unwrap();
+        assert_eq!((info.width, info.height), (20, 4));
+        // Each pixel is doubled across and down.
+        let second_row = &rgb[20 * 3..][..8 * 3];
+        assert_eq!(second_row, &rgb[..8 * 3]);
+        assert_eq!(
+            second_row,
+            [
+                0, 0, 0, 0, 0, 0, 255, 255, 255, 255, 255, 255, 170, 170, 170, 170, 170, 170, 85,
+                85, 85, 85, 85, 85
+            ]
+        );
+    }
+}
@@ -0,0 +1,424 @@
+//! Typed CHIP-8, SUPER-CHIP and XO-CHIP instructions.
+
+use crate::platform::Platform;
+use std::fmt;
+
+/// The 8XYN arithmetic and logic operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AluOp {
+    /// 8XY0: Vx = Vy
+    Load,
+    /// 8XY1: Vx |= Vy
+    Or,
+    /// 8XY2: Vx &= Vy
+    And,
+    /// 8XY3: Vx ^= Vy
+    Xor,
+    /// 8XY4: Vx += Vy, VF = carry
+    Add,
+    /// 8XY5: Vx -= Vy, VF = no borrow
+    Sub,
+    /// 8XY6: Vx = Vy >> 1 (or Vx >> 1), VF = bit shifted out
+    ShiftRight,
+    /// 8XY7: Vx = Vy - Vx, VF = no borrow
+    SubN,
+    /// 8XYE: Vx = Vy << 1 (or Vx << 1), VF = bit shifted out
+    ShiftLeft,
+}
+
+impl AluOp {
+    pub const ALL: [AluOp; 9] = [
+        AluOp::Load,
+        AluOp::Or,
+        AluOp::And,
+        AluOp::Xor,
+        AluOp::Add,
+        AluOp::Sub,
+        AluOp::ShiftRight,
+        AluOp::SubN,
+        AluOp::ShiftLeft,
+    ];
+
+    pub fn from_nibble(n: u8) -> Option<AluOp> {
+        AluOp::ALL.into_iter().find(|op| op.nibble() == n)
+    }
+
+    /// The N in 8XYN.
+    pub fn nibble(self) -> u8 {
+        match self {
+            AluOp::Load => 0x0,
+            AluOp::Or => 0x1,
+            AluOp::And => 0x2,
+            AluOp::Xor => 0x3,
+            AluOp::Add => 0x4,
+            AluOp::Sub => 0x5,
+            AluOp::ShiftRight => 0x6,
+            AluOp::SubN => 0x7,
+            AluOp::ShiftLeft => 0xE,
+        }
+    }
+
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            AluOp::Load => "ld",
+            AluOp::Or => "or",
+            AluOp::And => "and",
+            AluOp::Xor => "xor",
+            AluOp::Add => "add",
+            AluOp::Sub => "sub",
+            AluOp::ShiftRight => "shr",
+            AluOp::SubN => "subn",
+            AluOp::ShiftLeft => "shl",
+        }
+    }
+}
+
+/// One decoded instruction. Register operands are register numbers, not
+/// values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// 0NNN: call a machine code routine; not supported.
+    Sys(u16),
+    /// 00E0
+    ClearScreen,
+    /// 00EE
+    Return,
+    /// 00CN (SUPER-CHIP)
+    ScrollDown(u8),
+    /// 00DN (XO-CHIP)
+    ScrollUp(u8),
+    /// 00FB (SUPER-CHIP): scroll right 4 pixels
+    ScrollRight,
+    /// 00FC (SUPER-CHIP): scroll left 4 pixels
+    ScrollLeft,
+    /// 00FD (SUPER-CHIP)
+    Exit,
+    /// 00FE (SUPER-CHIP)
+    LowRes,
+    /// 00FF (SUPER-CHIP)
+    HighRes,
+    /// 1NNN
+    Jump(u16),
+    /// 2NNN
+    Call(u16),
+    /// 3XNN: skip if Vx == NN
+    SkipEqImm(u8, u8),
+    /// 4XNN: skip if Vx != NN
+    SkipNeImm(u8, u8),
+    /// 5XY0: skip if Vx == Vy
+    SkipEqReg(u8, u8),
+    /// 5XY2 (XO-CHIP): store Vx..=Vy at I
+    SaveRange(u8, u8),
+    /// 5XY3 (XO-CHIP): load Vx..=Vy from I
+    LoadRange(u8, u8),
+    /// 6XNN
+    LoadImm(u8, u8),
+    /// 7XNN, no carry flag
+    AddImm(u8, u8),
+    /// 8XYN
+    Alu(AluOp, u8, u8),
+    /// 9XY0: skip if Vx != Vy
+    SkipNeReg(u8, u8),
+    /// ANNN
+    LoadI(u16),
+    /// BNNN: jump to NNN plus V0, or VX with the jump quirk
+    JumpOffset(u16),
+    /// CXNN: Vx = random & NN
+    Random(u8, u8),
+    /// DXYN: draw an N-row sprite, or 16x16 for N = 0 on SUPER-CHIP
+    Draw(u8, u8, u8),
+    /// EX9E
+    SkipKeyPressed(u8),
+    /// EXA1
+    SkipKeyNotPressed(u8),
+    /// F000 NNNN (XO-CHIP): I = NNNN, the word after the opcode. `decode`
+    /// only sees the opcode and leaves the address 0; see `decode_from`.
+    LoadILong(u16),
+    /// FN01 (XO-CHIP): select the bitplanes in N
+    SelectPlanes(u8),
+    /// F002 (XO-CHIP): load the audio pattern at I
+    LoadAudio,
+    /// FX07
+    GetDelay(u8),
+    /// FX0A: wait for a key press and release
+    WaitKey(u8),
+    /// FX15
+    SetDelay(u8),
+    /// FX18
+    SetSound(u8),
+    /// FX1E
+    AddI(u8),
+    /// FX29
+    Font(u8),
+    /// FX30 (SUPER-CHIP)
+    BigFont(u8),
+    /// FX33
+    Bcd(u8),
+    /// FX3A (XO-CHIP)
+    SetPitch(u8),
+    /// FX55
+    Store(u8),
+    /// FX65
+    Load(u8),
+    /// FX75 (SUPER-CHIP)
+    SaveFlags(u8),
+    /// FX85 (SUPER-CHIP)
+    LoadFlags(u8),
+    /// Anything else.
+    Invalid(u16),
+}
+
+pub fn decode(opcode: u16) -> Instruction {
+    let nnn = opcode & 0x0FFF;
+    let nn = (opcode & 0x0FF) as u8;
+    let n = (opcode & 0x00F) as u8;
+    let x = ((opcode & 0x0F00) >> 8) as u8;
+    let y = ((opcode & 0x00F0) >> 4) as u8;
+
+    match (opcode & 0xF000) >> 12 {
+        0x0 => match nnn {
+            0x0E0 => Instruction::ClearScreen,
+            0x0EE => Instruction::Return,
+            0x0C0..=0x0CF => Instruction::ScrollDown(n),
+            0x0D0..=0x0DF => Instruction::ScrollUp(n),
+            0x0FB => Instruction::ScrollRight,
+            0x0FC => Instruction::ScrollLeft,
+            0x0FD => Instruction::Exit,
+            0x0FE => Instruction::LowRes,
+            0x0FF => Instruction::HighRes,
+            _ => Instruction::Sys(nnn),
+        },
+        0x1 => Instruction::Jump(nnn),
+        0x2 => Instruction::Call(nnn),
+        0x3 => Instruction::SkipEqImm(x, nn),
+        0x4 => Instruction::SkipNeImm(x, nn),
+        0x5 => match n {
+            0x0 => Instruction::SkipEqReg(x, y),
+            0x2 => Instruction::SaveRange(x, y),
+            0x3 => Instruction::LoadRange(x, y),
+            _ => Instruction::Invalid(opcode),
+        },
+        0x6 => Instruction::LoadImm(x, nn),
+        0x7 => Instruction::AddImm(x, nn),
+        0x8 => match AluOp::from_nibble(n) {
+            Some(op) => Instruction::Alu(op, x, y),
+            None => Instruction::Invalid(opcode),
+        },
+        0x9 if n == 0 => Instruction::SkipNeReg(x, y),
+        0xA => Instruction::LoadI(nnn),
+        0xB => Instruction::JumpOffset(nnn),
+        0xC => Instruction::Random(x, nn),
+        0xD => Instruction::Draw(x, y, n),
+        0xE => match nn {
+            0x9E => Instruction::SkipKeyPressed(x),
+            0xA1 => Instruction::SkipKeyNotPressed(x),
+            _ => Instruction::Invalid(opcode),
+        },
+        0xF => match nn {
+            0x00 if x == 0 => Instruction::LoadILong(0),
+            0x01 => Instruction::SelectPlanes(x),
+            0x02 if x == 0 => Instruction::LoadAudio,
+            0x07 => Instruction::GetDelay(x),
+            0x0A => Instruction::WaitKey(x),
+            0x15 => Instruction::SetDelay(x),
+            0x18 => Instruction::SetSound(x),
+            0x1E => Instruction::AddI(x),
+            0x29 => Instruction::Font(x),
+            0x30 => Instruction::BigFont(x),
+            0x33 => Instruction::Bcd(x),
+            0x3A => Instruction::SetPitch(x),
+            0x55 => Instruction::Store(x),
+            0x65 => Instruction::Load(x),
+            0x75 => Instruction::SaveFlags(x),
+            0x85 => Instruction::LoadFlags(x),
+            _ => Instruction::Invalid(opcode),
+        },
+        _ => Instruction::Invalid(opcode),
+    }
+}
+
+/// Decodes the instruction at the start of `memory`, including the address
+/// word of F000 NNNN. Returns `None` if `memory` ends first.
+pub fn decode_from(memory: &[u8]) -> Option<Instruction> {
+    let word = |offset: usize| -> Option<u16> {
+        let bytes = memory.get(offset..offset + 2)?;
+        Some((bytes[0] as u16) << 8 | bytes[1] as u16)
+    };
+    match decode(word(0)?) {
+        Instruction::LoadILong(_) => Some(Instruction::LoadILong(word(2)?)),
+        instruction => Some(instruction),
+    }
+}
+
+impl Instruction {
+    /// Whether `platform` implements this instruction. DXY0 is accepted
+    /// everywhere: on plain CHIP-8 it draws nothing.
+    pub fn is_supported_by(self, platform: Platform) -> bool {
+        match self {
+            Instruction::Sys(_) | Instruction::Invalid(_) => false,
+            Instruction::ScrollDown(_)
+            | Instruction::ScrollRight
+            | Instruction::ScrollLeft
+            | Instruction::Exit
+            | Instruction::LowRes
+            | Instruction::HighRes
+            | Instruction::BigFont(_)
+            | Instruction::SaveFlags(_)
+            | Instruction::LoadFlags(_) => platform.has_schip_instructions(),
+            Instruction::ScrollUp(_)
+            | Instruction::SaveRange(_, _)
+            | Instruction::LoadRange(_, _)
+            | Instruction::LoadILong(_)
+            | Instruction::SelectPlanes(_)
+            | Instruction::LoadAudio
+            | Instruction::SetPitch(_) => platform.has_xo_instructions(),
+            _ => true,
+        }
+    }
+
+    /// Size in bytes, including the address word of F000 NNNN.
+    pub fn size(self) -> u16 {
+        match self {
+            Instruction::LoadILong(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// The opcode, followed by the address word for F000 NNNN. Operands are
+    /// masked to their field width, so `decode_from(&i.encode())` gives back
+    /// `i` for anything `decode` produces.
+    pub fn encode(self) -> Vec<u8> {
+        let xy = |high: u16, x: u8, y: u8, low: u16| {
+            high << 12 | (x as u16 & 0xF) << 8 | (y as u16 & 0xF) << 4 | low
+        };
+        let xnn = |high: u16, x: u8, nn: u8| high << 12 | (x as u16 & 0xF) << 8 | nn as u16;
+        let fx = |x: u8, nn: u16| xnn(0xF, x, nn as u8);
+        let opcode = match self {
+            Instruction::Sys(nnn) => nnn & 0xFFF,
+            Instruction::ClearScreen => 0x00E0,
+            Instruction::Return => 0x00EE,
+            Instruction::ScrollDown(n) => 0x00C0 | (n as u16 & 0xF),
+            Instruction::ScrollUp(n) => 0x00D0 | (n as u16 & 0xF),
+            Instruction::ScrollRight => 0x00FB,
+            Instruction::ScrollLeft => 0x00FC,
+            Instruction::Exit => 0x00FD,
+            Instruction::LowRes => 0x00FE,
+            Instruction::HighRes => 0x00FF,
+            Instruction::Jump(nnn) => 0x1000 | (nnn & 0xFFF),
+            Instruction::Call(nnn) => 0x2000 | (nnn & 0xFFF),
+            Instruction::SkipEqImm(x, nn) => xnn(0x3, x, nn),
+            Instruction::SkipNeImm(x, nn) => xnn(0x4, x, nn),
+            Instruction::SkipEqReg(x, y) => xy(0x5, x, y, 0x0),
+            Instruction::SaveRange(x, y) => xy(0x5, x, y, 0x2),
+            Instruction::LoadRange(x, y) => xy(0x5, x, y, 0x3),
+            Instruction::LoadImm(x, nn) => xnn(0x6, x, nn),
+            Instruction::AddImm(x, nn) => xnn(0x7, x, nn),
+            Instruction::Alu(op, x, y) => xy(0x8, x, y, op.nibble() as u16),
+            Instruction::SkipNeReg(x, y) => xy(0x9, x, y, 0x0),
+            Instruction::LoadI(nnn) => 0xA000 | (nnn & 0xFFF),
+            Instruction::JumpOffset(nnn) => 0xB000 | (nnn & 0xFFF),
+            Instruction::Random(x, nn) => xnn(0xC, x, nn),
+            Instruction::Draw(x, y, n) => xy(0xD, x, y, n as u16 & 0xF),
+            Instruction::SkipKeyPressed(x) => xnn(0xE, x, 0x9E),
+            Instruction::SkipKeyNotPressed(x) => xnn(0xE, x, 0xA1),
+            Instruction::LoadILong(_) => 0xF000,
+            Instruction::SelectPlanes(n) => fx(n, 0x01),
+            Instruction::LoadAudio => 0xF002,
+            Instruction::GetDelay(x) => fx(x, 0x07),
+            Instruction::WaitKey(x) => fx(x, 0x0A),
+            Instruction::SetDelay(x) => fx(x, 0x15),
+            Instruction::SetSound(x) => fx(x, 0x18),
+            Instruction::AddI(x) => fx(x, 0x1E),
+            Instruction::Font(x) => fx(x, 0x29),
+            Instruction::BigFont(x) => fx(x, 0x30),
+            Instruction::Bcd(x) => fx(x, 0x33),
+            Instruction::SetPitch(x) => fx(x, 0x3A),
+            Instruction::Store(x) => fx(x, 0x55),
+            Instruction::Load(x) => fx(x, 0x65),
+            Instruction::SaveFlags(x) => fx(x, 0x75),
+            Instruction::LoadFlags(x) => fx(x, 0x85),
+            Instruction::Invalid(opcode) => opcode,
+        };
+        let mut bytes = opcode.to_be_bytes().to_vec();
+        if let Instruction::LoadILong(nnnn) = self {
+            bytes.extend(nnnn.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Assembler source for this instruction. Addresses are printed through
+    /// `label` when it returns a name and as hex otherwise.
+    pub fn to_asm(self, label: impl Fn(u16) -> Option<String>) -> String {
+        let addr = |address: u16| label(address).unwrap_or_else(|| format!("{:#05X}", address));
+        match self {
+            Instruction::Sys(nnn) => format!("sys {}", addr(nnn)),
+            Instruction::ClearScreen => "cls".to_string(),
+            Instruction::Return => "ret".to_string(),
+            Instruction::ScrollDown(n) => format!("scd {}", n),
+            Instruction::ScrollUp(n) => format!("scu {}", n),
+            Instruction::ScrollRight => "scr".to_string(),
+            Instruction::ScrollLeft => "scl".to_string(),
+            Instruction::Exit => "exit".to_string(),
+            Instruction::LowRes => "low".to_string(),
+            Instruction::HighRes => "high".to_string(),
+            Instruction::Jump(nnn) => format!("jp {}", addr(nnn)),
+            Instruction::Call(nnn) => format!("call {}", addr(nnn)),
+            Instruction::SkipEqImm(x, nn) => format!("se v{:X}, {:#04X}", x, nn),
+            Instruction::SkipNeImm(x, nn) => format!("sne v{:X}, {:#04X}", x, nn),
+            Instruction::SkipEqReg(x, y) => format!("se v{:X}, v{:X}", x, y),
+            Instruction::SaveRange(x, y) => format!("save v{:X}, v{:X}", x, y),
+            Instruction::LoadRange(x, y) => format!("load v{:X}, v{:X}", x, y),
+            Instruction::LoadImm(x, nn) => format!("ld v{:X}, {:#04X}", x, nn),
+            Instruction::AddImm(x, nn) => format!("add v{:X}, {:#04X}", x, nn),
+            Instruction::Alu(op, x, y) => format!("{} v{:X}, v{:X}", op.mnemonic(), x, y),
+            Instruction::SkipNeReg(x, y) => format!("sne v{:X}, v{:X}", x, y),
+            Instruction::LoadI(nnn) => format!("ld i, {}", addr(nnn)),
+            Instruction::JumpOffset(nnn) => format!("jp v0, {}", addr(nnn)),
+            Instruction::Random(x, nn) => format!("rnd v{:X}, {:#04X}", x, nn),
+            Instruction::Draw(x, y, n) => format!("drw v{:X}, v{:X}, {}", x, y, n),
+            Instruction::SkipKeyPressed(x) => format!("skp v{:X}", x),
+            Instruction::SkipKeyNotPressed(x) => format!("sknp v{:X}", x),
+            Instruction::LoadILong(nnnn) => format!("ld i, long {}", addr(nnnn)),
+            Instruction::SelectPlanes(n) => format!("plane {}", n),
+            Instruction::LoadAudio => "audio".to_string(),
+            Instruction::GetDelay(x) => format!("ld v{:X}, dt", x),
+            Instruction::WaitKey(x) => format!("ld v{:X}, k", x),
+            Instruction::SetDelay(x) => format!("ld dt, v{:X}", x),
+            Instruction::SetSound(x) => format!("ld st, v{:X}", x),
+            Instruction::AddI(x) => format!("add i, v{:X}", x),
+            Instruction::Font(x) => format!("ld f, v{:X}", x),
+            Instruction::BigFont(x) => format!("ld hf, v{:X}", x),
+            Instruction::Bcd(x) => format!("ld b, v{:X}", x),
+            Instruction::SetPitch(x) => format!("pitch v{:X}", x),
+            Instruction::Store(x) => format!("ld [i], v{:X}", x),
+            Instruction::Load(x) => format!("ld v{:X}, [i]", x),
+            Instruction::SaveFlags(x) => format!("ld r, v{:X}", x),
+            Instruction::LoadFlags(x) => format!("ld v{:X}, r", x),
+            Instruction::Invalid(opcode) => format!("dw {:#06X}", opcode),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.to_asm(|_| None))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_inverts_decode() {
+        for opcode in 0..=0xFFFF {
+            let instruction = decode(opcode);
+            let bytes = match instruction {
+                Instruction::LoadILong(_) => vec![0xF0, 0x00, 0x12, 0x34],
+                _ => opcode.to_be_bytes().to_vec(),
+            };
+            let instruction = decode_from(&bytes).unwrap();
+            assert_eq!(instruction.encode(), bytes, "{:04X}", opcode);
+        }
+    }
+}
@@ -1,26 +1,126 @@
+use crate::state::{ChunkWriter, StateError, StateReader, StateWriter};
+use log::debug;
+
+/// The 16-key hex keypad. Bit `n` of each mask corresponds to key `n`.
 pub struct Keyboard {
-    key_pressed: Option<u8>,
+    pressed: u16,
+    // Key-down and key-up events seen since the last `clear_events`, used by
+    // FX0A to wait for a full press and release.
+    went_down: u16,
+    went_up: u16,
 }
 
 impl Keyboard {
     pub fn new() -> Keyboard {
-        Keyboard { key_pressed: None }
+        Keyboard {
+            pressed: 0,
+            went_down: 0,
+            went_up: 0,
+        }
     }
 
-    //Todo implement proper key handling
     pub fn is_key_pressed(&self, key_code: u8) -> bool {
-        if let Some(key) = self.key_pressed {
-            key == key_code
-        } else {
-            false
+        self.pressed & Keyboard::mask(key_code) != 0
+    }
+
+    pub fn key_down(&mut self, key_code: u8) {
+        self.set_keys(self.pressed | Keyboard::mask(key_code));
+    }
+
+    pub fn key_up(&mut self, key_code: u8) {
+        self.set_keys(self.pressed & !Keyboard::mask(key_code));
+    }
+
+    /// Replaces the whole keypad state, recording which keys changed.
+    pub fn set_keys(&mut self, keys: u16) {
+        if keys != self.pressed {
+            debug!(target: "input", "keys = {:#018b}", keys);
+        }
+        self.went_down |= keys & !self.pressed;
+        self.went_up |= self.pressed & !keys;
+        self.pressed = keys;
+    }
+
+    pub fn get_keys(&self) -> u16 {
+        self.pressed
+    }
+
+    pub fn clear_events(&mut self) {
+        self.went_down = 0;
+        self.went_up = 0;
+    }
+
+    /// Returns the lowest key that was both pressed and released since the
+    /// last `clear_events`, clearing the events if there is one.
+    pub fn take_released_key(&mut self) -> Option<u8> {
+        let released = self.went_down & self.went_up & !self.pressed;
+        if released == 0 {
+            return None;
         }
+        self.clear_events();
+        Some(released.trailing_zeros() as u8)
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        let mut chunk = ChunkWriter::new();
+        chunk
+            .u16(self.pressed)
+            .u16(self.went_down)
+            .u16(self.went_up);
+        state.chunk("KEYS", chunk);
+    }
+
+    pub fn load_state(state: &StateReader) -> Result<Keyboard, StateError> {
+        let mut chunk = state.chunk("KEYS")?;
+        Ok(Keyboard {
+            pressed: chunk.u16()?,
+            went_down: chunk.u16()?,
+            went_up: chunk.u16()?,
+        })
+    }
+
+    fn mask(key_code: u8) -> u16 {
+        1 << (key_code & 0xF)
     }
+}
 
-    pub fn set_key_pressed(&mut self, key: Option<u8>) {
-        self.key_pressed = key;
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self::new()
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_key_has_its_own_bit() {
+        let mut keyboard = Keyboard::new();
+        for key in 0..16 {
+            keyboard.key_down(key);
+            assert_eq!(keyboard.get_keys(), 1 << key);
+            assert!((0..16).all(|other| keyboard.is_key_pressed(other) == (other == key)));
+            keyboard.key_up(key);
+        }
+        assert_eq!(keyboard.get_keys(), 0);
+    }
+
+    #[test]
+    fn keys_count_once_released() {
+        let mut keyboard = Keyboard::new();
+        keyboard.key_down(0x9);
+        keyboard.key_down(0x3);
+        assert_eq!(keyboard.take_released_key(), None);
+        keyboard.set_keys(0);
+        // The lowest wins and the rest are forgotten.
+        assert_eq!(keyboard.take_released_key(), Some(0x3));
+        assert_eq!(keyboard.take_released_key(), None);
 
-    pub fn get_key_pressed(&self) -> Option<u8> {
-        self.key_pressed
+        // A key already down when the events were cleared doesn't count.
+        keyboard.key_down(0x5);
+        keyboard.clear_events();
+        keyboard.key_up(0x5);
+        assert_eq!(keyboard.take_released_key(), None);
     }
 }
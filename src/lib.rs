@@ -0,0 +1,32 @@
+//! Headless CHIP-8 emulator core.
+//!
+//! The [`Chip8`] type owns the whole machine and is driven by the host:
+//! feed it a ROM, call [`Chip8::run_frame`] sixty times a second, forward the
+//! keypad state and draw [`Chip8::framebuffer`] however you like.
+
+pub mod assembler;
+pub mod audio;
+pub mod bus;
+pub mod chip8;
+pub mod cpu;
+pub mod debugger;
+pub mod disassembler;
+pub mod display;
+pub mod error;
+pub mod gdb;
+pub mod image;
+pub mod instruction;
+pub mod keyboard;
+pub mod movie;
+pub mod octo;
+pub mod palette;
+pub mod platform;
+pub mod quirks;
+pub mod ram;
+pub mod random;
+pub mod rewind;
+pub mod state;
+pub mod video;
+
+pub use chip8::Chip8;
+pub use error::Chip8Error;
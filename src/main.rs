@@ -1,124 +1,67 @@
-extern crate minifb;
-
-use chip8::Chip8;
-use display::Display;
-use minifb::{Key, KeyRepeat, Window, WindowOptions};
-use std::env;
-use std::fs::File;
-use std::io::Read;
-use std::time::{Duration, Instant};
-
-mod bus;
-mod chip8;
-mod cpu;
-mod display;
-mod keyboard;
-mod ram;
-
-fn get_chip8_keycode_for(key: Option<Key>) -> Option<u8> {
-    match key {
-        Some(Key::Key1) => Some(0x1),
-        Some(Key::Key2) => Some(0x2),
-        Some(Key::Key3) => Some(0x3),
-        Some(Key::Key4) => Some(0xC),
-
-        Some(Key::Q) => Some(0x4),
-        Some(Key::W) => Some(0x5),
-        Some(Key::E) => Some(0x6),
-        Some(Key::R) => Some(0xD),
-
-        Some(Key::A) => Some(0x7),
-        Some(Key::S) => Some(0x8),
-        Some(Key::D) => Some(0x9),
-        Some(Key::F) => Some(0xE),
-
-        Some(Key::Z) => Some(0xA),
-        Some(Key::X) => Some(0x0),
-        Some(Key::C) => Some(0xB),
-        Some(Key::V) => Some(0xF),
-        _ => None,
+mod cli;
+#[cfg(feature = "window")]
+mod window;
+
+// Without the window feature only the subcommands run.
+#[cfg(not(feature = "window"))]
+mod window {
+    pub fn run(_options: crate::cli::Options) {
+        eprintln!("built without the window feature; use a subcommand such as headless");
+        std::process::exit(1);
     }
 }
 
+use cli::{parse_options, Failure};
+use std::env;
+use std::process;
+
+const USAGE: &str = "usage: rust-chip-8 [--platform chip8|schip|xochip] [--ipf N]
+                   [--quirks vip|schip|xochip] [--quirk NAME=on|off]... [--seed N]
+                   [--tone HZ] [--volume 0..1] [--log FILTER] [--trace FILE]
+                   [--rewind MIB] [--record MOVIE | --replay MOVIE]
+                   [--video FILE.gif|FILE|-] [--video-scale N]
+                   [--palette NAME|RRGGBB,...] [--palette-file FILE]
+                   [--debug | --gdb PORT] [ROM|SOURCE.8o]
+       rust-chip-8 debug [machine options] ROM|SOURCE.8o
+       rust-chip-8 gdb [--port N] [machine options] ROM|SOURCE.8o
+       rust-chip-8 headless [--frames N | --cycles N] [--keys FRAME:KEYS,...]
+                            [--output FILE] [--format ascii|pbm|png] [--every N]
+                            [--video FILE.gif|FILE|-] [--video-scale N]
+                            [machine options] ROM|SOURCE.8o
+       rust-chip-8 replay MOVIE ROM|SOURCE.8o
+       rust-chip-8 asm [-o OUT] SOURCE
+       rust-chip-8 disasm [--platform chip8|schip|xochip] ROM";
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let file_name = match args.len() {
-        0 | 1 => "data/INVADERS",
-        _ => args.get(1).unwrap(),
+    let args: Vec<String> = env::args().skip(1).collect();
+    let subcommand = match args.first().map(String::as_str) {
+        Some("asm") => Some((cli::asm::run as fn(&[String]) -> _, cli::asm::USAGE)),
+        Some("debug") => Some((cli::debug::run as fn(&[String]) -> _, cli::debug::USAGE)),
+        Some("disasm") => Some((cli::disasm::run as fn(&[String]) -> _, cli::disasm::USAGE)),
+        Some("gdb") => Some((cli::gdb::run as fn(&[String]) -> _, cli::gdb::USAGE)),
+        Some("headless") => Some((
+            cli::headless::run as fn(&[String]) -> _,
+            cli::headless::USAGE,
+        )),
+        Some("replay") => Some((cli::replay::run as fn(&[String]) -> _, cli::replay::USAGE)),
+        _ => None,
     };
-    let mut file = File::open(file_name).unwrap();
-    let mut data = Vec::<u8>::new();
-    file.read_to_end(&mut data).expect("File not found!");
-
-    let width = 640;
-    let height = 320;
-
-    //ARGB buffer
-    let mut buffer: Vec<u32> = vec![0; width * height];
-
-    let mut window = Window::new(
-        "Rust Chip8 emulator",
-        width,
-        height,
-        WindowOptions::default(),
-    )
-    .unwrap_or_else(|e| {
-        panic!("{}", e);
-    });
-
-    let mut chip8 = Chip8::new();
-    chip8.load_rom(&data);
-
-    let mut last_key_update_time = Instant::now();
-    let mut last_instruction_run_time = Instant::now();
-    let mut last_display_time = Instant::now();
-
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        let keys_pressed = window.get_keys_pressed(KeyRepeat::Yes);
-        let key = match keys_pressed {
-            keys => {
-                if !keys.is_empty() {
-                    Some(keys[0])
-                } else {
-                    None
-                }
+    if let Some((run, usage)) = subcommand {
+        match run(&args[1..]) {
+            Ok(()) => return,
+            Err(Failure::Usage(e)) => {
+                eprintln!("{}\n{}", e, usage);
+                process::exit(2);
             }
-            _ => None,
-        };
-
-        let chip8_key = get_chip8_keycode_for(key);
-        if chip8_key.is_some()
-            || Instant::now() - last_key_update_time >= Duration::from_millis(200)
-        {
-            last_key_update_time = Instant::now();
-            chip8.set_key_pressed(chip8_key);
-        }
-
-        if Instant::now() - last_instruction_run_time > Duration::from_millis(2) {
-            chip8.run_instruction();
-            last_instruction_run_time = Instant::now();
-        }
-
-        if Instant::now() - last_display_time > Duration::from_millis(10) {
-            let chip8_buffer = chip8.get_display_buffer();
-
-            for y in 0..height {
-                let y_coord = y / 10;
-                let offset = y * width;
-                for x in 0..width {
-                    let index = Display::get_index_from_coords(x / 10, y_coord);
-                    let pixel = chip8_buffer[index];
-                    let color_pixel = match pixel {
-                        0 => 0x0,
-                        1 => 0xffffff,
-                        _ => unreachable!(),
-                    };
-                    buffer[offset + x] = color_pixel;
-                }
+            Err(Failure::Error(e)) => {
+                eprintln!("{}", e);
+                process::exit(1);
             }
-
-            window.update_with_buffer(&buffer, width, height).unwrap();
-            last_display_time = Instant::now();
         }
     }
+    let options = parse_options(&args).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        process::exit(2);
+    });
+    window::run(options);
 }
@@ -0,0 +1,350 @@
+//! Input movies: the keypad state of every frame, for replaying a run
+//! exactly.
+//!
+//! A movie is a text file. A header names the ROM by SHA-1 and gives
+//! everything else that decides how it runs, then each line holds the keys
+//! of one or more frames:
+//!
+//! ```text
+//! chip8-movie 1
+//! rom 2a9f6d8b4a3c0c6e0e8f5d1c3b2a190807060504
+//! platform chip8
+//! quirks shift=on,load-store=on,jump=off,vf-reset=on,clip=on,display-wait=on
+//! ipf 8
+//! seed 12345
+//! 0000 120
+//! 0010 3
+//! reset
+//! 0000
+//! ```
+//!
+//! `0010 3` holds key 4 for three frames; `reset` resets the machine.
+
+use std::error::Error;
+use std::fmt;
+
+use crate::chip8::{Chip8, MAX_INSTRUCTIONS_PER_FRAME};
+use crate::error::Chip8Error;
+use crate::platform::Platform;
+use crate::quirks::Quirks;
+
+pub const VERSION: u32 = 1;
+
+/// The most inputs a movie file may expand to, a day of frames at 60 Hz;
+/// a few bytes of run length can't ask for more memory than that.
+pub const MAX_INPUTS: usize = 24 * 60 * 60 * 60;
+
+/// One step of a movie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    /// Run one frame with these keys held.
+    Frame(u16),
+    Reset,
+}
+
+/// A problem with a movie file; `line` is 0 when it isn't about one line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MovieError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl MovieError {
+    fn new(line: usize, message: impl Into<String>) -> MovieError {
+        MovieError {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "line {}: {}", self.line, self.message)
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_sha1: [u8; 20],
+    pub platform: Platform,
+    pub quirks: Quirks,
+    pub instructions_per_frame: usize,
+    pub seed: u64,
+    pub inputs: Vec<Input>,
+}
+
+impl Movie {
+    /// An empty movie of `chip8` as configured now. Replays start from
+    /// power-on, so record from a fresh or freshly reset machine.
+    pub fn new(chip8: &Chip8) -> Movie {
+        Movie {
+            rom_sha1: chip8.rom_sha1(),
+            platform: chip8.platform(),
+            quirks: chip8.quirks(),
+            instructions_per_frame: chip8.instructions_per_frame(),
+            seed: chip8.seed(),
+            inputs: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, input: Input) {
+        self.inputs.push(input);
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.inputs
+            .iter()
+            .filter(|input| matches!(input, Input::Frame(_)))
+            .count()
+    }
+
+    /// Configures `chip8`, which must have the movie's ROM loaded, the way
+    /// the movie was recorded and resets it, ready for the first input.
+    pub fn start(&self, chip8: &mut Chip8) -> Result<(), MovieError> {
+        if chip8.rom_sha1() != self.rom_sha1 {
+            return Err(MovieError::new(
+                0,
+                "movie was recorded with a different ROM",
+            ));
+        }
+        chip8.set_platform(self.platform);
+        chip8.set_quirks(self.quirks);
+        chip8.set_instructions_per_frame(self.instructions_per_frame);
+        chip8.set_seed(self.seed);
+        chip8.reset();
+        Ok(())
+    }
+
+    /// Applies one input: a frame runs with its keys held.
+    pub fn play(chip8: &mut Chip8, input: Input) -> Result<(), Chip8Error> {
+        match input {
+            Input::Frame(keys) => {
+                chip8.set_keys(keys);
+                chip8.run_frame()
+            }
+            Input::Reset => {
+                chip8.reset();
+                Ok(())
+            }
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, MovieError> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty());
+        let mut header = |name: &str| -> Result<(usize, &str), MovieError> {
+            let (number, line) = lines
+                .next()
+                .ok_or_else(|| MovieError::new(0, format!("missing {}", name)))?;
+            match line.split_once(' ') {
+                Some((key, value)) if key == name => Ok((number, value.trim())),
+                _ => Err(MovieError::new(number, format!("expected {}", name))),
+            }
+        };
+        let (number, version) = header("chip8-movie")?;
+        if version != VERSION.to_string() {
+            return Err(MovieError::new(
+                number,
+                format!("movie version {} is not supported", version),
+            ));
+        }
+        let (number, rom) = header("rom")?;
+        let rom_sha1 = parse_sha1(rom).ok_or_else(|| MovieError::new(number, "invalid SHA-1"))?;
+        let (number, platform) = header("platform")?;
+        let platform = Platform::from_name(platform)
+            .ok_or_else(|| MovieError::new(number, format!("unknown platform {}", platform)))?;
+        let (number, quirks) = header("quirks")?;
+        let quirks = quirks.parse().map_err(|e| MovieError::new(number, e))?;
+        let (number, ipf) = header("ipf")?;
+        let instructions_per_frame = ipf
+            .parse()
+            .ok()
+            .filter(|count| (1..=MAX_INSTRUCTIONS_PER_FRAME).contains(count))
+            .ok_or_else(|| MovieError::new(number, format!("invalid ipf {}", ipf)))?;
+        let (number, seed) = header("seed")?;
+        let seed = seed
+            .parse()
+            .map_err(|_| MovieError::new(number, format!("invalid seed {}", seed)))?;
+
+        let mut inputs = Vec::new();
+        for (number, line) in lines {
+            let (input, count) = if line == "reset" {
+                (Input::Reset, 1)
+            } else {
+                let (keys, count) = line.split_once(' ').unwrap_or((line, "1"));
+                let keys = u16::from_str_radix(keys, 16)
+                    .map_err(|_| MovieError::new(number, format!("invalid keys {}", keys)))?;
+                let count = count.trim().parse().map_err(|_| {
+                    MovieError::new(number, format!("invalid frame count {}", count))
+                })?;
+                (Input::Frame(keys), count)
+            };
+            if count > MAX_INPUTS - inputs.len() {
+                let message = format!("movie is longer than {} frames", MAX_INPUTS);
+                return Err(MovieError::new(number, message));
+            }
+            inputs.extend(std::iter::repeat_n(input, count));
+        }
+        Ok(Movie {
+            rom_sha1,
+            platform,
+            quirks,
+            instructions_per_frame,
+            seed,
+            inputs,
+        })
+    }
+}
+
+/// Feeds a movie to a machine one frame at a time, for frontends that run
+/// frames themselves.
+pub struct Player {
+    movie: Movie,
+    position: usize,
+}
+
+impl Player {
+    /// Starts `movie` on `chip8`, as [`Movie::start`] does.
+    pub fn new(movie: Movie, chip8: &mut Chip8) -> Result<Player, MovieError> {
+        movie.start(chip8)?;
+        Ok(Player { movie, position: 0 })
+    }
+
+    /// Applies any resets due before the next frame and returns the keys to
+    /// hold for it, or `None` once the movie is over.
+    pub fn next_keys(&mut self, chip8: &mut Chip8) -> Option<u16> {
+        loop {
+            let input = *self.movie.inputs.get(self.position)?;
+            self.position += 1;
+            match input {
+                Input::Frame(keys) => return Some(keys),
+                Input::Reset => chip8.reset(),
+            }
+        }
+    }
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "chip8-movie {}", VERSION)?;
+        write!(f, "rom ")?;
+        for byte in self.rom_sha1 {
+            write!(f, "{:02x}", byte)?;
+        }
+        writeln!(f)?;
+        writeln!(f, "platform {}", self.platform.name())?;
+        writeln!(f, "quirks {}", self.quirks)?;
+        writeln!(f, "ipf {}", self.instructions_per_frame)?;
+        writeln!(f, "seed {}", self.seed)?;
+        let mut inputs = self.inputs.iter().peekable();
+        while let Some(&input) = inputs.next() {
+            match input {
+                Input::Reset => writeln!(f, "reset")?,
+                Input::Frame(keys) => {
+                    let mut count = 1;
+                    while inputs.next_if_eq(&&input).is_some() {
+                        count += 1;
+                    }
+                    if count == 1 {
+                        writeln!(f, "{:04X}", keys)?;
+                    } else {
+                        writeln!(f, "{:04X} {}", keys, count)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn parse_sha1(text: &str) -> Option<[u8; 20]> {
+    if text.len() != 40 {
+        return None;
+    }
+    let mut sha1 = [0; 20];
+    for (index, byte) in sha1.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(text.get(index * 2..index * 2 + 2)?, 16).ok()?;
+    }
+    Some(sha1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 200: rnd v0, 0xFF; 202: skp v1; 204: jp 200; 206: add v2, 1; 208: jp 200
+    const ROM: [u8; 10] = [0xC0, 0xFF, 0xE1, 0x9E, 0x12, 0x00, 0x72, 0x01, 0x12, 0x00];
+
+    fn run(movie: &Movie) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(&ROM).unwrap();
+        movie.start(&mut chip8).unwrap();
+        for &input in &movie.inputs {
+            Movie::play(&mut chip8, input).unwrap();
+        }
+        chip8
+    }
+
+    #[test]
+    fn replays_are_identical() {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(&ROM).unwrap();
+        let mut movie = Movie::new(&chip8);
+        let inputs = [0, 0, 1, 1, 1, 0].map(Input::Frame);
+        for input in inputs.into_iter().chain([Input::Reset, Input::Frame(1)]) {
+            Movie::play(&mut chip8, input).unwrap();
+            movie.record(input);
+        }
+        assert_eq!(movie.frame_count(), 7);
+
+        let text = movie.to_string();
+        let expected = format!(
+            "ipf 8\nseed {}\n0000 2\n0001 3\n0000\nreset\n0001\n",
+            chip8.seed()
+        );
+        assert!(text.ends_with(&expected));
+        let parsed = Movie::parse(&text).unwrap();
+        assert_eq!(parsed, movie);
+        let replayed = run(&parsed);
+        assert_eq!(replayed.cpu().registers(), chip8.cpu().registers());
+        assert_eq!(replayed.save_state(), chip8.save_state());
+    }
+
+    #[test]
+    fn bad_movies_are_rejected() {
+        let mut movie = Movie::new(&Chip8::new());
+        movie.record(Input::Frame(0));
+        let text = movie.to_string();
+        assert_eq!(
+            Movie::parse(&text.replace("ipf", "ips")).unwrap_err(),
+            MovieError::new(5, "expected ipf")
+        );
+        for ipf in ["0", "18446744073709551615"] {
+            assert_eq!(
+                Movie::parse(&text.replace("ipf 8", &format!("ipf {}", ipf))).unwrap_err(),
+                MovieError::new(5, format!("invalid ipf {}", ipf))
+            );
+        }
+        assert_eq!(
+            Movie::parse(&(text.clone() + "12345 2\n")).unwrap_err(),
+            MovieError::new(8, "invalid keys 12345")
+        );
+        assert_eq!(
+            Movie::parse(&(text.clone() + "0000 99999999999\n")).unwrap_err(),
+            MovieError::new(8, "movie is longer than 5184000 frames")
+        );
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(&ROM).unwrap();
+        assert!(movie.start(&mut chip8).is_err());
+    }
+}
@@ -0,0 +1,1057 @@
+//! Compiles Octo source (`.8o`) into ROM images.
+//!
+//! Covers the Octo language as the current Octo IDE accepts it: labels,
+//! `:=`-style register statements, `if ... then`, `if ... begin ... else
+//! ... end`, `loop ... while ... again`, `:const`, `:alias`, `:macro`,
+//! `:calc`, `:unpack`, `:next`, `:org`, `:byte` and `:call`, plus the
+//! SUPER-CHIP and XO-CHIP statements. Names may be used before they are
+//! defined. Debugger directives (`:breakpoint`, `:monitor`) are accepted
+//! and ignored; `:stringmode`, `:assert` and `:pointer` are not supported.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::f64::consts;
+use std::fmt;
+
+use crate::cpu::PROGRAM_START;
+use crate::instruction::{AluOp, Instruction};
+
+// Guards against macros that expand themselves forever.
+const MAX_MACRO_EXPANSIONS: usize = 100_000;
+
+/// A range of the source: byte offsets, plus the line and column (both
+/// starting at 1) of its first character.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A compile error and the part of the source it is about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OctoError {
+    pub span: Span,
+    pub message: String,
+}
+
+impl OctoError {
+    /// The offending source line with the span underlined.
+    pub fn snippet(&self, source: &str) -> String {
+        let line = source
+            .lines()
+            .nth(self.span.line.saturating_sub(1))
+            .unwrap_or("");
+        let width = source[self.span.start..self.span.end.min(source.len())]
+            .chars()
+            .count()
+            .max(1);
+        format!(
+            "{}\n{}{}",
+            line,
+            " ".repeat(self.span.column.saturating_sub(1)),
+            "^".repeat(width)
+        )
+    }
+}
+
+impl fmt::Display for OctoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}",
+            self.span.line, self.span.column, self.message
+        )
+    }
+}
+
+impl Error for OctoError {}
+
+/// Compiles Octo `source` into a ROM to be loaded at `PROGRAM_START`.
+pub fn compile(source: &str) -> Result<Vec<u8>, OctoError> {
+    let mut compiler = Compiler::new(tokenize(source));
+    while let Some(token) = compiler.next() {
+        compiler.statement(token)?;
+    }
+    compiler.finish()
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    span: Span,
+}
+
+// Octo tokens are separated by whitespace; `#` starts a comment that runs
+// to the end of the line.
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let line_start = line.as_ptr() as usize - source.as_ptr() as usize;
+        let mut chars = line.char_indices().peekable();
+        let mut column = 1;
+        while let Some(&(start, c)) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+                column += 1;
+                continue;
+            }
+            if c == '#' {
+                break;
+            }
+            let token_column = column;
+            let mut end = start;
+            while let Some(&(offset, c)) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                end = offset + c.len_utf8();
+                column += 1;
+                chars.next();
+            }
+            tokens.push(Token {
+                text: line[start..end].to_string(),
+                span: Span {
+                    start: line_start + start,
+                    end: line_start + end,
+                    line: index + 1,
+                    column: token_column,
+                },
+            });
+        }
+    }
+    tokens
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+    // The NNN of the instruction at the position.
+    Address,
+    // The word after F000.
+    Long,
+    // The NN of `:unpack`'s two loads.
+    UnpackHigh(u8),
+    UnpackLow,
+}
+
+#[derive(Debug)]
+enum Flow {
+    // The jump over the `if` body, patched at `else` or `end`.
+    Begin {
+        jump: usize,
+        span: Span,
+    },
+    // The jump over the `else` body, patched at `end`.
+    Else {
+        jump: usize,
+        span: Span,
+    },
+    Loop {
+        start: u16,
+        whiles: Vec<usize>,
+        span: Span,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    Register(u8),
+    Byte(u8),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Condition {
+    Compare(u8, Comparison, Operand),
+    Key(u8),
+    NotKey(u8),
+}
+
+#[derive(Debug)]
+struct Macro {
+    params: Vec<String>,
+    body: Vec<Token>,
+}
+
+struct Compiler {
+    // Upcoming tokens, last first, so macros can push their expansion.
+    pending: Vec<Token>,
+    last_span: Span,
+    rom: Vec<u8>,
+    position: usize,
+    // The first two bytes hold `jump main` unless `main` comes first.
+    main_reclaimed: bool,
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<(usize, Fixup, Token)>,
+    flow: Vec<Flow>,
+    expansions: usize,
+}
+
+impl Compiler {
+    fn new(mut tokens: Vec<Token>) -> Compiler {
+        tokens.reverse();
+        Compiler {
+            pending: tokens,
+            last_span: Span::default(),
+            rom: vec![0, 0],
+            position: 2,
+            main_reclaimed: false,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            flow: Vec::new(),
+            expansions: 0,
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.pending.pop()?;
+        self.last_span = token.span;
+        Some(token)
+    }
+
+    fn peek(&self) -> Option<&str> {
+        self.pending.last().map(|token| token.text.as_str())
+    }
+
+    fn expect(&mut self, what: &str) -> Result<Token, OctoError> {
+        let span = self.last_span;
+        self.next().ok_or_else(|| OctoError {
+            span,
+            message: format!("expected {} after this", what),
+        })
+    }
+
+    fn expect_text(&mut self, text: &str) -> Result<Token, OctoError> {
+        let token = self.expect(&format!("`{}`", text))?;
+        if token.text != text {
+            return Err(error(&token, format!("expected `{}`", text)));
+        }
+        Ok(token)
+    }
+
+    fn here(&self) -> u16 {
+        (PROGRAM_START as usize + self.position) as u16
+    }
+
+    fn emit(&mut self, byte: u8) -> Result<(), OctoError> {
+        if PROGRAM_START as usize + self.position > 0xFFFF {
+            return Err(OctoError {
+                span: self.last_span,
+                message: "program does not fit in memory".to_string(),
+            });
+        }
+        if self.position >= self.rom.len() {
+            self.rom.resize(self.position + 1, 0);
+        }
+        self.rom[self.position] = byte;
+        self.position += 1;
+        Ok(())
+    }
+
+    fn emit_instruction(&mut self, instruction: Instruction) -> Result<(), OctoError> {
+        for byte in instruction.encode() {
+            self.emit(byte)?;
+        }
+        Ok(())
+    }
+
+    fn patch_jump(&mut self, position: usize, target: u16) {
+        self.rom[position] = 0x10 | (target >> 8) as u8 & 0xF;
+        self.rom[position + 1] = target as u8;
+    }
+
+    fn statement(&mut self, token: Token) -> Result<(), OctoError> {
+        match token.text.as_str() {
+            ":" => {
+                let name = self.expect("a label name")?;
+                self.check_name(&name)?;
+                if name.text == "main" && self.position == 2 && self.labels.is_empty() {
+                    self.rom.clear();
+                    self.position = 0;
+                    self.main_reclaimed = true;
+                }
+                self.define_label(name, self.here())?;
+            }
+            ":const" => {
+                let name = self.expect("a constant name")?;
+                self.check_name(&name)?;
+                let value = self.expect("a value")?;
+                let value = self.number(&value)?;
+                self.constants.insert(name.text, value);
+            }
+            ":alias" => {
+                let name = self.expect("an alias name")?;
+                self.check_name(&name)?;
+                let register = self.expect("a register")?;
+                let register = self.register(&register)?;
+                self.aliases.insert(name.text, register);
+            }
+            ":calc" => {
+                let name = self.expect("a constant name")?;
+                self.check_name(&name)?;
+                let value = self.braced_calc()?;
+                self.constants.insert(name.text, value);
+            }
+            ":byte" => {
+                let value = if self.peek() == Some("{") {
+                    self.braced_calc()?
+                } else {
+                    let value = self.expect("a value")?;
+                    self.number(&value)?
+                };
+                self.emit(value as i64 as u8)?;
+            }
+            ":org" => {
+                let value = self.expect("an address")?;
+                let address = self.number(&value)? as i64;
+                if !(PROGRAM_START as i64..=0xFFFF).contains(&address) {
+                    return Err(error(&value, "address outside the program"));
+                }
+                self.position = (address - PROGRAM_START as i64) as usize;
+            }
+            ":next" => {
+                let name = self.expect("a label name")?;
+                self.check_name(&name)?;
+                // The second byte of the next instruction.
+                self.define_label(name, self.here() + 1)?;
+            }
+            ":unpack" => {
+                let high = self.expect("a nibble")?;
+                let high = self.nibble(&high)?;
+                let target = self.expect("an address")?;
+                let address = self.address(&target, Fixup::UnpackHigh(high))?;
+                let value = (high as u16) << 12 | address;
+                self.emit_instruction(Instruction::LoadImm(0, (value >> 8) as u8))?;
+                let address = self.address(&target, Fixup::UnpackLow)?;
+                self.emit_instruction(Instruction::LoadImm(1, address as u8))?;
+            }
+            ":call" => {
+                let target = self.expect("an address")?;
+                let address = self.address(&target, Fixup::Address)?;
+                self.emit_instruction(Instruction::Call(address))?;
+            }
+            ":macro" => self.define_macro()?,
+            ":proto" => {
+                self.expect("a label name")?;
+            }
+            ":breakpoint" => {
+                self.expect("a breakpoint name")?;
+            }
+            ":monitor" => {
+                self.expect("an address")?;
+                self.expect("a length or format")?;
+            }
+            "clear" => self.emit_instruction(Instruction::ClearScreen)?,
+            "return" | ";" => self.emit_instruction(Instruction::Return)?,
+            "hires" => self.emit_instruction(Instruction::HighRes)?,
+            "lores" => self.emit_instruction(Instruction::LowRes)?,
+            "exit" => self.emit_instruction(Instruction::Exit)?,
+            "scroll-left" => self.emit_instruction(Instruction::ScrollLeft)?,
+            "scroll-right" => self.emit_instruction(Instruction::ScrollRight)?,
+            "audio" => self.emit_instruction(Instruction::LoadAudio)?,
+            "scroll-down" | "scroll-up" | "plane" => {
+                let n = self.expect("a number")?;
+                let n = self.nibble(&n)?;
+                self.emit_instruction(match token.text.as_str() {
+                    "scroll-down" => Instruction::ScrollDown(n),
+                    "scroll-up" => Instruction::ScrollUp(n),
+                    _ => Instruction::SelectPlanes(n),
+                })?;
+            }
+            "bcd" | "saveflags" | "loadflags" => {
+                let x = self.expect("a register")?;
+                let x = self.register(&x)?;
+                self.emit_instruction(match token.text.as_str() {
+                    "bcd" => Instruction::Bcd(x),
+                    "saveflags" => Instruction::SaveFlags(x),
+                    _ => Instruction::LoadFlags(x),
+                })?;
+            }
+            "save" | "load" => {
+                let x = self.expect("a register")?;
+                let x = self.register(&x)?;
+                let save = token.text == "save";
+                let instruction = if self.peek() == Some("-") {
+                    self.next();
+                    let y = self.expect("a register")?;
+                    let y = self.register(&y)?;
+                    if save {
+                        Instruction::SaveRange(x, y)
+                    } else {
+                        Instruction::LoadRange(x, y)
+                    }
+                } else if save {
+                    Instruction::Store(x)
+                } else {
+                    Instruction::Load(x)
+                };
+                self.emit_instruction(instruction)?;
+            }
+            "sprite" => {
+                let x = self.expect("a register")?;
+                let x = self.register(&x)?;
+                let y = self.expect("a register")?;
+                let y = self.register(&y)?;
+                let n = self.expect("a height")?;
+                let n = self.nibble(&n)?;
+                self.emit_instruction(Instruction::Draw(x, y, n))?;
+            }
+            "jump" | "jump0" | "native" => {
+                let target = self.expect("an address")?;
+                let address = self.address(&target, Fixup::Address)?;
+                self.emit_instruction(match token.text.as_str() {
+                    "jump" => Instruction::Jump(address),
+                    "jump0" => Instruction::JumpOffset(address),
+                    _ => Instruction::Sys(address),
+                })?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect_text(":=")?;
+                let x = self.expect("a register")?;
+                let x = self.register(&x)?;
+                self.emit_instruction(match token.text.as_str() {
+                    "delay" => Instruction::SetDelay(x),
+                    "buzzer" => Instruction::SetSound(x),
+                    _ => Instruction::SetPitch(x),
+                })?;
+            }
+            "i" => self.index_statement()?,
+            "if" => self.if_statement(token)?,
+            "else" => match self.flow.pop() {
+                Some(Flow::Begin { jump, .. }) => {
+                    let else_jump = self.position;
+                    self.emit_instruction(Instruction::Jump(0))?;
+                    self.patch_jump(jump, self.here());
+                    self.flow.push(Flow::Else {
+                        jump: else_jump,
+                        span: token.span,
+                    });
+                }
+                other => {
+                    self.flow.extend(other);
+                    return Err(error(&token, "`else` without `if ... begin`"));
+                }
+            },
+            "end" => match self.flow.pop() {
+                Some(Flow::Begin { jump, .. } | Flow::Else { jump, .. }) => {
+                    self.patch_jump(jump, self.here())
+                }
+                other => {
+                    self.flow.extend(other);
+                    return Err(error(&token, "`end` without `if ... begin`"));
+                }
+            },
+            "loop" => self.flow.push(Flow::Loop {
+                start: self.here(),
+                whiles: Vec::new(),
+                span: token.span,
+            }),
+            "while" => {
+                let condition = self.condition()?;
+                self.skip(condition, true)?;
+                let jump = self.position;
+                self.emit_instruction(Instruction::Jump(0))?;
+                match self
+                    .flow
+                    .iter_mut()
+                    .rev()
+                    .find(|flow| matches!(flow, Flow::Loop { .. }))
+                {
+                    Some(Flow::Loop { whiles, .. }) => whiles.push(jump),
+                    _ => return Err(error(&token, "`while` outside `loop`")),
+                }
+            }
+            "again" => match self.flow.pop() {
+                Some(Flow::Loop { start, whiles, .. }) => {
+                    self.emit_instruction(Instruction::Jump(start))?;
+                    for jump in whiles {
+                        self.patch_jump(jump, self.here());
+                    }
+                }
+                other => {
+                    self.flow.extend(other);
+                    return Err(error(&token, "`again` without `loop`"));
+                }
+            },
+            _ if self.is_register(&token.text) => self.register_statement(token)?,
+            _ if self.macros.contains_key(&token.text) => self.expand_macro(token)?,
+            // Numbers are data.
+            _ if parse_number(&token.text).is_some()
+                || self.constants.contains_key(&token.text) =>
+            {
+                let value = self.byte(&token)?;
+                self.emit(value)?;
+            }
+            // Anything else names a subroutine, possibly defined later.
+            _ => {
+                self.check_name(&token)?;
+                let address = self.address(&token, Fixup::Address)?;
+                self.emit_instruction(Instruction::Call(address))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn index_statement(&mut self) -> Result<(), OctoError> {
+        let op = self.expect("`:=` or `+=`")?;
+        match op.text.as_str() {
+            "+=" => {
+                let x = self.expect("a register")?;
+                let x = self.register(&x)?;
+                self.emit_instruction(Instruction::AddI(x))
+            }
+            ":=" => {
+                let value = self.expect("an address")?;
+                match value.text.as_str() {
+                    "hex" | "bighex" => {
+                        let x = self.expect("a register")?;
+                        let x = self.register(&x)?;
+                        self.emit_instruction(if value.text == "hex" {
+                            Instruction::Font(x)
+                        } else {
+                            Instruction::BigFont(x)
+                        })
+                    }
+                    "long" => {
+                        let target = self.expect("an address")?;
+                        let address = self.address(&target, Fixup::Long)?;
+                        self.emit_instruction(Instruction::LoadILong(address))
+                    }
+                    _ => {
+                        let address = self.address(&value, Fixup::Address)?;
+                        self.emit_instruction(Instruction::LoadI(address))
+                    }
+                }
+            }
+            _ => Err(error(&op, "expected `:=` or `+=`")),
+        }
+    }
+
+    fn register_statement(&mut self, target: Token) -> Result<(), OctoError> {
+        let x = self.register(&target)?;
+        let op = self.expect("an operator")?;
+        let source = self.expect("a value")?;
+        let y = self.is_register(&source.text);
+        let alu = |op| {
+            if y {
+                Ok(op)
+            } else {
+                Err(error(&source, "expected a register"))
+            }
+        };
+        let instruction = match op.text.as_str() {
+            ":=" => match source.text.as_str() {
+                "key" => Instruction::WaitKey(x),
+                "delay" => Instruction::GetDelay(x),
+                "random" => {
+                    let mask = self.expect("a mask")?;
+                    Instruction::Random(x, self.byte(&mask)?)
+                }
+                _ if y => Instruction::Alu(AluOp::Load, x, self.register(&source)?),
+                _ => Instruction::LoadImm(x, self.byte(&source)?),
+            },
+            "+=" if y => Instruction::Alu(AluOp::Add, x, self.register(&source)?),
+            "+=" => Instruction::AddImm(x, self.byte(&source)?),
+            // There is no subtract-immediate; add the negation instead.
+            "-=" if !y => Instruction::AddImm(x, self.byte(&source)?.wrapping_neg()),
+            "-=" => Instruction::Alu(alu(AluOp::Sub)?, x, self.register(&source)?),
+            "=-" => Instruction::Alu(alu(AluOp::SubN)?, x, self.register(&source)?),
+            "|=" => Instruction::Alu(alu(AluOp::Or)?, x, self.register(&source)?),
+            "&=" => Instruction::Alu(alu(AluOp::And)?, x, self.register(&source)?),
+            "^=" => Instruction::Alu(alu(AluOp::Xor)?, x, self.register(&source)?),
+            ">>=" => Instruction::Alu(alu(AluOp::ShiftRight)?, x, self.register(&source)?),
+            "<<=" => Instruction::Alu(alu(AluOp::ShiftLeft)?, x, self.register(&source)?),
+            _ => return Err(error(&op, format!("unknown operator `{}`", op.text))),
+        };
+        self.emit_instruction(instruction)
+    }
+
+    fn if_statement(&mut self, token: Token) -> Result<(), OctoError> {
+        let condition = self.condition()?;
+        let keyword = self.expect("`then` or `begin`")?;
+        match keyword.text.as_str() {
+            // Skip the next statement unless the condition holds.
+            "then" => self.skip(condition, false),
+            // Skip the jump over the block when the condition holds.
+            "begin" => {
+                self.skip(condition, true)?;
+                self.flow.push(Flow::Begin {
+                    jump: self.position,
+                    span: token.span,
+                });
+                self.emit_instruction(Instruction::Jump(0))
+            }
+            _ => Err(error(&keyword, "expected `then` or `begin`")),
+        }
+    }
+
+    fn condition(&mut self) -> Result<Condition, OctoError> {
+        let left = self.expect("a register")?;
+        let x = self.register(&left)?;
+        let op = self.expect("a comparison")?;
+        let comparison = match op.text.as_str() {
+            "key" => return Ok(Condition::Key(x)),
+            "-key" => return Ok(Condition::NotKey(x)),
+            "==" => Comparison::Eq,
+            "!=" => Comparison::Ne,
+            "<" => Comparison::Lt,
+            ">" => Comparison::Gt,
+            "<=" => Comparison::Le,
+            ">=" => Comparison::Ge,
+            _ => return Err(error(&op, format!("unknown comparison `{}`", op.text))),
+        };
+        let right = self.expect("a value")?;
+        let operand = if self.is_register(&right.text) {
+            Operand::Register(self.register(&right)?)
+        } else {
+            Operand::Byte(self.byte(&right)?)
+        };
+        Ok(Condition::Compare(x, comparison, operand))
+    }
+
+    // Emits code that skips the next instruction when `condition` is
+    // `when`.
+    fn skip(&mut self, condition: Condition, when: bool) -> Result<(), OctoError> {
+        let instruction = match condition {
+            Condition::Key(x) if when => Instruction::SkipKeyPressed(x),
+            Condition::Key(x) => Instruction::SkipKeyNotPressed(x),
+            Condition::NotKey(x) if when => Instruction::SkipKeyNotPressed(x),
+            Condition::NotKey(x) => Instruction::SkipKeyPressed(x),
+            Condition::Compare(x, comparison @ (Comparison::Eq | Comparison::Ne), operand) => {
+                let equal = matches!(comparison, Comparison::Eq) == when;
+                match (operand, equal) {
+                    (Operand::Register(y), true) => Instruction::SkipEqReg(x, y),
+                    (Operand::Register(y), false) => Instruction::SkipNeReg(x, y),
+                    (Operand::Byte(nn), true) => Instruction::SkipEqImm(x, nn),
+                    (Operand::Byte(nn), false) => Instruction::SkipNeImm(x, nn),
+                }
+            }
+            Condition::Compare(x, comparison, operand) => {
+                // VF = right, then VF -= x or VF =- x leaves the no-borrow
+                // flag in VF, which decides the comparison.
+                self.emit_instruction(match operand {
+                    Operand::Register(y) => Instruction::Alu(AluOp::Load, 0xF, y),
+                    Operand::Byte(nn) => Instruction::LoadImm(0xF, nn),
+                })?;
+                let (op, holds_when) = match comparison {
+                    // right >= x
+                    Comparison::Le => (AluOp::Sub, 1),
+                    Comparison::Gt => (AluOp::Sub, 0),
+                    // x >= right
+                    Comparison::Ge => (AluOp::SubN, 1),
+                    _ => (AluOp::SubN, 0),
+                };
+                self.emit_instruction(Instruction::Alu(op, 0xF, x))?;
+                if when {
+                    Instruction::SkipEqImm(0xF, holds_when)
+                } else {
+                    Instruction::SkipNeImm(0xF, holds_when)
+                }
+            }
+        };
+        self.emit_instruction(instruction)
+    }
+
+    fn define_macro(&mut self) -> Result<(), OctoError> {
+        let name = self.expect("a macro name")?;
+        self.check_name(&name)?;
+        let mut params = Vec::new();
+        loop {
+            let token = self.expect("`{`")?;
+            if token.text == "{" {
+                break;
+            }
+            params.push(token.text);
+        }
+        let body = self.braced_tokens()?;
+        self.macros.insert(name.text, Macro { params, body });
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, call: Token) -> Result<(), OctoError> {
+        self.expansions += 1;
+        if self.expansions > MAX_MACRO_EXPANSIONS {
+            return Err(error(&call, "too many macro expansions"));
+        }
+        let count = self.macros[&call.text].params.len();
+        let mut args = Vec::new();
+        for _ in 0..count {
+            args.push(self.expect("a macro argument")?);
+        }
+        let definition = &self.macros[&call.text];
+        let expansion: Vec<Token> = definition
+            .body
+            .iter()
+            .map(|token| {
+                match definition
+                    .params
+                    .iter()
+                    .position(|param| *param == token.text)
+                {
+                    Some(index) => args[index].clone(),
+                    None => token.clone(),
+                }
+            })
+            .collect();
+        self.pending.extend(expansion.into_iter().rev());
+        Ok(())
+    }
+
+    // The tokens up to the `}` matching an already consumed `{`.
+    fn braced_tokens(&mut self) -> Result<Vec<Token>, OctoError> {
+        let mut depth = 0;
+        let mut tokens = Vec::new();
+        loop {
+            let token = self.expect("`}`")?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" if depth == 0 => return Ok(tokens),
+                "}" => depth -= 1,
+                _ => {}
+            }
+            tokens.push(token);
+        }
+    }
+
+    fn braced_calc(&mut self) -> Result<f64, OctoError> {
+        let open = self.expect_text("{")?;
+        let tokens = self.braced_tokens()?;
+        let mut calc = Calc {
+            compiler: self,
+            tokens: &tokens,
+            index: 0,
+            span: open.span,
+        };
+        let value = calc.expression()?;
+        if let Some(token) = tokens.get(calc.index) {
+            return Err(error(token, "unexpected token in expression"));
+        }
+        Ok(value)
+    }
+
+    fn finish(mut self) -> Result<Vec<u8>, OctoError> {
+        if let Some(flow) = self.flow.last() {
+            let (span, message) = match flow {
+                Flow::Begin { span, .. } | Flow::Else { span, .. } => {
+                    (*span, "`if ... begin` without `end`")
+                }
+                Flow::Loop { span, .. } => (*span, "`loop` without `again`"),
+            };
+            return Err(OctoError {
+                span,
+                message: message.to_string(),
+            });
+        }
+        for (position, fixup, token) in std::mem::take(&mut self.fixups) {
+            let address = *self
+                .labels
+                .get(&token.text)
+                .ok_or_else(|| error(&token, format!("undefined name `{}`", token.text)))?;
+            match fixup {
+                Fixup::Address => {
+                    if address > 0xFFF {
+                        return Err(error(&token, "address is out of range"));
+                    }
+                    self.rom[position] |= (address >> 8) as u8;
+                    self.rom[position + 1] = address as u8;
+                }
+                Fixup::Long => {
+                    self.rom[position + 2] = (address >> 8) as u8;
+                    self.rom[position + 3] = address as u8;
+                }
+                Fixup::UnpackHigh(high) => {
+                    self.rom[position + 1] = high << 4 | (address >> 8) as u8 & 0xF
+                }
+                Fixup::UnpackLow => self.rom[position + 1] = address as u8,
+            }
+        }
+        if !self.main_reclaimed {
+            let main = *self.labels.get("main").ok_or_else(|| OctoError {
+                span: Span {
+                    line: 1,
+                    column: 1,
+                    ..Span::default()
+                },
+                message: "the program has no `main` label".to_string(),
+            })?;
+            self.patch_jump(0, main);
+        }
+        Ok(self.rom)
+    }
+
+    fn check_name(&self, token: &Token) -> Result<(), OctoError> {
+        let valid = token
+            .text
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_alphabetic() || c == '_')
+            && token
+                .text
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+        if !valid || self.is_register(&token.text) {
+            return Err(error(token, format!("invalid name `{}`", token.text)));
+        }
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: Token, address: u16) -> Result<(), OctoError> {
+        if self.labels.contains_key(&name.text) {
+            return Err(error(&name, format!("`{}` is already defined", name.text)));
+        }
+        self.labels.insert(name.text, address);
+        Ok(())
+    }
+
+    fn is_register(&self, text: &str) -> bool {
+        register_number(text).is_some() || self.aliases.contains_key(text)
+    }
+
+    fn register(&self, token: &Token) -> Result<u8, OctoError> {
+        register_number(&token.text)
+            .or_else(|| self.aliases.get(&token.text).copied())
+            .ok_or_else(|| error(token, "expected a register"))
+    }
+
+    // A literal, a constant or an already defined label.
+    fn value(&self, token: &Token) -> Option<f64> {
+        parse_number(&token.text)
+            .or_else(|| self.constants.get(&token.text).copied())
+            .or_else(|| self.labels.get(&token.text).map(|&address| address as f64))
+    }
+
+    fn number(&self, token: &Token) -> Result<f64, OctoError> {
+        self.value(token)
+            .ok_or_else(|| error(token, format!("undefined name `{}`", token.text)))
+    }
+
+    fn byte(&self, token: &Token) -> Result<u8, OctoError> {
+        let value = self.number(token)?;
+        if !(-128.0..256.0).contains(&value) {
+            return Err(error(token, format!("{} does not fit in a byte", value)));
+        }
+        Ok(value as i64 as u8)
+    }
+
+    fn nibble(&self, token: &Token) -> Result<u8, OctoError> {
+        let value = self.number(token)?;
+        if !(0.0..16.0).contains(&value) {
+            return Err(error(token, format!("{} does not fit in a nibble", value)));
+        }
+        Ok(value as u8)
+    }
+
+    // The address `token` names, or 0 with a fixup for the instruction
+    // about to be emitted when it is defined later.
+    fn address(&mut self, token: &Token, fixup: Fixup) -> Result<u16, OctoError> {
+        match self.value(token) {
+            Some(value) => {
+                let max = match fixup {
+                    Fixup::Address => 0xFFF,
+                    _ => 0xFFFF,
+                };
+                if !(0.0..=max as f64).contains(&value) {
+                    return Err(error(token, "address is out of range"));
+                }
+                Ok(value as u16)
+            }
+            None => {
+                self.fixups.push((self.position, fixup, token.clone()));
+                Ok(0)
+            }
+        }
+    }
+}
+
+fn error(token: &Token, message: impl Into<String>) -> OctoError {
+    OctoError {
+        span: token.span,
+        message: message.into(),
+    }
+}
+
+fn register_number(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix(['v', 'V'])?;
+    if digit.len() != 1 {
+        return None;
+    }
+    u8::from_str_radix(digit, 16).ok()
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()?
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value } as f64)
+}
+
+/// `:calc` expressions. As in Octo, binary operators have no precedence and
+/// group to the right, so `2 * 3 + 1` is 8; use parentheses otherwise.
+struct Calc<'a> {
+    compiler: &'a Compiler,
+    tokens: &'a [Token],
+    index: usize,
+    span: Span,
+}
+
+impl Calc<'_> {
+    fn next(&mut self) -> Result<&Token, OctoError> {
+        let token = self.tokens.get(self.index).ok_or_else(|| OctoError {
+            span: self.tokens.last().map_or(self.span, |token| token.span),
+            message: "incomplete expression".to_string(),
+        })?;
+        self.index += 1;
+        Ok(token)
+    }
+
+    fn expression(&mut self) -> Result<f64, OctoError> {
+        let left = self.term()?;
+        let Some(op) = self.tokens.get(self.index) else {
+            return Ok(left);
+        };
+        let apply: fn(f64, f64) -> f64 = match op.text.as_str() {
+            "+" => |a, b| a + b,
+            "-" => |a, b| a - b,
+            "*" => |a, b| a * b,
+            "/" => |a, b| a / b,
+            "%" => |a, b| a % b,
+            "&" => |a, b| (a as i64 & b as i64) as f64,
+            "|" => |a, b| (a as i64 | b as i64) as f64,
+            "^" => |a, b| (a as i64 ^ b as i64) as f64,
+            "<<" => |a, b| ((a as i64) << (b as i64 & 63)) as f64,
+            ">>" => |a, b| ((a as i64) >> (b as i64 & 63)) as f64,
+            "pow" => f64::powf,
+            "min" => f64::min,
+            "max" => f64::max,
+            "<" => |a, b| (a < b) as u8 as f64,
+            ">" => |a, b| (a > b) as u8 as f64,
+            "<=" => |a, b| (a <= b) as u8 as f64,
+            ">=" => |a, b| (a >= b) as u8 as f64,
+            "==" => |a, b| (a == b) as u8 as f64,
+            "!=" => |a, b| (a != b) as u8 as f64,
+            _ => return Ok(left),
+        };
+        self.index += 1;
+        let right = self.expression()?;
+        Ok(apply(left, right))
+    }
+
+    fn term(&mut self) -> Result<f64, OctoError> {
+        let token = self.next()?.clone();
+        let unary: fn(f64) -> f64 = match token.text.as_str() {
+            "(" => {
+                let value = self.expression()?;
+                let close = self.next()?;
+                if close.text != ")" {
+                    return Err(error(close, "expected `)`"));
+                }
+                return Ok(value);
+            }
+            "-" => |a| -a,
+            "~" => |a| !(a as i64) as f64,
+            "!" => |a| (a == 0.0) as u8 as f64,
+            "sin" => f64::sin,
+            "cos" => f64::cos,
+            "tan" => f64::tan,
+            "exp" => f64::exp,
+            "log" => f64::ln,
+            "abs" => f64::abs,
+            "sqrt" => f64::sqrt,
+            "sign" => f64::signum,
+            "ceil" => f64::ceil,
+            "floor" => f64::floor,
+            "PI" => return Ok(consts::PI),
+            "E" => return Ok(consts::E),
+            "HERE" => return Ok(self.compiler.here() as f64),
+            _ => return self.compiler.number(&token),
+        };
+        Ok(unary(self.term()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compiles_statements_and_control_flow() {
+        let source = "
+            :alias x v1
+            :const SPEED 2
+            :calc LIMIT { 60 - SPEED * 2 }
+            : main
+                clear
+                i := ball
+                loop
+                    x += SPEED
+                    if x >= LIMIT then x := 0
+                    if v2 key begin draw else draw end
+                again
+            : draw  sprite x v0 1 ;
+            : ball  0x80
+        ";
+        #[rustfmt::skip]
+        let expected = [
+            0x00, 0xE0, // clear
+            0xA2, 0x1E, // i := ball
+            0x71, 0x02, // x += SPEED
+            0x6F, 0x38, 0x8F, 0x17, 0x4F, 0x01, 0x61, 0x00, // if x >= 56 then x := 0
+            0xE2, 0x9E, 0x12, 0x16, 0x22, 0x1A, 0x12, 0x18, 0x22, 0x1A, // if v2 key ...
+            0x12, 0x04, // again
+            0xD1, 0x01, 0x00, 0xEE, // draw
+            0x80, // ball
+        ];
+        assert_eq!(compile(source).unwrap(), expected);
+    }
+
+    #[test]
+    fn jumps_to_main_unless_it_comes_first() {
+        let rom = compile(": helper ;\n: main helper").unwrap();
+        assert_eq!(rom, [0x12, 0x04, 0x00, 0xEE, 0x22, 0x02]);
+    }
+
+    #[test]
+    fn macros_expand_their_arguments() {
+        let source = ":macro twice op { op op }\n: main twice clear";
+        assert_eq!(compile(source).unwrap(), [0x00, 0xE0, 0x00, 0xE0]);
+    }
+
+    #[test]
+    fn errors_point_at_the_token() {
+        let source = ": main\n  v0 := nowhere\n";
+        let error = compile(source).unwrap_err();
+        assert_eq!(error.span.line, 2);
+        assert_eq!(error.span.column, 9);
+        assert_eq!(&source[error.span.start..error.span.end], "nowhere");
+        assert_eq!(error.snippet(source), "  v0 := nowhere\n        ^^^^^^^");
+
+        let source = ": main\n: loop\n  jump loop\n: loop\n";
+        let error = compile(source).unwrap_err();
+        assert_eq!((error.span.line, error.span.column), (4, 3));
+        assert_eq!(error.message, "`loop` is already defined");
+    }
+}
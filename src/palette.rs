@@ -0,0 +1,174 @@
+//! Colours for the four pixel values.
+//!
+//! Besides the presets, a palette can be written as two or four hex colours,
+//! `RRGGBB,RRGGBB[,RRGGBB,RRGGBB]`, for off, the first plane, the second
+//! XO-CHIP plane and both planes. With only two, the plane colours are
+//! shades between them, as in the classic palette.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// 0xRRGGBB colours for pixel values 0 to 3: off, the first plane, the
+/// second XO-CHIP plane and both planes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Palette {
+    pub colors: [u32; 4],
+}
+
+pub const PRESETS: [&str; 10] = [
+    "classic",
+    "lcd",
+    "amber",
+    "octo",
+    "hotdog",
+    "gray",
+    "cga0",
+    "cga1",
+    "high-contrast",
+    "colorblind",
+];
+
+impl Palette {
+    pub const CLASSIC: Palette = Palette {
+        colors: [0x000000, 0xFFFFFF, 0xAAAAAA, 0x555555],
+    };
+
+    pub fn preset(name: &str) -> Option<Palette> {
+        let colors = match name {
+            "classic" => Palette::CLASSIC.colors,
+            // Green monochrome LCD, dark pixels on a light screen.
+            "lcd" => [0x9BBC0F, 0x0F380F, 0x8BAC0F, 0x306230],
+            // Amber phosphor monitor.
+            "amber" => [0x1A0F00, 0xFFB000, 0xB36B00, 0x5C3A00],
+            // Octo's built-in themes.
+            "octo" => [0x996600, 0xFFCC00, 0xFF6600, 0x662200],
+            "hotdog" => [0x000000, 0xFF0000, 0xFFFF00, 0xFFFFFF],
+            "gray" => [0xAAAAAA, 0x000000, 0xFFFFFF, 0x666666],
+            "cga0" => [0x000000, 0x00FF00, 0xFF0000, 0xFFFF00],
+            "cga1" => [0x000000, 0xFF00FF, 0x00FFFF, 0xFFFFFF],
+            "high-contrast" => [0x000000, 0xFFFFFF, 0xFFFF00, 0x00FFFF],
+            // Okabe-Ito colours, told apart with any colour vision.
+            "colorblind" => [0x000000, 0xE69F00, 0x56B4E9, 0xF0E442],
+            _ => return None,
+        };
+        Some(Palette { colors })
+    }
+
+    pub fn color(&self, pixel: u8) -> u32 {
+        self.colors[pixel as usize & 3]
+    }
+
+    pub fn rgb(&self, pixel: u8) -> [u8; 3] {
+        let [_, r, g, b] = self.color(pixel).to_be_bytes();
+        [r, g, b]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::CLASSIC
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    /// A preset's name or a list of hex colours.
+    fn from_str(text: &str) -> Result<Palette, String> {
+        if let Some(palette) = Palette::preset(text) {
+            return Ok(palette);
+        }
+        if !text.contains(',') {
+            return Err(format!(
+                "unknown palette {}, expected one of {:?} or hex colours",
+                text, PRESETS
+            ));
+        }
+        let colors = text
+            .split(',')
+            .map(|color| {
+                let hex = color.trim().trim_start_matches('#');
+                match u32::from_str_radix(hex, 16) {
+                    Ok(color) if hex.len() == 6 => Ok(color),
+                    _ => Err(format!("invalid colour {}, expected RRGGBB", color)),
+                }
+            })
+            .collect::<Result<Vec<u32>, String>>()?;
+        match colors[..] {
+            [off, on] => Ok(Palette {
+                colors: [off, on, mix(off, on, 2), mix(off, on, 1)],
+            }),
+            [off, on, second, both] => Ok(Palette {
+                colors: [off, on, second, both],
+            }),
+            _ => Err(format!("expected 2 or 4 hex colours, got {}", text)),
+        }
+    }
+}
+
+impl fmt::Display for Palette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [off, on, second, both] = self.colors;
+        write!(f, "{:06X},{:06X},{:06X},{:06X}", off, on, second, both)
+    }
+}
+
+// `thirds` of the way from `from` to `to`, channel by channel.
+fn mix(from: u32, to: u32, thirds: u32) -> u32 {
+    (0..3).fold(0, |color, channel| {
+        let shift = channel * 8;
+        let from = (from >> shift & 0xFF) as i32;
+        let to = (to >> shift & 0xFF) as i32;
+        let mixed = from + (to - from) * thirds as i32 / 3;
+        color | (mixed as u32) << shift
+    })
+}
+
+/// Reads named palettes from a file of `NAME COLOURS` lines, where the
+/// colours are written as for [`Palette::from_str`]. Blank lines and lines
+/// starting with `#` are skipped.
+pub fn parse_palettes(text: &str) -> Result<Vec<(String, Palette)>, String> {
+    let mut palettes = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, colors) = line
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| format!("line {}: expected NAME COLOURS", index + 1))?;
+        let palette = colors
+            .trim()
+            .parse()
+            .map_err(|e| format!("line {}: {}", index + 1, e))?;
+        palettes.push((name.to_string(), palette));
+    }
+    Ok(palettes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn palettes_parse_from_names_and_colours() {
+        for name in PRESETS {
+            assert!(Palette::preset(name).is_some(), "{}", name);
+        }
+        assert_eq!("classic".parse(), Ok(Palette::CLASSIC));
+        // Two colours give the classic greys between black and white.
+        assert_eq!("000000,#FFFFFF".parse(), Ok(Palette::CLASSIC));
+        let palette: Palette = "102030,405060,708090,A0B0C0".parse().unwrap();
+        assert_eq!(palette.to_string().parse(), Ok(palette));
+        assert_eq!(palette.rgb(3), [0xA0, 0xB0, 0xC0]);
+        assert!("000000".parse::<Palette>().is_err());
+        assert!("000000,FFFFFG".parse::<Palette>().is_err());
+        assert!("sepia".parse::<Palette>().is_err());
+
+        let palettes = parse_palettes("# mine\n\nsunset FF8000,400080\nbad\n");
+        assert_eq!(palettes.unwrap_err(), "line 4: expected NAME COLOURS");
+        let palettes = parse_palettes("sunset FF8000, 400080\n").unwrap();
+        assert_eq!(palettes[0].0, "sunset");
+        assert_eq!(palettes[0].1.colors[1], 0x400080);
+    }
+}
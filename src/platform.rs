@@ -0,0 +1,62 @@
+use crate::quirks::Quirks;
+use crate::ram::{RAM_SIZE, XO_RAM_SIZE};
+
+/// The instruction set the machine implements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Platform {
+    /// The original COSMAC VIP CHIP-8.
+    #[default]
+    Chip8,
+    /// SUPER-CHIP 1.1: 128x64 hires mode, scrolling, 16x16 sprites, big
+    /// font and RPL flags.
+    SuperChip,
+    /// XO-CHIP: SUPER-CHIP plus 64 KiB of memory, two bitplanes and
+    /// programmable audio.
+    XoChip,
+}
+
+pub const PLATFORMS: [&str; 3] = ["chip8", "schip", "xochip"];
+
+impl Platform {
+    pub fn from_name(name: &str) -> Option<Platform> {
+        match name {
+            "chip8" => Some(Platform::Chip8),
+            "schip" => Some(Platform::SuperChip),
+            "xochip" => Some(Platform::XoChip),
+            _ => None,
+        }
+    }
+
+    /// The name [`Platform::from_name`] accepts.
+    pub fn name(self) -> &'static str {
+        match self {
+            Platform::Chip8 => "chip8",
+            Platform::SuperChip => "schip",
+            Platform::XoChip => "xochip",
+        }
+    }
+
+    /// The quirks ROMs written for this platform expect.
+    pub fn quirks(self) -> Quirks {
+        match self {
+            Platform::Chip8 => Quirks::vip(),
+            Platform::SuperChip => Quirks::schip(),
+            Platform::XoChip => Quirks::xo_chip(),
+        }
+    }
+
+    pub fn memory_size(self) -> usize {
+        match self {
+            Platform::XoChip => XO_RAM_SIZE,
+            _ => RAM_SIZE,
+        }
+    }
+
+    pub fn has_schip_instructions(self) -> bool {
+        self != Platform::Chip8
+    }
+
+    pub fn has_xo_instructions(self) -> bool {
+        self == Platform::XoChip
+    }
+}
@@ -0,0 +1,176 @@
+//! Behaviour that differs between CHIP-8 interpreters.
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// 8XY6/8XYE shift VY into VX instead of shifting VX in place.
+    pub shift_uses_vy: bool,
+    /// FX55/FX65 leave I pointing past the last register transferred.
+    pub load_store_increments_i: bool,
+    /// BNNN jumps to NNN + VX (X being the top nibble of NNN) instead of
+    /// NNN + V0.
+    pub jump_uses_vx: bool,
+    /// 8XY1/8XY2/8XY3 clear VF.
+    pub logic_resets_vf: bool,
+    /// Sprites are cut off at the screen edges instead of wrapping around.
+    pub clip_sprites: bool,
+    /// DXYN waits for the vertical blank, so at most one sprite is drawn per
+    /// frame.
+    pub display_wait: bool,
+}
+
+pub const PRESETS: [&str; 3] = ["vip", "schip", "xochip"];
+
+impl Quirks {
+    /// The original COSMAC VIP interpreter.
+    pub fn vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            logic_resets_vf: true,
+            clip_sprites: true,
+            display_wait: true,
+        }
+    }
+
+    /// SUPER-CHIP 1.1 on the HP 48.
+    pub fn schip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: true,
+            logic_resets_vf: false,
+            clip_sprites: true,
+            display_wait: false,
+        }
+    }
+
+    /// XO-CHIP as implemented by Octo.
+    pub fn xo_chip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            logic_resets_vf: false,
+            clip_sprites: false,
+            display_wait: false,
+        }
+    }
+
+    pub fn preset(name: &str) -> Option<Quirks> {
+        match name {
+            "vip" => Some(Quirks::vip()),
+            "schip" => Some(Quirks::schip()),
+            "xochip" => Some(Quirks::xo_chip()),
+            _ => None,
+        }
+    }
+
+    /// Toggles a single quirk by name, for building custom profiles.
+    pub fn set(&mut self, name: &str, enabled: bool) -> Result<(), String> {
+        let quirk = match name {
+            "shift" => &mut self.shift_uses_vy,
+            "load-store" => &mut self.load_store_increments_i,
+            "jump" => &mut self.jump_uses_vx,
+            "vf-reset" => &mut self.logic_resets_vf,
+            "clip" => &mut self.clip_sprites,
+            "display-wait" => &mut self.display_wait,
+            _ => return Err(format!("unknown quirk {}", name)),
+        };
+        *quirk = enabled;
+        Ok(())
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::vip()
+    }
+}
+
+impl fmt::Display for Quirks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = [
+            ("shift", self.shift_uses_vy),
+            ("load-store", self.load_store_increments_i),
+            ("jump", self.jump_uses_vx),
+            ("vf-reset", self.logic_resets_vf),
+            ("clip", self.clip_sprites),
+            ("display-wait", self.display_wait),
+        ];
+        let mut first = true;
+        for (name, enabled) in flags {
+            if !first {
+                write!(f, ",")?;
+            }
+            first = false;
+            write!(f, "{}={}", name, if enabled { "on" } else { "off" })?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the `name=on,name=off` list `Display` writes, starting from the
+/// default quirks.
+impl FromStr for Quirks {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Quirks, String> {
+        let mut quirks = Quirks::default();
+        for setting in text.split(',').filter(|setting| !setting.is_empty()) {
+            match setting.split_once('=') {
+                Some((name, "on")) => quirks.set(name, true)?,
+                Some((name, "off")) => quirks.set(name, false)?,
+                _ => return Err(format!("expected NAME=on|off, got {}", setting)),
+            }
+        }
+        Ok(quirks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_differ_where_the_interpreters_do() {
+        assert_eq!(Quirks::preset("vip"), Some(Quirks::vip()));
+        assert_eq!(Quirks::preset("schip"), Some(Quirks::schip()));
+        assert_eq!(Quirks::preset("xochip"), Some(Quirks::xo_chip()));
+        assert_eq!(Quirks::preset("octo"), None);
+        assert_eq!(Quirks::default(), Quirks::vip());
+
+        let (vip, schip, xo) = (Quirks::vip(), Quirks::schip(), Quirks::xo_chip());
+        assert!(vip.shift_uses_vy && !schip.shift_uses_vy && xo.shift_uses_vy);
+        assert!(!vip.jump_uses_vx && schip.jump_uses_vx && !xo.jump_uses_vx);
+        assert!(vip.logic_resets_vf && !schip.logic_resets_vf && !xo.logic_resets_vf);
+        assert!(vip.display_wait && !schip.display_wait && !xo.display_wait);
+        assert!(vip.clip_sprites && schip.clip_sprites && !xo.clip_sprites);
+    }
+
+    #[test]
+    fn display_parses_back() {
+        let mut custom = Quirks::schip();
+        custom.set("vf-reset", true).unwrap();
+        custom.set("clip", false).unwrap();
+        for quirks in [Quirks::vip(), Quirks::schip(), Quirks::xo_chip(), custom] {
+            assert_eq!(quirks.to_string().parse(), Ok(quirks));
+        }
+        assert_eq!(
+            Quirks::vip().to_string(),
+            "shift=on,load-store=on,jump=off,vf-reset=on,clip=on,display-wait=on"
+        );
+        // Unnamed quirks keep their defaults.
+        let parsed: Quirks = "jump=on".parse().unwrap();
+        assert_eq!((parsed.jump_uses_vx, parsed.shift_uses_vy), (true, true));
+
+        assert_eq!(
+            Quirks::vip().set("wrap", true),
+            Err("unknown quirk wrap".into())
+        );
+        assert!("shift=yes".parse::<Quirks>().is_err());
+    }
+}
@@ -1,10 +1,19 @@
+use crate::state::{ChunkWriter, StateError, StateReader, StateWriter};
+
+pub const RAM_SIZE: usize = 4096;
+pub const XO_RAM_SIZE: usize = 0x10000;
+pub const FONT_START: u16 = 0x000;
+pub const BIG_FONT_START: u16 = 0x050;
+
 pub struct Ram {
-    mem: [u8; 4096],
+    mem: Vec<u8>,
 }
 
 impl Ram {
     pub fn new() -> Ram {
-        let mut ram = Ram { mem: [0; 4096] };
+        let mut ram = Ram {
+            mem: vec![0; RAM_SIZE],
+        };
 
         let sprites: [[u8; 5]; 16] = [
             [0xF0, 0x90, 0x90, 0x90, 0xF0], // 0
@@ -24,19 +33,78 @@ impl Ram {
             [0xF0, 0x80, 0xF0, 0x80, 0xF0], // E
             [0xF0, 0x80, 0xF0, 0x80, 0x80], // F
         ];
-        let mut i = 0;
+        let mut i = FONT_START as usize;
         for sprite in sprites.iter() {
             for ch in sprite {
                 ram.mem[i] = *ch;
                 i += 1;
             }
         }
+
+        // SUPER-CHIP 8x10 digits; A-F as in Octo.
+        let big_sprites: [[u8; 10]; 16] = [
+            [0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C], // 0
+            [0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C], // 1
+            [0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF], // 2
+            [0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C], // 3
+            [0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06], // 4
+            [0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C], // 5
+            [0x3E, 0x7C, 0xC0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C], // 6
+            [0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60], // 7
+            [0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C], // 8
+            [0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C], // 9
+            [0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3], // A
+            [0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC], // B
+            [0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C], // C
+            [0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC], // D
+            [0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF], // E
+            [0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0], // F
+        ];
+        let mut i = BIG_FONT_START as usize;
+        for sprite in big_sprites.iter() {
+            for ch in sprite {
+                ram.mem[i] = *ch;
+                i += 1;
+            }
+        }
         ram
     }
-    pub fn write_byte(&mut self, addr: u16, byte: u8) {
-        self.mem[addr as usize] = byte;
+    pub fn size(&self) -> usize {
+        self.mem.len()
+    }
+
+    // Growing keeps the current contents, so the fonts survive.
+    pub fn resize(&mut self, size: usize) {
+        self.mem.resize(size, 0);
     }
-    pub fn read_byte(&self, addr: u16) -> u8 {
-        self.mem[addr as usize]
+
+    pub fn write_byte(&mut self, addr: u16, byte: u8) -> Option<()> {
+        let cell = self.mem.get_mut(addr as usize)?;
+        *cell = byte;
+        Some(())
+    }
+    pub fn read_byte(&self, addr: u16) -> Option<u8> {
+        self.mem.get(addr as usize).copied()
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        let mut chunk = ChunkWriter::new();
+        chunk.bytes(&self.mem);
+        state.chunk("RAM ", chunk);
+    }
+
+    pub fn load_state(state: &StateReader) -> Result<Ram, StateError> {
+        let mut chunk = state.chunk("RAM ")?;
+        let mem = chunk.bytes()?.to_vec();
+        if mem.len() != RAM_SIZE && mem.len() != XO_RAM_SIZE {
+            return Err(chunk.corrupt());
+        }
+        Ok(Ram { mem })
+    }
+}
+
+impl Default for Ram {
+    fn default() -> Self {
+        Self::new()
     }
 }
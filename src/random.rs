@@ -0,0 +1,90 @@
+//! Where CXNN gets its random numbers.
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+/// A repeatable stream of random bytes. The same seed must always produce
+/// the same bytes, and seeking must land exactly where `position` was taken,
+/// so that replays and save states stay bit-identical.
+pub trait RandomSource {
+    /// Starts the stream over from `seed`.
+    fn reseed(&mut self, seed: u64);
+
+    fn next_byte(&mut self) -> u8;
+
+    /// How far into the stream this is, for save states.
+    fn position(&self) -> u128;
+
+    /// Continues the stream for `seed` from `position`.
+    fn seek(&mut self, seed: u64, position: u128);
+}
+
+/// The default source: ChaCha8, a fast generator whose position can be
+/// saved.
+pub struct ChaChaSource {
+    rng: ChaCha8Rng,
+}
+
+impl ChaChaSource {
+    pub fn new(seed: u64) -> ChaChaSource {
+        ChaChaSource {
+            rng: ChaCha8Rng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl RandomSource for ChaChaSource {
+    fn reseed(&mut self, seed: u64) {
+        self.rng = ChaCha8Rng::seed_from_u64(seed);
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        self.rng.gen()
+    }
+
+    fn position(&self) -> u128 {
+        self.rng.get_word_pos()
+    }
+
+    fn seek(&mut self, seed: u64, position: u128) {
+        self.reseed(seed);
+        self.rng.set_word_pos(position);
+    }
+}
+
+/// Plays back a fixed list of bytes over and over, whatever the seed; for
+/// tests that need to know what CXNN will produce.
+pub struct ScriptedSource {
+    bytes: Vec<u8>,
+    position: usize,
+}
+
+impl ScriptedSource {
+    pub fn new(bytes: &[u8]) -> ScriptedSource {
+        assert!(!bytes.is_empty(), "a scripted source needs bytes");
+        ScriptedSource {
+            bytes: bytes.to_vec(),
+            position: 0,
+        }
+    }
+}
+
+impl RandomSource for ScriptedSource {
+    fn reseed(&mut self, _seed: u64) {
+        self.position = 0;
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        let byte = self.bytes[self.position];
+        self.position = (self.position + 1) % self.bytes.len();
+        byte
+    }
+
+    fn position(&self) -> u128 {
+        self.position as u128
+    }
+
+    fn seek(&mut self, _seed: u64, position: u128) {
+        self.position = (position % self.bytes.len() as u128) as usize;
+    }
+}
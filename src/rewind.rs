@@ -0,0 +1,191 @@
+//! A memory-bounded history of save states for running games backwards.
+//!
+//! Only the newest state is kept whole. Each older one is stored as the
+//! bytes that differ from the state after it, which is tiny since memory
+//! and the screen change little from one frame to the next. When the
+//! history outgrows its budget the oldest frames are forgotten.
+
+use std::collections::VecDeque;
+
+use log::warn;
+
+use crate::chip8::Chip8;
+
+/// Default memory budget for [`Rewind`], in bytes.
+pub const DEFAULT_CAPACITY: usize = 16 * 1024 * 1024;
+
+// Runs of equal bytes shorter than a run header are cheaper to copy.
+const RUN_HEADER: usize = 8;
+
+pub struct Rewind {
+    capacity: usize,
+    // The state of the most recent frame pushed.
+    current: Vec<u8>,
+    // How to get from each frame back to the one before it, oldest first.
+    deltas: VecDeque<Vec<u8>>,
+    used: usize,
+}
+
+impl Rewind {
+    /// An empty history holding at most about `capacity` bytes.
+    pub fn new(capacity: usize) -> Rewind {
+        Rewind {
+            capacity,
+            current: Vec::new(),
+            deltas: VecDeque::new(),
+            used: 0,
+        }
+    }
+
+    /// Frames that can be stepped back.
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// Bytes the history takes up.
+    pub fn memory_used(&self) -> usize {
+        self.used + self.current.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.current.clear();
+        self.deltas.clear();
+        self.used = 0;
+    }
+
+    /// Records the machine as it is now, usually after each frame.
+    pub fn push(&mut self, chip8: &Chip8) {
+        let state = chip8.save_state();
+        if !self.current.is_empty() {
+            let delta = diff(&self.current, &state);
+            self.used += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.current = state;
+        while self.memory_used() > self.capacity {
+            match self.deltas.pop_front() {
+                Some(delta) => self.used -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Puts the machine back to the frame before the last one recorded and
+    /// forgets that frame. Returns false when there is nothing to go back to.
+    pub fn step_back(&mut self, chip8: &mut Chip8) -> bool {
+        let Some(delta) = self.deltas.pop_back() else {
+            return false;
+        };
+        self.used -= delta.len();
+        let state = patch(&self.current, &delta);
+        if let Err(e) = chip8.load_state(&state) {
+            warn!(target: "rewind", "history dropped: {}", e);
+            self.clear();
+            return false;
+        }
+        self.current = state;
+        true
+    }
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Rewind::new(DEFAULT_CAPACITY)
+    }
+}
+
+// What turns `new` back into `old`: old's length, then runs of an offset
+// past the previous run, a length and old's bytes.
+fn diff(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let differs = |i: usize| new.get(i) != Some(&old[i]);
+    let mut delta = (old.len() as u32).to_le_bytes().to_vec();
+    let mut end = 0;
+    let mut i = 0;
+    while i < old.len() {
+        if !differs(i) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        let mut last = i;
+        while i < old.len() && i - last <= RUN_HEADER {
+            if differs(i) {
+                last = i;
+            }
+            i += 1;
+        }
+        delta.extend(((start - end) as u32).to_le_bytes());
+        delta.extend(((last + 1 - start) as u32).to_le_bytes());
+        delta.extend(&old[start..=last]);
+        end = last + 1;
+        i = end;
+    }
+    delta
+}
+
+fn patch(new: &[u8], delta: &[u8]) -> Vec<u8> {
+    let word = |at: usize| u32::from_le_bytes(delta[at..at + 4].try_into().unwrap()) as usize;
+    let mut old = new.to_vec();
+    old.resize(word(0), 0);
+    let mut at = 4;
+    let mut end = 0;
+    while at < delta.len() {
+        let start = end + word(at);
+        let length = word(at + 4);
+        old[start..start + length].copy_from_slice(&delta[at + 8..at + 8 + length]);
+        end = start + length;
+        at += 8 + length;
+    }
+    old
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deltas_rebuild_the_older_state() {
+        let old = b"the quick brown fox jumps over the lazy dog".to_vec();
+        let mut new = old.clone();
+        new[4] = b'Q';
+        new[40] = b'D';
+        let delta = diff(&old, &new);
+        assert!(delta.len() < 32);
+        assert_eq!(patch(&new, &delta), old);
+        assert_eq!(patch(&old, &diff(&new, &old)), new);
+        assert_eq!(patch(&new[..10], &diff(&old, &new[..10])), old);
+        assert_eq!(patch(&old, &diff(&old[..10], &old)), &old[..10]);
+    }
+
+    #[test]
+    fn stepping_back_retraces_frames() {
+        // 200: add v0, 1; 202: jp 200
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+        let mut rewind = Rewind::default();
+        rewind.push(&chip8);
+        let mut frames = Vec::new();
+        for _ in 0..10 {
+            frames.push(chip8.cpu().registers()[0]);
+            chip8.run_frame().unwrap();
+            rewind.push(&chip8);
+        }
+        assert_eq!(rewind.len(), 10);
+        while let Some(v0) = frames.pop() {
+            assert!(rewind.step_back(&mut chip8));
+            assert_eq!(chip8.cpu().registers()[0], v0);
+        }
+        assert!(!rewind.step_back(&mut chip8));
+
+        let mut small = Rewind::new(rewind.memory_used() + 100);
+        for _ in 0..100 {
+            chip8.run_frame().unwrap();
+            small.push(&chip8);
+        }
+        assert!(small.len() < 99 && small.memory_used() <= rewind.memory_used() + 100);
+    }
+}
@@ -0,0 +1,283 @@
+//! The save state file format.
+//!
+//! A state starts with [`MAGIC`] and a little-endian `u16` version, followed
+//! by chunks: a four-character tag, a `u32` length and that many bytes.
+//! Each part of the machine writes its own chunk, readers skip tags they
+//! don't know, and all integers are little endian.
+
+use std::error::Error;
+use std::fmt;
+
+pub const MAGIC: &[u8; 8] = b"CHIP8SAV";
+pub const VERSION: u16 = 1;
+
+/// Why a save state couldn't be loaded. The machine is left untouched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion(u16),
+    MissingChunk(&'static str),
+    // The named chunk is truncated or holds an impossible value.
+    Corrupt(&'static str),
+    RomMismatch,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "save state version {} is not supported", version)
+            }
+            StateError::MissingChunk(tag) => write!(f, "save state has no {:?} chunk", tag),
+            StateError::Corrupt(tag) => write!(f, "save state {:?} chunk is corrupt", tag),
+            StateError::RomMismatch => write!(f, "save state is for a different ROM"),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+/// Builds a state file one chunk at a time.
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        let mut data = MAGIC.to_vec();
+        data.extend(VERSION.to_le_bytes());
+        StateWriter { data }
+    }
+
+    pub fn chunk(&mut self, tag: &'static str, chunk: ChunkWriter) {
+        debug_assert_eq!(tag.len(), 4);
+        self.data.extend(tag.as_bytes());
+        self.data.extend((chunk.data.len() as u32).to_le_bytes());
+        self.data.extend(chunk.data);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The fields of one chunk.
+#[derive(Default)]
+pub struct ChunkWriter {
+    data: Vec<u8>,
+}
+
+impl ChunkWriter {
+    pub fn new() -> ChunkWriter {
+        ChunkWriter::default()
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.data.push(value);
+        self
+    }
+
+    pub fn bool(&mut self, value: bool) -> &mut Self {
+        self.u8(value as u8)
+    }
+
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.data.extend(value.to_le_bytes());
+        self
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.data.extend(value.to_le_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, value: u64) -> &mut Self {
+        self.data.extend(value.to_le_bytes());
+        self
+    }
+
+    pub fn u128(&mut self, value: u128) -> &mut Self {
+        self.data.extend(value.to_le_bytes());
+        self
+    }
+
+    /// A `u32` length followed by the bytes.
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.u32(bytes.len() as u32);
+        self.data.extend(bytes);
+        self
+    }
+
+    pub fn str(&mut self, text: &str) -> &mut Self {
+        self.bytes(text.as_bytes())
+    }
+}
+
+/// A parsed state file.
+pub struct StateReader<'a> {
+    chunks: Vec<(&'a [u8], &'a [u8])>,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<StateReader<'a>, StateError> {
+        let rest = data.strip_prefix(MAGIC).ok_or(StateError::NotAState)?;
+        if rest.len() < 2 {
+            return Err(StateError::NotAState);
+        }
+        let version = u16::from_le_bytes([rest[0], rest[1]]);
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        let mut rest = &rest[2..];
+        let mut chunks = Vec::new();
+        while !rest.is_empty() {
+            if rest.len() < 8 {
+                return Err(StateError::NotAState);
+            }
+            let (tag, length) = rest.split_at(4);
+            let length = u32::from_le_bytes(length[..4].try_into().unwrap()) as usize;
+            let body = &rest[8..];
+            if body.len() < length {
+                return Err(StateError::NotAState);
+            }
+            chunks.push((tag, &body[..length]));
+            rest = &body[length..];
+        }
+        Ok(StateReader { chunks })
+    }
+
+    pub fn chunk(&self, tag: &'static str) -> Result<ChunkReader<'a>, StateError> {
+        self.chunks
+            .iter()
+            .find(|(name, _)| *name == tag.as_bytes())
+            .map(|&(_, data)| ChunkReader { tag, data })
+            .ok_or(StateError::MissingChunk(tag))
+    }
+}
+
+/// Reads the fields of one chunk back in the order they were written.
+pub struct ChunkReader<'a> {
+    tag: &'static str,
+    data: &'a [u8],
+}
+
+impl<'a> ChunkReader<'a> {
+    /// An error blaming this chunk, for values that make no sense.
+    pub fn corrupt(&self) -> StateError {
+        StateError::Corrupt(self.tag)
+    }
+
+    fn take(&mut self, count: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < count {
+            return Err(self.corrupt());
+        }
+        let (bytes, rest) = self.data.split_at(count);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(self.corrupt()),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn u128(&mut self) -> Result<u128, StateError> {
+        Ok(u128::from_le_bytes(self.array()?))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let length = self.u32()? as usize;
+        self.take(length)
+    }
+
+    pub fn str(&mut self) -> Result<&'a str, StateError> {
+        let bytes = self.bytes()?;
+        std::str::from_utf8(bytes).map_err(|_| self.corrupt())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::chip8::Chip8;
+    use crate::state::StateError;
+
+    // 200: rnd v0, 0xFF; 202: ld dt, v0; 204: add v1, 1; 206: jp 200
+    const ROM: [u8; 8] = [0xC0, 0xFF, 0xF0, 0x15, 0x71, 0x01, 0x12, 0x00];
+
+    fn chip8_with(rom: &[u8]) -> Chip8 {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(rom).unwrap();
+        chip8
+    }
+
+    #[test]
+    fn loading_a_state_replays_the_same_frames() {
+        let mut chip8 = chip8_with(&ROM);
+        for _ in 0..3 {
+            chip8.run_frame().unwrap();
+        }
+        let state = chip8.save_state();
+        for _ in 0..5 {
+            chip8.run_frame().unwrap();
+        }
+        let registers = *chip8.cpu().registers();
+
+        let mut other = chip8_with(&ROM);
+        other.load_state(&state).unwrap();
+        assert_eq!(other.frame_count(), 3);
+        for _ in 0..5 {
+            other.run_frame().unwrap();
+        }
+        // Including the random numbers.
+        assert_eq!(*other.cpu().registers(), registers);
+        assert_eq!(other.delay_timer(), chip8.delay_timer());
+        assert_eq!(other.save_state(), chip8.save_state());
+    }
+
+    #[test]
+    fn bad_states_are_rejected() {
+        let mut chip8 = chip8_with(&ROM);
+        let state = chip8.save_state();
+
+        let mut other = chip8_with(&[0x12, 0x00]);
+        assert_eq!(other.load_state(&state), Err(StateError::RomMismatch));
+        assert_eq!(chip8.load_state(b"PNG"), Err(StateError::NotAState));
+        let mut newer = state.clone();
+        newer[8] = 2;
+        assert_eq!(
+            chip8.load_state(&newer),
+            Err(StateError::UnsupportedVersion(2))
+        );
+        assert!(chip8.load_state(&state[..state.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,252 @@
+//! Recording the screen frame by frame, as an animated GIF or as raw RGB
+//! frames for external encoders.
+//!
+//! Every frame is recorded at the size of the hires screen times `scale`,
+//! so a ROM can switch resolution mid-recording; lores frames are simply
+//! drawn twice as large. Raw video is `rgb24` frames back to back with no
+//! header, which ffmpeg reads with
+//! `ffmpeg -f rawvideo -pixel_format rgb24 -video_size 512x256 -framerate 60 -i FILE`
+//! at the default scale.
+
+use std::io::{self, Write};
+use std::path::Path;
+
+use crate::chip8::Chip8;
+use crate::display::{HIRES_HEIGHT, HIRES_WIDTH};
+use crate::palette::Palette;
+
+/// Emulated frames per second, which recordings play back at.
+pub const FRAME_RATE: u64 = 60;
+
+pub const DEFAULT_SCALE: usize = 4;
+
+/// The largest scale whose frames still fit in a GIF's 16-bit width.
+pub const MAX_SCALE: usize = u16::MAX as usize / HIRES_WIDTH;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoFormat {
+    Gif,
+    Raw,
+}
+
+impl VideoFormat {
+    /// GIF for `.gif` files, raw frames for anything else.
+    pub fn from_path(path: &str) -> VideoFormat {
+        match Path::new(path).extension() {
+            Some(extension) if extension.eq_ignore_ascii_case("gif") => VideoFormat::Gif,
+            _ => VideoFormat::Raw,
+        }
+    }
+}
+
+enum Output<W: Write> {
+    Gif {
+        encoder: gif::Encoder<W>,
+        // The last frame and the frame number it first showed at; it's only
+        // written once it changes, so that a still screen is one long frame.
+        pending: Option<(Vec<u8>, u64)>,
+    },
+    Raw(W),
+}
+
+/// Records every frame it's given to `W`.
+pub struct VideoRecorder<W: Write> {
+    output: Output<W>,
+    scale: usize,
+    palette: Palette,
+    frames: u64,
+}
+
+impl<W: Write> VideoRecorder<W> {
+    /// Starts a recording; GIFs write their header and `palette` straight
+    /// away. `scale` can be at most [`MAX_SCALE`].
+    pub fn new(
+        format: VideoFormat,
+        writer: W,
+        scale: usize,
+        palette: Palette,
+    ) -> io::Result<VideoRecorder<W>> {
+        let scale = scale.max(1);
+        if scale > MAX_SCALE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("scale {} is larger than {}", scale, MAX_SCALE),
+            ));
+        }
+        let output = match format {
+            VideoFormat::Gif => {
+                let colors: Vec<u8> = (0..4).flat_map(|pixel| palette.rgb(pixel)).collect();
+                let mut encoder = gif::Encoder::new(
+                    writer,
+                    (HIRES_WIDTH * scale) as u16,
+                    (HIRES_HEIGHT * scale) as u16,
+                    &colors,
+                )
+                .map_err(encoding_error)?;
+                encoder
+                    .set_repeat(gif::Repeat::Infinite)
+                    .map_err(encoding_error)?;
+                Output::Gif {
+                    encoder,
+                    pending: None,
+                }
+            }
+            VideoFormat::Raw => Output::Raw(writer),
+        };
+        Ok(VideoRecorder {
+            output,
+            scale,
+            palette,
+            frames: 0,
+        })
+    }
+
+    /// Frames recorded so far.
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    /// Records the screen as `chip8` shows it now, for one 60 Hz frame.
+    pub fn frame(&mut self, chip8: &Chip8) -> io::Result<()> {
+        let pixels = self.scaled(chip8.framebuffer(), chip8.width());
+        match &mut self.output {
+            Output::Gif { encoder, pending } => {
+                if !matches!(pending, Some((last, _)) if *last == pixels) {
+                    if let Some((last, start)) = pending.take() {
+                        write_gif_frame(encoder, last, start, self.frames, self.scale)?;
+                    }
+                    *pending = Some((pixels, self.frames));
+                }
+            }
+            Output::Raw(writer) => {
+                let rgb: Vec<u8> = pixels
+                    .iter()
+                    .flat_map(|&pixel| self.palette.rgb(pixel))
+                    .collect();
+                writer.write_all(&rgb)?;
+            }
+        }
+        self.frames += 1;
+        Ok(())
+    }
+
+    /// Writes out whatever is buffered and ends the file.
+    pub fn finish(self) -> io::Result<W> {
+        match self.output {
+            Output::Gif {
+                mut encoder,
+                pending,
+            } => {
+                if let Some((last, start)) = pending {
+                    write_gif_frame(&mut encoder, last, start, self.frames, self.scale)?;
+                }
+                encoder.into_inner()
+            }
+            Output::Raw(mut writer) => {
+                writer.flush()?;
+                Ok(writer)
+            }
+        }
+    }
+
+    // The framebuffer blown up to the recording's size, one byte per pixel.
+    fn scaled(&self, pixels: &[u8], width: usize) -> Vec<u8> {
+        let scale = HIRES_WIDTH * self.scale / width;
+        let mut scaled = Vec::with_capacity(HIRES_WIDTH * HIRES_HEIGHT * self.scale * self.scale);
+        for row in pixels.chunks(width) {
+            let line: Vec<u8> = row
+                .iter()
+                .flat_map(|&pixel| std::iter::repeat_n(pixel, scale))
+                .collect();
+            for _ in 0..scale {
+                scaled.extend(&line);
+            }
+        }
+        scaled
+    }
+}
+
+// GIF delays are in hundredths of a second, which 60 Hz doesn't divide, so
+// each frame ends at its own time rounded; the delays come out as 2, 1, 2
+// over and over and never drift.
+fn centiseconds(frame: u64) -> u64 {
+    (frame * 100 + FRAME_RATE / 2) / FRAME_RATE
+}
+
+fn write_gif_frame<W: Write>(
+    encoder: &mut gif::Encoder<W>,
+    pixels: Vec<u8>,
+    start: u64,
+    end: u64,
+    scale: usize,
+) -> io::Result<()> {
+    let mut frame = gif::Frame::from_indexed_pixels(
+        (HIRES_WIDTH * scale) as u16,
+        (HIRES_HEIGHT * scale) as u16,
+        pixels,
+        None,
+    );
+    frame.delay = (centiseconds(end) - centiseconds(start)).min(u16::MAX as u64) as u16;
+    encoder.write_frame(&frame).map_err(encoding_error)
+}
+
+fn encoding_error(error: gif::EncodingError) -> io::Error {
+    match error {
+        gif::EncodingError::Io(error) => error,
+        gif::EncodingError::Format(error) => io::Error::new(io::ErrorKind::InvalidData, error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 200: cls; 202: drw v0, v0, 1 (I = 0, the "0" glyph); 204: jp 202
+    const ROM: [u8; 6] = [0x00, 0xE0, 0xD0, 0x01, 0x12, 0x02];
+
+    #[test]
+    fn gifs_play_at_sixty_hertz() {
+        let mut chip8 = Chip8::new();
+        chip8.load_rom(&ROM).unwrap();
+        let mut recorder =
+            VideoRecorder::new(VideoFormat::Gif, Vec::new(), 1, Palette::CLASSIC).unwrap();
+        // The sprite blinks every frame for six frames, then a still screen
+        // for six more.
+        for _ in 0..6 {
+            chip8.run_frame().unwrap();
+            recorder.frame(&chip8).unwrap();
+        }
+        for _ in 0..6 {
+            recorder.frame(&chip8).unwrap();
+        }
+        assert_eq!(recorder.frame_count(), 12);
+        let gif = recorder.finish().unwrap();
+
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(gif.as_slice()).unwrap();
+        assert_eq!((decoder.width(), decoder.height()), (128, 64));
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            delays.push(frame.delay);
+        }
+        assert_eq!(delays, [2, 1, 2, 2, 1, 12]);
+        // Twelve frames at 60 Hz.
+        assert_eq!(delays.iter().sum::<u16>(), 20);
+    }
+
+    #[test]
+    fn raw_frames_have_a_fixed_size() {
+        let chip8 = Chip8::new();
+        let mut recorder =
+            VideoRecorder::new(VideoFormat::Raw, Vec::new(), 2, Palette::CLASSIC).unwrap();
+        recorder.frame(&chip8).unwrap();
+        recorder.frame(&chip8).unwrap();
+        let raw = recorder.finish().unwrap();
+        assert_eq!(raw.len(), 2 * 256 * 128 * 3);
+        assert_eq!(VideoFormat::from_path("clip.GIF"), VideoFormat::Gif);
+        assert_eq!(VideoFormat::from_path("clip.rgb"), VideoFormat::Raw);
+        let error = VideoRecorder::new(VideoFormat::Gif, Vec::new(), 512, Palette::CLASSIC);
+        assert_eq!(error.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,433 @@
+//! The minifb window frontend: the default way to play a ROM, with its
+//! hotkeys for save states, rewind, captures and palettes.
+
+use crate::cli::{self, Options};
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+#[cfg(feature = "audio")]
+use rust_chip_8::audio::CpalAudio;
+use rust_chip_8::audio::{AudioBackend, NullAudio, Tone};
+use rust_chip_8::debugger::Debugger;
+use rust_chip_8::gdb::GdbServer;
+use rust_chip_8::movie::{Input, Movie, Player};
+use rust_chip_8::rewind::Rewind;
+use rust_chip_8::{Chip8, Chip8Error};
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const TITLE: &str = "Rust Chip8 emulator";
+
+fn get_chip8_keycode_for(key: Key) -> Option<u8> {
+    match key {
+        Key::Key1 => Some(0x1),
+        Key::Key2 => Some(0x2),
+        Key::Key3 => Some(0x3),
+        Key::Key4 => Some(0xC),
+
+        Key::Q => Some(0x4),
+        Key::W => Some(0x5),
+        Key::E => Some(0x6),
+        Key::R => Some(0xD),
+
+        Key::A => Some(0x7),
+        Key::S => Some(0x8),
+        Key::D => Some(0x9),
+        Key::F => Some(0xE),
+
+        Key::Z => Some(0xA),
+        Key::X => Some(0x0),
+        Key::C => Some(0xB),
+        Key::V => Some(0xF),
+        _ => None,
+    }
+}
+
+// Held to run the game backwards.
+const REWIND_KEY: Key = Key::Backspace;
+
+// Saves a screenshot at the window's scale, or with Shift at the native
+// resolution.
+const SCREENSHOT_KEY: Key = Key::F12;
+
+// Switches to the next palette, or with Shift the previous one.
+const PALETTE_KEY: Key = Key::F6;
+
+// Starts and stops recording a GIF.
+const VIDEO_KEY: Key = Key::F9;
+
+// F1-F4 load a slot, Shift+F1-F4 save to it.
+const STATE_KEYS: [Key; 4] = [Key::F1, Key::F2, Key::F3, Key::F4];
+
+// Save states sit next to the ROM, one file per slot.
+fn state_path(rom: &str, slot: usize) -> String {
+    format!("{}.{}.state", rom, slot)
+}
+
+fn save_state(chip8: &Chip8, rom: &str, slot: usize) -> Result<String, String> {
+    let path = state_path(rom, slot);
+    fs::write(&path, chip8.save_state()).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(format!("saved slot {}", slot))
+}
+
+fn load_state(chip8: &mut Chip8, rom: &str, slot: usize) -> Result<String, String> {
+    let path = state_path(rom, slot);
+    let data = fs::read(&path).map_err(|e| format!("{}: {}", path, e))?;
+    chip8
+        .load_state(&data)
+        .map_err(|e| format!("{}: {}", path, e))?;
+    Ok(format!("loaded slot {}", slot))
+}
+
+// A new file name for a capture of `rom`: its name, the UTC time and
+// `extension`, in the current directory.
+fn capture_path(rom: &str, extension: &str) -> String {
+    let name = Path::new(rom)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    format!("{}-{}.{}", name, timestamp(), extension)
+}
+
+// YYYYMMDD-HHMMSS.mmm, without pulling in a date library.
+fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = now.as_secs();
+    let (days, time) = (seconds / 86_400, seconds % 86_400);
+    // Days since 1970-01-01 to a civil date, after Howard Hinnant's
+    // days_from_civil inverse.
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}.{:03}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time / 60 % 60,
+        time % 60,
+        now.subsec_millis()
+    )
+}
+
+// Starts the rewind history over from the machine as it is now, once
+// something other than running it has replaced its state; stepping back
+// must not land in the old timeline.
+fn restart_history(rewind: &mut Option<Rewind>, chip8: &Chip8) {
+    if let Some(history) = rewind {
+        history.clear();
+        history.push(chip8);
+    }
+}
+
+// Writes the movie out; recording stops when the machine jumps to another
+// point in time, since the movie couldn't reproduce that.
+fn finish_recording(recording: &mut Option<(String, Movie)>) {
+    if let Some((path, movie)) = recording.take() {
+        match fs::write(&path, movie.to_string()) {
+            Ok(()) => eprintln!("recorded {} frames to {}", movie.frame_count(), path),
+            Err(e) => eprintln!("{}: {}", path, e),
+        }
+    }
+}
+
+// Ends the video being recorded, if any, and says how it went.
+fn finish_video(video: &mut Option<(String, cli::Video)>) -> Option<String> {
+    let (path, recorder) = video.take()?;
+    let message = cli::finish_video(recorder, &path).unwrap_or_else(|e| e);
+    eprintln!("{}", message);
+    Some(message)
+}
+
+#[cfg(feature = "audio")]
+fn audio_backend(tone: Tone) -> Box<dyn AudioBackend> {
+    match CpalAudio::new(tone) {
+        Ok(audio) => Box::new(audio),
+        Err(e) => {
+            eprintln!("Sound disabled: {}", e);
+            Box::new(NullAudio)
+        }
+    }
+}
+
+#[cfg(not(feature = "audio"))]
+fn audio_backend(_tone: Tone) -> Box<dyn AudioBackend> {
+    Box::new(NullAudio)
+}
+
+/// Runs the ROM in a window until it's closed or Escape is pressed.
+pub fn run(options: Options) {
+    options.init_logger();
+    let mut chip8 = options.create_chip8().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    chip8.set_audio_backend(audio_backend(options.tone));
+
+    let width = 640;
+    let height = 320;
+
+    //ARGB buffer
+    let mut buffer: Vec<u32> = vec![0; width * height];
+
+    let mut window =
+        Window::new(TITLE, width, height, WindowOptions::default()).unwrap_or_else(|e| {
+            panic!("{}", e);
+        });
+
+    // One emulated frame per host frame.
+    window.set_target_fps(60);
+
+    let mut error: Option<Chip8Error> = None;
+
+    // The window keeps drawing while the debugger waits for commands.
+    let mut debugging = options.debug.then(|| {
+        let mut debugger = Debugger::new();
+        cli::debug::show(&debugger.execute(&mut chip8, "list"));
+        cli::debug::prompt();
+        (debugger, cli::debug::stdin_lines())
+    });
+
+    let mut gdb = options.gdb.map(|port| {
+        let server = GdbServer::bind(port).unwrap_or_else(|e| {
+            eprintln!("port {}: {}", port, e);
+            process::exit(1);
+        });
+        eprintln!("waiting for gdb on 127.0.0.1:{}", port);
+        server
+    });
+
+    let mut recording = options
+        .record
+        .as_ref()
+        .map(|path| (path.clone(), Movie::new(&chip8)));
+
+    // The movie configures and resets the machine before the first frame.
+    let mut player = options.replay.as_ref().map(|path| {
+        let movie = cli::read_movie(path).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+        Player::new(movie, &mut chip8).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        })
+    });
+
+    let mut rewind = (options.rewind > 0).then(|| {
+        let mut rewind = Rewind::new(options.rewind << 20);
+        rewind.push(&chip8);
+        rewind
+    });
+
+    let mut rewinding = false;
+    let (palettes, mut current_palette) = options.palettes().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let mut palette = palettes[current_palette].1;
+
+    let mut video = options.video.as_ref().map(|path| {
+        let recorder = cli::start_video(path, options.video_scale, palette).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        });
+        (path.clone(), recorder)
+    });
+
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        let keys = window
+            .get_keys()
+            .into_iter()
+            .filter_map(get_chip8_keycode_for)
+            .fold(0u16, |keys, key| keys | 1 << key);
+        chip8.set_keys(keys);
+
+        if window.is_key_pressed(Key::F5, KeyRepeat::No) {
+            chip8.reset();
+            restart_history(&mut rewind, &chip8);
+            error = None;
+            window.set_title(TITLE);
+            if let Some((_, movie)) = &mut recording {
+                movie.record(Input::Reset);
+            }
+        }
+
+        let shift = window.is_key_down(Key::LeftShift) || window.is_key_down(Key::RightShift);
+        for (index, &key) in STATE_KEYS.iter().enumerate() {
+            if !window.is_key_pressed(key, KeyRepeat::No) {
+                continue;
+            }
+            let slot = index + 1;
+            let result = if shift {
+                save_state(&chip8, &options.rom, slot)
+            } else {
+                load_state(&mut chip8, &options.rom, slot)
+            };
+            match result {
+                Ok(message) => {
+                    if !shift {
+                        restart_history(&mut rewind, &chip8);
+                        error = None;
+                        finish_recording(&mut recording);
+                        player = None;
+                    }
+                    window.set_title(&format!("{} - {}", TITLE, message));
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
+                    window.set_title(&format!("{} - {}", TITLE, e));
+                }
+            }
+        }
+
+        if window.is_key_pressed(SCREENSHOT_KEY, KeyRepeat::No) {
+            let scale = if shift { 1 } else { width / chip8.width() };
+            let path = capture_path(&options.rom, "png");
+            match fs::write(&path, chip8.screenshot_png(scale, &palette)) {
+                Ok(()) => window.set_title(&format!("{} - saved {}", TITLE, path)),
+                Err(e) => {
+                    eprintln!("{}: {}", path, e);
+                    window.set_title(&format!("{} - {}: {}", TITLE, path, e));
+                }
+            }
+        }
+
+        if window.is_key_pressed(PALETTE_KEY, KeyRepeat::No) {
+            let step = if shift { palettes.len() - 1 } else { 1 };
+            current_palette = (current_palette + step) % palettes.len();
+            let (name, colors) = &palettes[current_palette];
+            palette = *colors;
+            window.set_title(&format!("{} - palette {}", TITLE, name));
+        }
+
+        if window.is_key_pressed(VIDEO_KEY, KeyRepeat::No) {
+            let message = finish_video(&mut video).unwrap_or_else(|| {
+                let path = capture_path(&options.rom, "gif");
+                match cli::start_video(&path, options.video_scale, palette) {
+                    Ok(recorder) => {
+                        let message = format!("recording {}", path);
+                        video = Some((path, recorder));
+                        message
+                    }
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        e
+                    }
+                }
+            });
+            window.set_title(&format!("{} - {}", TITLE, message));
+        }
+
+        // Whatever moved the machine on, each emulated frame is recorded
+        // once; a paused machine records nothing.
+        let frame = chip8.frame_count();
+
+        if rewinding && !window.is_key_down(REWIND_KEY) {
+            rewinding = false;
+            window.set_title(TITLE);
+        }
+
+        if let Some((debugger, commands)) = &mut debugging {
+            // The debugger reports errors and exit itself.
+            while let Ok(line) = commands.try_recv() {
+                cli::debug::show(&debugger.execute(&mut chip8, &line));
+                cli::debug::prompt();
+            }
+            if debugger.has_quit() {
+                break;
+            }
+            if let Some(message) = debugger.run_frame(&mut chip8) {
+                cli::debug::show(&message);
+                cli::debug::prompt();
+            }
+        } else if let Some(server) = &mut gdb {
+            // The client sees errors and exit as stop replies.
+            if let Err(e) = server.run_frame(&mut chip8) {
+                eprintln!("gdb: {}", e);
+                break;
+            }
+            if server.is_killed() {
+                break;
+            }
+        } else if let Some(history) = rewind.as_mut().filter(|_| window.is_key_down(REWIND_KEY)) {
+            // Going back from an error or exit lets the game carry on.
+            if history.step_back(&mut chip8) {
+                error = None;
+                finish_recording(&mut recording);
+                player = None;
+            }
+            rewinding = true;
+            window.set_title(&format!(
+                "{} - rewinding ({} frames left)",
+                TITLE,
+                history.len()
+            ));
+        } else if error.is_none() && !chip8.has_exited() {
+            if let Some(movie) = &mut player {
+                match movie.next_keys(&mut chip8) {
+                    Some(keys) => chip8.set_keys(keys),
+                    None => {
+                        player = None;
+                        window.set_title(&format!("{} - replay finished", TITLE));
+                    }
+                }
+            }
+            if let Some((_, movie)) = &mut recording {
+                movie.record(Input::Frame(chip8.keys()));
+            }
+            let result = chip8.run_frame();
+            if let Some(history) = &mut rewind {
+                history.push(&chip8);
+            }
+            if let Err(e) = result {
+                eprintln!("Emulation stopped: {}", e);
+                window.set_title(&format!("{} - {} (F5 to reset)", TITLE, e));
+                error = Some(e);
+            } else if chip8.has_exited() {
+                window.set_title(&format!("{} - program exited (F5 to reset)", TITLE));
+            }
+        }
+
+        if let Some((path, recorder)) = video.as_mut().filter(|_| chip8.frame_count() != frame) {
+            if let Err(e) = recorder.frame(&chip8) {
+                eprintln!("{}: {}", path, e);
+                window.set_title(&format!("{} - {}: {}", TITLE, path, e));
+                video = None;
+            }
+        }
+
+        let chip8_buffer = chip8.framebuffer();
+        // 10x in lores, 5x in SUPER-CHIP hires.
+        let scale = width / chip8.width();
+        let chip8_width = chip8.width();
+
+        for y in 0..height {
+            let y_coord = y / scale;
+            let offset = y * width;
+            for x in 0..width {
+                let index = y_coord * chip8_width + x / scale;
+                buffer[offset + x] = palette.color(chip8_buffer[index]);
+            }
+        }
+
+        window.update_with_buffer(&buffer, width, height).unwrap();
+    }
+
+    finish_recording(&mut recording);
+    finish_video(&mut video);
+}